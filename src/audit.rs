@@ -0,0 +1,223 @@
+//! Opt-in audit trail for downloads: one JSON line per served download,
+//! appended to a configurable file. Bytes served are counted by wrapping
+//! the response stream, and the record is written when the stream is
+//! dropped — which covers both completed transfers and client disconnects,
+//! labelled accordingly in the `result` field.
+
+use std::{
+    io::Write,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use serde::Serialize;
+
+/// One download, as it lands in the log file.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    /// RFC 3339 UTC timestamp of when the transfer ended.
+    pub timestamp: String,
+    pub client_ip: String,
+    /// The API path that served the download, e.g. "/api/video/stream".
+    pub endpoint: String,
+    pub url: String,
+    /// The format id or selector handed to yt-dlp.
+    pub format: String,
+    pub bytes_served: u64,
+    /// "completed" when the stream reached its end, "aborted" when the
+    /// client disconnected first.
+    pub result: String,
+}
+
+/// Everything needed to write the record once the transfer ends.
+#[derive(Debug, Clone)]
+pub struct AuditContext {
+    pub log_file: String,
+    pub max_bytes: u64,
+    pub client_ip: String,
+    pub endpoint: String,
+    pub url: String,
+    pub format: String,
+}
+
+/// Append `record` as one JSON line, rotating the file to `<path>.1` first
+/// when it has outgrown the cap. Log failures are warned about, never
+/// surfaced to the client — the download itself already succeeded.
+pub fn append_record(path: &str, max_bytes: u64, record: &AuditRecord) {
+    rotate_if_needed(path, max_bytes);
+    let line = match serde_json::to_string(record) {
+        Ok(line) => line,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to serialize audit record");
+            return;
+        }
+    };
+    let result = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{line}"));
+    if let Err(e) = result {
+        tracing::warn!(error = %e, path, "failed to append audit record");
+    }
+}
+
+/// One-deep rotation: the previous `.1` file is overwritten. Operators who
+/// need longer retention should ship the file off-host.
+fn rotate_if_needed(path: &str, max_bytes: u64) {
+    if max_bytes == 0 {
+        return;
+    }
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return;
+    };
+    if metadata.len() >= max_bytes {
+        if let Err(e) = std::fs::rename(path, format!("{path}.1")) {
+            tracing::warn!(error = %e, path, "failed to rotate audit log");
+        }
+    }
+}
+
+/// Counts the bytes a response stream yields and writes the audit record
+/// when the stream is dropped.
+pub struct AuditedStream<S> {
+    inner: S,
+    bytes: u64,
+    finished: bool,
+    context: Option<AuditContext>,
+}
+
+impl<S> AuditedStream<S> {
+    pub fn new(inner: S, context: AuditContext) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            finished: false,
+            context: Some(context),
+        }
+    }
+}
+
+impl<S> Stream for AuditedStream<S>
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin,
+{
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, std::io::Error>>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => this.bytes += chunk.len() as u64,
+            Poll::Ready(None) => this.finished = true,
+            _ => {}
+        }
+        polled
+    }
+}
+
+impl<S> Drop for AuditedStream<S> {
+    fn drop(&mut self) {
+        let Some(context) = self.context.take() else {
+            return;
+        };
+        let record = AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            client_ip: context.client_ip,
+            endpoint: context.endpoint,
+            url: context.url,
+            format: context.format,
+            bytes_served: self.bytes,
+            result: if self.finished { "completed" } else { "aborted" }.to_string(),
+        };
+        append_record(&context.log_file, context.max_bytes, &record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn context(log_file: &str) -> AuditContext {
+        AuditContext {
+            log_file: log_file.to_string(),
+            max_bytes: 0,
+            client_ip: "203.0.113.9".to_string(),
+            endpoint: "/api/video/stream".to_string(),
+            url: "https://www.tiktok.com/@u/video/1".to_string(),
+            format: "hd".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn completed_download_logs_one_line_with_all_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_file = dir.path().join("audit.jsonl");
+        let log_file = log_file.to_str().unwrap();
+
+        let chunks: Vec<Result<Bytes, std::io::Error>> =
+            vec![Ok(Bytes::from_static(b"abc")), Ok(Bytes::from_static(b"defgh"))];
+        let mut stream = AuditedStream::new(futures::stream::iter(chunks), context(log_file));
+        while stream.next().await.is_some() {}
+        drop(stream);
+
+        let contents = std::fs::read_to_string(log_file).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        let record: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+        assert_eq!(record["client_ip"], "203.0.113.9");
+        assert_eq!(record["endpoint"], "/api/video/stream");
+        assert_eq!(record["url"], "https://www.tiktok.com/@u/video/1");
+        assert_eq!(record["format"], "hd");
+        assert_eq!(record["bytes_served"], 8);
+        assert_eq!(record["result"], "completed");
+        assert!(record["timestamp"].as_str().unwrap().contains('T'));
+    }
+
+    #[tokio::test]
+    async fn dropped_mid_stream_logs_an_abort() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_file = dir.path().join("audit.jsonl");
+        let log_file = log_file.to_str().unwrap();
+
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![Ok(Bytes::from_static(b"abc"))];
+        let mut stream = AuditedStream::new(futures::stream::iter(chunks), context(log_file));
+        let _first = stream.next().await;
+        drop(stream); // client went away before EOF
+
+        let contents = std::fs::read_to_string(log_file).unwrap();
+        let record: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+        assert_eq!(record["result"], "aborted");
+        assert_eq!(record["bytes_served"], 3);
+    }
+
+    #[test]
+    fn oversized_logs_rotate_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_file = dir.path().join("audit.jsonl");
+        let log_file = log_file.to_str().unwrap();
+        std::fs::write(log_file, "old line\n").unwrap();
+
+        let record = AuditRecord {
+            timestamp: "2026-01-01T00:00:00Z".to_string(),
+            client_ip: "203.0.113.9".to_string(),
+            endpoint: "/api/video/stream".to_string(),
+            url: "u".to_string(),
+            format: "f".to_string(),
+            bytes_served: 1,
+            result: "completed".to_string(),
+        };
+        append_record(log_file, 4, &record);
+
+        // The old contents moved aside; the new file holds only the record.
+        assert!(std::fs::read_to_string(format!("{log_file}.1"))
+            .unwrap()
+            .contains("old line"));
+        assert_eq!(std::fs::read_to_string(log_file).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,532 @@
+use std::env;
+
+use crate::url_validator::FilenamePolicy;
+
+/// Server configuration, loaded once at startup from environment variables.
+///
+/// Every knob has a sensible default so the server runs with no configuration
+/// at all in development.
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    /// Address to bind, e.g. "0.0.0.0" (HOST).
+    pub host: String,
+    /// Port to bind (PORT).
+    pub port: u16,
+    /// Directory where finished profile ZIPs are placed (DOWNLOADS_DIR).
+    pub downloads_dir: String,
+    /// Base directory for scratch space (TEMP_DIR).
+    pub temp_dir: String,
+    /// Maximum size in bytes for a single video download (MAX_FILE_SIZE).
+    /// Enforced up front when the format's size is known, and as a hard
+    /// byte cap on the stream otherwise. 0 means unlimited.
+    pub max_file_size: u64,
+    /// Maximum number of videos enumerated per profile (MAX_PROFILE_VIDEOS).
+    pub max_profile_videos: usize,
+    /// Hard timeout in seconds for a single yt-dlp invocation (YTDLP_TIMEOUT).
+    pub ytdlp_timeout: u64,
+    /// Read-buffer size in bytes for streamed downloads (STREAM_BUFFER_SIZE).
+    pub stream_buffer_size: usize,
+    /// Maximum yt-dlp download processes running at once (MAX_CONCURRENT_DOWNLOADS).
+    pub max_concurrent_downloads: usize,
+    /// Per-download bandwidth cap passed to yt-dlp --limit-rate, e.g. "500K"
+    /// or "2M" (DOWNLOAD_RATE_LIMIT). Unset means unlimited. Besides being
+    /// polite to TikTok, this smooths server egress under many concurrent
+    /// downloads.
+    pub download_rate_limit: Option<String>,
+    /// Concurrent yt-dlp metadata jobs for batch endpoints (BATCH_CONCURRENCY).
+    pub batch_concurrency: usize,
+    /// Parallel video downloads allowed from one profile at a time
+    /// (PER_PROFILE_CONCURRENCY). Applies on top of BATCH_CONCURRENCY: a
+    /// selection takes at most this many of the global batch slots, so
+    /// hammering a single profile stays gentle while downloads from other
+    /// profiles still fill the remaining slots.
+    pub per_profile_concurrency: usize,
+    /// What to do when every download slot is taken (LOAD_SHED_STRATEGY,
+    /// "reject" or "queue"). Reject 503s immediately; queue waits up to
+    /// DOWNLOAD_QUEUE_TIMEOUT for a slot to free before 503ing.
+    pub load_shed_strategy: LoadShedStrategy,
+    /// Seconds a queued request may wait for a download slot
+    /// (DOWNLOAD_QUEUE_TIMEOUT). Only meaningful with the queue strategy.
+    pub download_queue_timeout: u64,
+    /// Upper bound in milliseconds for the randomized pause inserted
+    /// between consecutive yt-dlp calls on the profile/batch paths
+    /// (REQUEST_JITTER_MS). Spacing requests out in a human-ish pattern
+    /// lowers the odds of TikTok blocking the server's IP. 0 (the
+    /// default) disables the delay.
+    pub request_jitter_ms: u64,
+    /// Fixed scratch directory for in-flight downloads (SCRATCH_DIR),
+    /// for operators who want yt-dlp's working files on a specific fast
+    /// disk or tmpfs. Created if absent. Unset keeps the default: a
+    /// random directory under TEMP_DIR, removed when the server exits.
+    pub scratch_dir: Option<String>,
+    /// Where yt-dlp keeps its extractor cache (YTDLP_CACHE_DIR). Unset
+    /// leaves yt-dlp's default (~/.cache/yt-dlp); a path passes
+    /// --cache-dir, and the special value "none" passes --no-cache-dir —
+    /// the right choice in read-only containers, where the default cache
+    /// write fails in hard-to-diagnose ways.
+    pub ytdlp_cache_dir: Option<String>,
+    /// Base URL of a self-hosted tikwm-style JSON API tried when yt-dlp
+    /// fails with an extraction error (FALLBACK_API_URL). It yields the
+    /// no-watermark URL and basic metadata only. Unset disables the
+    /// fallback entirely.
+    pub fallback_api_url: Option<String>,
+    /// Requests per minute allowed per client IP (RATE_LIMIT_PER_MINUTE).
+    pub rate_limit_per_minute: u32,
+    /// Overall per-request timeout in seconds for non-streaming endpoints
+    /// (REQUEST_TIMEOUT_SECS). Streaming routes are exempt — a large
+    /// download legitimately outlives any sane request timeout. 0 disables.
+    pub request_timeout_secs: u64,
+    /// TCP keep-alive probe interval in seconds on accepted connections
+    /// (TCP_KEEPALIVE_SECS), so dead peers are noticed instead of holding
+    /// a connection open forever. 0 disables.
+    pub tcp_keepalive_secs: u64,
+    /// Connect timeout in seconds for our own outbound HTTP calls
+    /// (HTTP_CONNECT_TIMEOUT_SECS) — recaptcha, thumbnails, the fallback
+    /// API. 0 disables.
+    pub http_connect_timeout_secs: u64,
+    /// Total timeout in seconds for outbound HTTP calls
+    /// (HTTP_TIMEOUT_SECS); call sites with tighter deadlines keep their
+    /// own per-request timeouts on top. 0 disables.
+    pub http_timeout_secs: u64,
+    /// CIDR ranges of reverse proxies whose X-Forwarded-For / X-Real-IP
+    /// headers are believed (TRUSTED_PROXIES, comma-separated). When the
+    /// direct peer is not in this set the socket address is used, so
+    /// clients can't spoof their way past the rate limiter with a fake
+    /// header. Empty (the default) trusts no proxy headers at all.
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+    /// IPs or CIDR ranges exempt from the rate limiter entirely
+    /// (RATE_LIMIT_BYPASS, comma-separated) — for the operator's own
+    /// monitoring and internal tools. Bypassed requests don't consume any
+    /// budget either. Empty by default: everyone is limited.
+    pub rate_limit_bypass: Vec<ipnet::IpNet>,
+    /// How many parsed formats video info responses list (MAX_FORMATS).
+    /// 0 means all of them; the raw /api/debug/formats output is never
+    /// truncated either way.
+    pub max_formats: usize,
+    /// Tallest video format users may request, in pixels
+    /// (MAX_DOWNLOAD_HEIGHT). Unset means no ceiling.
+    pub max_download_height: Option<u32>,
+    /// Seconds to refuse new yt-dlp spawns after TikTok rate-limits us
+    /// (TIKTOK_COOLDOWN_SECS). 0 disables the circuit breaker.
+    pub tiktok_cooldown_secs: u64,
+    /// reCAPTCHA v2/v3 secret (RECAPTCHA_SECRET). Verification is skipped when unset.
+    pub recaptcha_secret: Option<String>,
+    /// Allow requests through when the siteverify API itself is unreachable
+    /// (RECAPTCHA_FAIL_OPEN). Invalid tokens are still rejected.
+    pub recaptcha_fail_open: bool,
+    /// PEM certificate chain for serving HTTPS directly (TLS_CERT_PATH).
+    /// TLS is enabled only when both this and TLS_KEY_PATH are set.
+    pub tls_cert_path: Option<String>,
+    /// PEM private key matching the certificate (TLS_KEY_PATH).
+    pub tls_key_path: Option<String>,
+    /// Expose the downloads directory as static files under /api/downloads
+    /// (SERVE_DOWNLOADS_DIR). Off by default: anyone who can guess a ZIP
+    /// filename could fetch other users' archives, and the streaming
+    /// endpoint has replaced the static mount.
+    pub serve_downloads_dir: bool,
+    /// File where active profile jobs are persisted so a restart doesn't
+    /// orphan them (JOB_STATE_FILE). Unset disables persistence.
+    pub job_state_file: Option<String>,
+    /// How download filenames treat non-ASCII title characters
+    /// (FILENAME_POLICY, "ascii" or "unicode").
+    pub filename_policy: FilenamePolicy,
+    /// Offload ZIP downloads to the front proxy by answering with this
+    /// header (SENDFILE_HEADER, e.g. "X-Accel-Redirect" for nginx or
+    /// "X-Sendfile" for Apache) and no body, letting the proxy sendfile
+    /// the bytes instead of pumping them through this process. Unset
+    /// streams directly, as before.
+    pub sendfile_header: Option<String>,
+    /// Internal location prefix the X-Accel-Redirect value points into
+    /// (SENDFILE_LOCATION); must match an `internal` nginx location
+    /// aliased to the downloads directory. Ignored for X-Sendfile, which
+    /// takes the filesystem path.
+    pub sendfile_location: String,
+    /// Soft time limit in seconds for enumerating a profile's videos
+    /// (PROFILE_LIST_SOFT_LIMIT_SECS). When a huge profile is still
+    /// listing past this point, the entries collected so far come back
+    /// flagged as truncated instead of the request failing outright. 0
+    /// leaves only the hard YTDLP_TIMEOUT.
+    pub profile_list_soft_limit_secs: u64,
+    /// How long a freshly built profile ZIP may be reused for an
+    /// identical repeat request (PROFILE_ZIP_CACHE_TTL_SECS) before the
+    /// profile is re-downloaded. 0 disables the cache and rebuilds every
+    /// time.
+    pub profile_zip_cache_ttl_secs: u64,
+    /// How many ZIP archives may be assembled at the same time
+    /// (MAX_CONCURRENT_ZIP_BUILDS). Each build occupies a blocking-pool
+    /// thread and saturates disk I/O for its duration.
+    pub max_concurrent_zip_builds: usize,
+    /// Video codecs to prefer, best first, when building the default
+    /// "best" selector and ordering the displayed format list
+    /// (PREFERRED_CODECS, comma-separated). Values are matched as vcodec
+    /// prefixes, so "h264" also covers "h264.main" and "avc1" covers
+    /// "avc1.64001f". Lets compatibility-sensitive deployments favor
+    /// h264/mp4 over higher-efficiency codecs older devices choke on.
+    /// Empty keeps yt-dlp's own ordering.
+    pub preferred_codecs: Vec<String>,
+    /// Container to prefer alongside the codec preferences
+    /// (PREFER_CONTAINER), e.g. "mp4". Unset expresses no preference.
+    pub prefer_container: Option<String>,
+    /// Audio conversion formats the audio endpoint will accept
+    /// (ALLOWED_AUDIO_FORMATS, comma-separated). Conversions like wav are
+    /// ffmpeg-heavy, so operators can fence the list in without rebuilding.
+    /// Empty allows every supported format.
+    pub allowed_audio_formats: Vec<String>,
+    /// Ask yt-dlp to dodge TikTok's age-consent interstitial
+    /// (BYPASS_AGE_GATE) by routing extraction through API endpoints that
+    /// don't show it. Helps with videos that fail as "login required" or
+    /// "age restricted" despite being publicly visible in the app — a
+    /// class of failures that doesn't actually need cookies. Off by
+    /// default because the alternate endpoints occasionally return
+    /// different format lists.
+    pub bypass_age_gate: bool,
+    /// Netscape-format cookie jar passed to every yt-dlp call via
+    /// --cookies (COOKIES_FILE). Mutually exclusive with
+    /// COOKIES_FROM_BROWSER.
+    pub cookies_file: Option<String>,
+    /// Browser whose cookie store yt-dlp reads via --cookies-from-browser,
+    /// e.g. "firefox" or "chrome:Profile 1" (COOKIES_FROM_BROWSER). Meant
+    /// for desktop deployments running next to a logged-in browser, where
+    /// it unlocks private and age-gated videos without exporting a cookie
+    /// file. Mutually exclusive with COOKIES_FILE.
+    pub cookies_from_browser: Option<String>,
+    /// Let callers supply their own session cookies per request for
+    /// private videos (ALLOW_REQUEST_COOKIES). Off by default: accepting
+    /// credentials from clients is sensitive even though we only write them
+    /// to a private temp file for the one yt-dlp call.
+    pub allow_request_cookies: bool,
+    /// Allow /api/system/ytdlp-version to query GitHub for the latest
+    /// yt-dlp release (YTDLP_UPDATE_CHECK). Off by default for air-gapped
+    /// deployments; without it only the local version is reported.
+    pub ytdlp_update_check: bool,
+    /// Keep the deprecated POST /api/video/download endpoint serving
+    /// (LEGACY_DOWNLOAD_ENABLED). When false it returns 410 Gone.
+    pub legacy_download_enabled: bool,
+    /// Give files produced by the trim/subtitle/mute paths an mtime equal
+    /// to the video's upload date instead of the processing time
+    /// (PRESERVE_TIMESTAMPS). Plain streamed downloads never touch a file,
+    /// so nothing applies there; yt-dlp already stamps its own downloads.
+    pub preserve_timestamps: bool,
+    /// Include subprocess diagnostics (yt-dlp exit code and stderr tail) in
+    /// error responses (VERBOSE_ERRORS). Off by default: stderr can leak
+    /// paths and URLs end users have no business seeing.
+    pub verbose_errors: bool,
+    /// Image composited onto downloads by /api/video/watermark
+    /// (WATERMARK_IMAGE). The endpoint 400s when unset; no other path
+    /// touches the file.
+    pub watermark_image: Option<String>,
+    /// JSON-lines audit log recording every served download
+    /// (AUDIT_LOG_FILE). Unset (the default) disables auditing.
+    pub audit_log_file: Option<String>,
+    /// Size in bytes at which the audit log is rotated aside to `.1`
+    /// (AUDIT_LOG_MAX_BYTES). 0 never rotates.
+    pub audit_log_max_bytes: u64,
+    /// API key required for admin endpoints (ADMIN_API_KEY). Disabled when unset.
+    pub admin_api_key: Option<String>,
+    /// Serve the profile info/download/stream endpoints at all
+    /// (PROFILE_DOWNLOADS_ENABLED). Operators worried about bulk scraping
+    /// can turn the whole surface off and offer single-video downloads
+    /// only; when false those routes return 403.
+    pub profile_downloads_enabled: bool,
+    /// Usernames whose profiles may be bulk-downloaded (PROFILE_ALLOWLIST,
+    /// comma-separated). Empty means every profile is allowed.
+    pub profile_allowlist: Vec<String>,
+    /// Usernames whose profiles may never be bulk-downloaded
+    /// (PROFILE_DENYLIST, comma-separated). Takes precedence over the
+    /// allowlist.
+    pub profile_denylist: Vec<String>,
+}
+
+/// Load-shedding behavior when MAX_CONCURRENT_DOWNLOADS is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadShedStrategy {
+    /// Fail fast with 503 so clients can back off immediately.
+    #[default]
+    Reject,
+    /// Hold the request briefly in hope a slot frees up; kinder to bursty
+    /// traffic at the cost of tying up a connection while it waits.
+    Queue,
+}
+
+impl std::str::FromStr for LoadShedStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(LoadShedStrategy::Reject),
+            "queue" => Ok(LoadShedStrategy::Queue),
+            _ => Err(()),
+        }
+    }
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().trim_start_matches('@').to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Case-insensitive allow/deny decision; deny wins, and an empty allowlist
+/// admits everyone.
+fn profile_allowed(username: &str, allowlist: &[String], denylist: &[String]) -> bool {
+    let username = username.to_lowercase();
+    if denylist.contains(&username) {
+        return false;
+    }
+    allowlist.is_empty() || allowlist.contains(&username)
+}
+
+fn env_or(key: &str, default: &str) -> String {
+    env::var(key).unwrap_or_else(|_| default.to_string())
+}
+
+fn env_parse_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// A TRUSTED_PROXIES entry: a CIDR range ("10.0.0.0/8") or a bare address.
+fn parse_proxy_net(s: &str) -> Option<ipnet::IpNet> {
+    if let Ok(net) = s.parse() {
+        return Some(net);
+    }
+    if let Ok(addr) = s.parse::<std::net::IpAddr>() {
+        return Some(ipnet::IpNet::from(addr));
+    }
+    tracing::warn!(value = %s, "ignoring invalid TRUSTED_PROXIES entry (use CIDR, e.g. 10.0.0.0/8)");
+    None
+}
+
+/// Browsers yt-dlp can extract cookies from. A COOKIES_FROM_BROWSER value
+/// may carry keyring/profile/container suffixes ("chrome+gnomekeyring:
+/// Profile 1::work"); only the leading browser name is checked.
+const SUPPORTED_COOKIE_BROWSERS: &[&str] = &[
+    "brave", "chrome", "chromium", "edge", "firefox", "opera", "safari", "vivaldi", "whale",
+];
+
+fn is_supported_cookie_browser(value: &str) -> bool {
+    let browser = value.split([':', '+']).next().unwrap_or("").to_lowercase();
+    SUPPORTED_COOKIE_BROWSERS.contains(&browser.as_str())
+}
+
+/// A rate limit is digits optionally followed by a K or M suffix.
+fn is_valid_rate_limit(value: &str) -> bool {
+    let digits = value
+        .strip_suffix(['K', 'M', 'k', 'm'])
+        .unwrap_or(value);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Both settings feed the same yt-dlp option; silently preferring one
+/// would mask an operator mistake, so refusing to start is kinder.
+fn reject_conflicting_cookie_sources(file: &Option<String>, browser: &Option<String>) {
+    if file.is_some() && browser.is_some() {
+        panic!("COOKIES_FILE and COOKIES_FROM_BROWSER are mutually exclusive; set only one");
+    }
+}
+
+impl AppConfig {
+    pub fn from_env() -> Self {
+        let config = Self {
+            host: env_or("HOST", "0.0.0.0"),
+            port: env_parse_or("PORT", 3000),
+            downloads_dir: env_or("DOWNLOADS_DIR", "./downloads"),
+            temp_dir: env_or("TEMP_DIR", "./tmp"),
+            max_file_size: env_parse_or("MAX_FILE_SIZE", 100 * 1024 * 1024),
+            max_profile_videos: env_parse_or("MAX_PROFILE_VIDEOS", 50),
+            ytdlp_timeout: env_parse_or("YTDLP_TIMEOUT", 300),
+            stream_buffer_size: env_parse_or("STREAM_BUFFER_SIZE", 64 * 1024),
+            max_concurrent_downloads: env_parse_or("MAX_CONCURRENT_DOWNLOADS", 4),
+            batch_concurrency: env_parse_or("BATCH_CONCURRENCY", 3),
+            per_profile_concurrency: env_parse_or("PER_PROFILE_CONCURRENCY", 2),
+            download_rate_limit: env::var("DOWNLOAD_RATE_LIMIT").ok().filter(|v| {
+                if is_valid_rate_limit(v) {
+                    true
+                } else {
+                    tracing::warn!(value = %v, "ignoring invalid DOWNLOAD_RATE_LIMIT (use e.g. 500K or 2M)");
+                    false
+                }
+            }),
+            load_shed_strategy: env_parse_or("LOAD_SHED_STRATEGY", LoadShedStrategy::Reject),
+            download_queue_timeout: env_parse_or("DOWNLOAD_QUEUE_TIMEOUT", 10),
+            request_jitter_ms: env_parse_or("REQUEST_JITTER_MS", 0),
+            scratch_dir: env::var("SCRATCH_DIR").ok().filter(|v| !v.is_empty()),
+            ytdlp_cache_dir: env::var("YTDLP_CACHE_DIR").ok().filter(|v| !v.is_empty()),
+            fallback_api_url: env::var("FALLBACK_API_URL").ok().filter(|v| !v.is_empty()),
+            rate_limit_per_minute: env_parse_or("RATE_LIMIT_PER_MINUTE", 30),
+            request_timeout_secs: env_parse_or("REQUEST_TIMEOUT_SECS", 60),
+            tcp_keepalive_secs: env_parse_or("TCP_KEEPALIVE_SECS", 60),
+            http_connect_timeout_secs: env_parse_or("HTTP_CONNECT_TIMEOUT_SECS", 10),
+            http_timeout_secs: env_parse_or("HTTP_TIMEOUT_SECS", 30),
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .filter_map(parse_proxy_net)
+                .collect(),
+            rate_limit_bypass: env::var("RATE_LIMIT_BYPASS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .filter_map(parse_proxy_net)
+                .collect(),
+            max_formats: env_parse_or("MAX_FORMATS", 5),
+            max_download_height: env::var("MAX_DOWNLOAD_HEIGHT")
+                .ok()
+                .and_then(|v| v.parse().ok()),
+            tiktok_cooldown_secs: env_parse_or("TIKTOK_COOLDOWN_SECS", 60),
+            recaptcha_secret: env::var("RECAPTCHA_SECRET").ok().filter(|s| !s.is_empty()),
+            recaptcha_fail_open: env_parse_or("RECAPTCHA_FAIL_OPEN", false),
+            tls_cert_path: env::var("TLS_CERT_PATH").ok().filter(|s| !s.is_empty()),
+            tls_key_path: env::var("TLS_KEY_PATH").ok().filter(|s| !s.is_empty()),
+            serve_downloads_dir: env_parse_or("SERVE_DOWNLOADS_DIR", false),
+            job_state_file: env::var("JOB_STATE_FILE").ok().filter(|s| !s.is_empty()),
+            filename_policy: env_parse_or("FILENAME_POLICY", FilenamePolicy::Ascii),
+            sendfile_header: env::var("SENDFILE_HEADER").ok().filter(|v| !v.is_empty()),
+            sendfile_location: env::var("SENDFILE_LOCATION")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "/protected-downloads".to_string()),
+            profile_list_soft_limit_secs: env_parse_or("PROFILE_LIST_SOFT_LIMIT_SECS", 60),
+            profile_zip_cache_ttl_secs: env_parse_or("PROFILE_ZIP_CACHE_TTL_SECS", 600),
+            max_concurrent_zip_builds: env_parse_or("MAX_CONCURRENT_ZIP_BUILDS", 2),
+            preferred_codecs: env::var("PREFERRED_CODECS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect(),
+            prefer_container: env::var("PREFER_CONTAINER").ok().filter(|v| !v.is_empty()),
+            allowed_audio_formats: env::var("ALLOWED_AUDIO_FORMATS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_lowercase)
+                .collect(),
+            bypass_age_gate: env_parse_or("BYPASS_AGE_GATE", false),
+            cookies_file: env::var("COOKIES_FILE").ok().filter(|s| !s.is_empty()),
+            cookies_from_browser: env::var("COOKIES_FROM_BROWSER")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .filter(|v| {
+                    if is_supported_cookie_browser(v) {
+                        true
+                    } else {
+                        tracing::warn!(value = %v, "ignoring COOKIES_FROM_BROWSER: unknown browser");
+                        false
+                    }
+                }),
+            allow_request_cookies: env_parse_or("ALLOW_REQUEST_COOKIES", false),
+            ytdlp_update_check: env_parse_or("YTDLP_UPDATE_CHECK", false),
+            legacy_download_enabled: env_parse_or("LEGACY_DOWNLOAD_ENABLED", true),
+            preserve_timestamps: env_parse_or("PRESERVE_TIMESTAMPS", true),
+            verbose_errors: env_parse_or("VERBOSE_ERRORS", false),
+            watermark_image: env::var("WATERMARK_IMAGE").ok().filter(|s| !s.is_empty()),
+            audit_log_file: env::var("AUDIT_LOG_FILE").ok().filter(|s| !s.is_empty()),
+            audit_log_max_bytes: env_parse_or("AUDIT_LOG_MAX_BYTES", 10 * 1024 * 1024),
+            admin_api_key: env::var("ADMIN_API_KEY").ok().filter(|s| !s.is_empty()),
+            profile_downloads_enabled: env_parse_or("PROFILE_DOWNLOADS_ENABLED", true),
+            profile_allowlist: env_list("PROFILE_ALLOWLIST"),
+            profile_denylist: env_list("PROFILE_DENYLIST"),
+        };
+        reject_conflicting_cookie_sources(&config.cookies_file, &config.cookies_from_browser);
+        config
+    }
+
+    pub fn recaptcha_enabled(&self) -> bool {
+        self.recaptcha_secret.is_some()
+    }
+
+    /// The cert and key paths, when the operator configured both. A lone
+    /// cert or key is a misconfiguration and gets a warning instead of a
+    /// silent fallback to HTTP.
+    pub fn tls_paths(&self) -> Option<(&str, &str)> {
+        match (self.tls_cert_path.as_deref(), self.tls_key_path.as_deref()) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => {
+                tracing::warn!(
+                    "TLS_CERT_PATH and TLS_KEY_PATH must both be set; serving plain HTTP"
+                );
+                None
+            }
+        }
+    }
+
+    /// Whether profile-level downloads are permitted for this username.
+    pub fn profile_access_allowed(&self, username: &str) -> bool {
+        profile_allowed(username, &self.profile_allowlist, &self.profile_denylist)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_lists_default_open_allow_and_deny() {
+        let none: Vec<String> = vec![];
+        let allow = vec!["alice".to_string()];
+        let deny = vec!["mallory".to_string()];
+
+        // Default open: no lists configured.
+        assert!(profile_allowed("anyone", &none, &none));
+        // Allowlist admits listed users only, case-insensitively.
+        assert!(profile_allowed("Alice", &allow, &none));
+        assert!(!profile_allowed("bob", &allow, &none));
+        // Denylist wins even over an allowlist entry.
+        assert!(!profile_allowed("Mallory", &none, &deny));
+        assert!(!profile_allowed("mallory", &[deny[0].clone()], &deny));
+    }
+
+    #[test]
+    fn tls_requires_both_cert_and_key() {
+        let mut config = AppConfig::from_env();
+        config.tls_cert_path = None;
+        config.tls_key_path = None;
+        assert!(config.tls_paths().is_none());
+        // A lone cert (or key) must not half-enable TLS.
+        config.tls_cert_path = Some("cert.pem".to_string());
+        assert!(config.tls_paths().is_none());
+        config.tls_key_path = Some("key.pem".to_string());
+        assert_eq!(config.tls_paths(), Some(("cert.pem", "key.pem")));
+    }
+
+    #[test]
+    fn cookie_browser_names_are_validated() {
+        assert!(is_supported_cookie_browser("firefox"));
+        assert!(is_supported_cookie_browser("Chrome:Profile 1"));
+        assert!(is_supported_cookie_browser("chrome+gnomekeyring"));
+        assert!(!is_supported_cookie_browser("netscape"));
+        assert!(!is_supported_cookie_browser(""));
+    }
+
+    #[test]
+    #[should_panic(expected = "mutually exclusive")]
+    fn both_cookie_sources_refuse_to_start() {
+        reject_conflicting_cookie_sources(
+            &Some("cookies.txt".to_string()),
+            &Some("firefox".to_string()),
+        );
+    }
+
+    #[test]
+    fn rate_limit_format_validation() {
+        assert!(is_valid_rate_limit("500K"));
+        assert!(is_valid_rate_limit("2M"));
+        assert!(is_valid_rate_limit("1048576"));
+        assert!(!is_valid_rate_limit(""));
+        assert!(!is_valid_rate_limit("2G"));
+        assert!(!is_valid_rate_limit("fast"));
+        assert!(!is_valid_rate_limit("K"));
+    }
+}
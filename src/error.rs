@@ -0,0 +1,192 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+/// Whether error bodies may include a `details` object with tool-level
+/// diagnostics. Set once at startup from VERBOSE_ERRORS; stderr tails can
+/// leak paths and URLs, so production keeps this off.
+static VERBOSE_ERRORS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbose_errors(enabled: bool) {
+    VERBOSE_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+/// Tool-level diagnostics attached to unclassified yt-dlp failures.
+#[derive(Debug, Clone, Serialize)]
+pub struct YtDlpDiagnostics {
+    /// yt-dlp's own exit code; absent when it was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// The last few stderr lines, newest first.
+    pub stderr_tail: String,
+}
+
+/// Application-level error returned by handlers and the service layer.
+///
+/// Each variant maps to a single HTTP status code; the `IntoResponse` impl
+/// produces the uniform JSON error body the frontend expects.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request itself was malformed or referenced something invalid (400).
+    BadRequest(String),
+    /// The caller is not allowed to do this (401).
+    Unauthorized(String),
+    /// The action is understood but disabled or off-limits here (403).
+    Forbidden(String),
+    /// The requested resource does not exist (404).
+    NotFound(String),
+    /// The video itself is gone from TikTok — deleted, banned or otherwise
+    /// unextractable (404).
+    VideoUnavailable(String),
+    /// The resource existed but has been retired or cleaned up (410).
+    Gone(String),
+    /// An upstream rate limit was hit; the client should wait before
+    /// retrying (429, with a Retry-After header).
+    TooManyRequests { message: String, retry_after: u64 },
+    /// The server is overloaded or a required dependency is down (503).
+    ServiceUnavailable(String),
+    /// Anything unexpected, including yt-dlp failures we cannot classify (500).
+    /// `details` carries yt-dlp diagnostics when the failure came from a
+    /// subprocess; it only reaches the response body with VERBOSE_ERRORS on.
+    Internal {
+        message: String,
+        details: Option<YtDlpDiagnostics>,
+    },
+}
+
+/// JSON error body shared by every error response.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub error: String,
+    pub message: String,
+    pub code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<YtDlpDiagnostics>,
+}
+
+impl AppError {
+    /// An internal error with no subprocess diagnostics; the common case.
+    pub fn internal(message: impl Into<String>) -> Self {
+        AppError::Internal {
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) | AppError::VideoUnavailable(_) => StatusCode::NOT_FOUND,
+            AppError::Gone(_) => StatusCode::GONE,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_kind(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::VideoUnavailable(_) => "video_unavailable",
+            AppError::Gone(_) => "gone",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::ServiceUnavailable(_) => "service_unavailable",
+            AppError::Internal { .. } => "internal_error",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            AppError::BadRequest(m)
+            | AppError::Unauthorized(m)
+            | AppError::Forbidden(m)
+            | AppError::NotFound(m)
+            | AppError::VideoUnavailable(m)
+            | AppError::Gone(m)
+            | AppError::ServiceUnavailable(m) => m,
+            AppError::Internal { message, .. } => message,
+            AppError::TooManyRequests { message, .. } => message,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.error_kind(), self.message())
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl AppError {
+    fn api_body(&self) -> ApiError {
+        let details = match self {
+            AppError::Internal { details, .. } if VERBOSE_ERRORS.load(Ordering::Relaxed) => {
+                details.clone()
+            }
+            _ => None,
+        };
+        ApiError {
+            error: self.error_kind().to_string(),
+            message: self.message().to_string(),
+            code: self.status_code().as_u16(),
+            details,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        let body = self.api_body();
+        let mut response = (status, Json(body)).into_response();
+        if let AppError::TooManyRequests { retry_after, .. } = self {
+            if let Ok(value) = retry_after.to_string().parse() {
+                response
+                    .headers_mut()
+                    .insert(axum::http::header::RETRY_AFTER, value);
+            }
+        }
+        response
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        AppError::internal(format!("I/O error: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn details_only_appear_with_verbose_errors_on() {
+        let err = AppError::Internal {
+            message: "yt-dlp failed".to_string(),
+            details: Some(YtDlpDiagnostics {
+                exit_code: Some(1),
+                stderr_tail: "ERROR: something".to_string(),
+            }),
+        };
+
+        set_verbose_errors(false);
+        assert!(err.api_body().details.is_none());
+
+        set_verbose_errors(true);
+        let details = err.api_body().details.expect("details with verbose on");
+        assert_eq!(details.exit_code, Some(1));
+        assert!(details.stderr_tail.contains("ERROR"));
+        set_verbose_errors(false);
+    }
+}
@@ -0,0 +1,3227 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    audit::{AuditContext, AuditedStream},
+    config::LoadShedStrategy,
+    error::AppError,
+    rate_limit::ClientIp,
+    models::{
+        AudioStreamQuery, BatchInfoRequest, BatchInfoResult, BundleRequest, CoverQuery,
+        DirectUrlQuery, ManifestQuery, ManifestResponse, MuxedStreamQuery,
+        DirectUrlResponse, EstimateResult, JobStatus,
+        ProfileDownloadRequest, ProfileDownloadResponse, ProfileExportRequest, ProfileInfo,
+        ProfileInfoRequest,
+        PhotoImageQuery, ProfileJob, ProfileStats, ProfileStatsRequest, ProfileStreamQuery,
+        SelectedDownloadRequest, StreamDownloadQuery,
+        TranscriptQuery, ValidateRequest, ValidateResult, WatermarkQuery,
+        DebugFormatsQuery, DebugResolveQuery, ThumbnailProxyQuery, VideoDownloadRequest, VideoInfo,
+        VideoInfoRequest, ZipPart,
+    },
+    service::{
+        apply_upload_mtime, run_bounded, select_format_by_size, ytdlp_output_template,
+        BundleOutput, CookieFile, MediaInfo,
+        WatermarkPosition, BEST_SINGLE_SELECTOR, VIDEO_ONLY_SELECTOR,
+    },
+    url_validator::{
+        classify_url, extract_username, is_live_url, is_valid_profile_url, is_valid_tiktok_url,
+        title_or_id, UrlKind,
+    },
+    AppState,
+};
+
+/// Monotonic counter used to keep generated download filenames unique and
+/// to count downloads served over the server's lifetime. Holds the next
+/// number to hand out; persisted to the downloads directory so neither
+/// uniqueness nor the total resets on restart.
+static DOWNLOAD_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+fn counter_path(config: &crate::config::AppConfig) -> PathBuf {
+    PathBuf::from(&config.downloads_dir).join(".download_count")
+}
+
+/// Claim the next download number and persist the new counter value.
+fn next_download_number(config: &crate::config::AppConfig) -> u64 {
+    let number = DOWNLOAD_COUNTER.fetch_add(1, Ordering::SeqCst);
+    // Serialize writers so a slow write can't clobber a newer value.
+    static WRITE_LOCK: Mutex<()> = Mutex::new(());
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let value = DOWNLOAD_COUNTER.load(Ordering::SeqCst);
+    if let Err(e) = std::fs::write(counter_path(config), value.to_string()) {
+        tracing::warn!(error = %e, "failed to persist download counter");
+    }
+    number
+}
+
+/// The number the next download will get, without consuming it. HEAD
+/// requests use this so probing doesn't burn filenames.
+fn peek_download_number() -> u64 {
+    DOWNLOAD_COUNTER.load(Ordering::SeqCst)
+}
+
+/// Reload the persisted counter at startup so filenames stay unique and the
+/// lifetime download total survives restarts.
+pub fn restore_download_counter(config: &crate::config::AppConfig) {
+    match std::fs::read_to_string(counter_path(config)) {
+        Ok(body) => {
+            if let Ok(value) = body.trim().parse::<u64>() {
+                DOWNLOAD_COUNTER.store(value.max(1), Ordering::SeqCst);
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => tracing::warn!(error = %e, "failed to read persisted download counter"),
+    }
+}
+
+/// Registry of asynchronous profile download jobs, keyed by download id.
+static JOB_REGISTRY: Lazy<Mutex<HashMap<String, ProfileJob>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Idempotency-Key header values seen recently, mapping to the request
+/// fingerprint they were first used with and the job they started. Retried
+/// POSTs with the same key get the original download id back instead of a
+/// second full profile download.
+static IDEMPOTENCY_KEYS: Lazy<Mutex<HashMap<String, IdempotencyEntry>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// How long an Idempotency-Key keeps pointing at its job.
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct IdempotencyEntry {
+    fingerprint: String,
+    download_id: String,
+    created_at: Instant,
+}
+
+/// Look up a prior job for this key, expiring stale entries along the way.
+/// A key reused with a different request body is an error rather than a
+/// silent replay of the old job.
+fn idempotent_job_id(key: &str, fingerprint: &str) -> Result<Option<String>, AppError> {
+    let mut keys = IDEMPOTENCY_KEYS.lock().unwrap();
+    keys.retain(|_, entry| entry.created_at.elapsed() < IDEMPOTENCY_TTL);
+    match keys.get(key) {
+        Some(entry) if entry.fingerprint == fingerprint => Ok(Some(entry.download_id.clone())),
+        Some(_) => Err(AppError::BadRequest(
+            "Idempotency-Key was already used with a different request".to_string(),
+        )),
+        None => Ok(None),
+    }
+}
+
+fn remember_idempotency_key(key: &str, fingerprint: &str, download_id: &str) {
+    IDEMPOTENCY_KEYS.lock().unwrap().insert(
+        key.to_string(),
+        IdempotencyEntry {
+            fingerprint: fingerprint.to_string(),
+            download_id: download_id.to_string(),
+            created_at: Instant::now(),
+        },
+    );
+}
+
+/// Hosts we are willing to proxy thumbnails from, to keep the proxy from
+/// becoming an open relay.
+const ALLOWED_THUMBNAIL_HOSTS: &[&str] = &["tiktokcdn.com", "tiktokcdn-us.com", "ttwstatic.com"];
+
+const MAX_BATCH_URLS: usize = 10;
+
+/// Cap for /api/validate. Classification is pure string matching, so the
+/// limit is generous compared to the yt-dlp-backed batch endpoints.
+const MAX_VALIDATE_URLS: usize = 500;
+
+/// One-time probe of the external tools, shared by every /api/capabilities
+/// call; tool availability doesn't change while the server runs.
+static TOOL_PROBE: tokio::sync::OnceCell<ToolProbe> = tokio::sync::OnceCell::const_new();
+
+#[derive(Debug, Clone)]
+struct ToolProbe {
+    ffmpeg_available: bool,
+    ffmpeg_version: Option<String>,
+    ytdlp_version: Option<String>,
+}
+
+/// Feature flags and limits the frontend can adapt to instead of hardcoding
+/// assumptions about the backend.
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    pub recaptcha_enabled: bool,
+    pub ffmpeg_available: bool,
+    pub ffmpeg_version: Option<String>,
+    pub ytdlp_version: Option<String>,
+    pub max_profile_videos: usize,
+    pub max_batch_urls: usize,
+    pub supported_audio_formats: Vec<String>,
+    pub legacy_download_enabled: bool,
+}
+
+impl Capabilities {
+    fn new(config: &crate::config::AppConfig, probe: &ToolProbe) -> Self {
+        Self {
+            recaptcha_enabled: config.recaptcha_enabled(),
+            ffmpeg_available: probe.ffmpeg_available,
+            ffmpeg_version: probe.ffmpeg_version.clone(),
+            ytdlp_version: probe.ytdlp_version.clone(),
+            max_profile_videos: config.max_profile_videos,
+            max_batch_urls: MAX_BATCH_URLS,
+            supported_audio_formats: SUPPORTED_AUDIO_FORMATS
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            legacy_download_enabled: config.legacy_download_enabled,
+        }
+    }
+}
+
+pub async fn capabilities(
+    State(state): State<AppState>,
+) -> Result<Json<Capabilities>, AppError> {
+    let probe = TOOL_PROBE
+        .get_or_init(|| async {
+            let ffmpeg_version = state.service.check_ffmpeg_availability().await.ok();
+            ToolProbe {
+                ffmpeg_available: ffmpeg_version.is_some(),
+                ffmpeg_version,
+                ytdlp_version: state.service.check_ytdlp_availability().await.ok(),
+            }
+        })
+        .await;
+    Ok(Json(Capabilities::new(&state.config, probe)))
+}
+
+/// Latest yt-dlp release tag fetched from GitHub, cached for an hour so the
+/// endpoint can't be used to hammer their API.
+static LATEST_YTDLP_RELEASE: Lazy<Mutex<Option<(Instant, String)>>> =
+    Lazy::new(|| Mutex::new(None));
+
+const LATEST_RELEASE_TTL: Duration = Duration::from_secs(60 * 60);
+
+const YTDLP_RELEASES_URL: &str =
+    "https://api.github.com/repos/yt-dlp/yt-dlp/releases/latest";
+
+#[derive(Debug, Serialize)]
+pub struct YtdlpVersionResponse {
+    /// Version string from `yt-dlp --version`.
+    pub version: String,
+    /// Latest released version, when the remote check is enabled and worked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub update_available: Option<bool>,
+}
+
+/// TikTok breaks regularly and the usual fix is a newer yt-dlp; this lets
+/// operators check staleness without shelling into the box.
+pub async fn ytdlp_version(
+    State(state): State<AppState>,
+) -> Result<Json<YtdlpVersionResponse>, AppError> {
+    let service = &state.service;
+    let version = service.check_ytdlp_availability().await?;
+
+    let latest_version = if state.config.ytdlp_update_check {
+        fetch_latest_ytdlp_release().await
+    } else {
+        None
+    };
+    let update_available = latest_version.as_ref().map(|latest| *latest != version);
+
+    Ok(Json(YtdlpVersionResponse {
+        version,
+        latest_version,
+        update_available,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct FfmpegVersionResponse {
+    pub available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// Whether ffmpeg-backed features (trimming, audio conversion, remux,
+/// muxed streaming) will work here, and which build is installed. Absence
+/// is an answer, not an error, so this always returns 200.
+pub async fn ffmpeg_version(State(state): State<AppState>) -> Json<FfmpegVersionResponse> {
+    let version = state.service.check_ffmpeg_availability().await.ok();
+    Json(FfmpegVersionResponse {
+        available: version.is_some(),
+        version,
+    })
+}
+
+/// Latest release tag from GitHub, served from the hourly cache when fresh.
+/// Failures are logged and reported as "unknown" rather than failing the
+/// whole endpoint.
+async fn fetch_latest_ytdlp_release() -> Option<String> {
+    if let Some((fetched_at, tag)) = LATEST_YTDLP_RELEASE.lock().unwrap().as_ref() {
+        if fetched_at.elapsed() < LATEST_RELEASE_TTL {
+            return Some(tag.clone());
+        }
+    }
+
+    let response = crate::service::shared_http_client()
+        .get(YTDLP_RELEASES_URL)
+        .header(header::USER_AGENT, "tiktok-downloader")
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await;
+    let tag = match response {
+        Ok(response) => response
+            .json::<serde_json::Value>()
+            .await
+            .ok()
+            .and_then(|body| body.get("tag_name")?.as_str().map(str::to_string)),
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to check latest yt-dlp release");
+            None
+        }
+    }?;
+
+    *LATEST_YTDLP_RELEASE.lock().unwrap() = Some((Instant::now(), tag.clone()));
+    Some(tag)
+}
+
+/// Guard for admin endpoints: the x-api-key header must match ADMIN_API_KEY.
+/// With no key configured, admin endpoints stay hidden rather than open.
+fn require_admin(config: &crate::config::AppConfig, headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(expected) = config.admin_api_key.as_deref() else {
+        return Err(AppError::NotFound(
+            "Admin endpoints are not enabled on this server".to_string(),
+        ));
+    };
+    let provided = headers.get("x-api-key").and_then(|v| v.to_str().ok());
+    if provided == Some(expected) {
+        Ok(())
+    } else {
+        Err(AppError::Unauthorized(
+            "A valid x-api-key header is required".to_string(),
+        ))
+    }
+}
+
+/// Verbatim `yt-dlp --list-formats` output for troubleshooting; unlike the
+/// parsed listing in /api/video/info nothing is filtered or reshaped.
+#[derive(Debug, Serialize)]
+pub struct DebugFormatsResponse {
+    pub url: String,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Admin-only: show exactly what yt-dlp sees for a URL, warnings and all.
+/// The filtered formats list hides most of the table, which is the right
+/// call for users and the wrong one for debugging extraction breakage.
+pub async fn debug_formats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<DebugFormatsQuery>,
+) -> Result<Json<DebugFormatsResponse>, AppError> {
+    require_admin(&state.config, &headers)?;
+    validate_video_url(&query.url)?;
+    let (stdout, stderr, exit_code) = state.service.list_formats_raw(&query.url).await?;
+    Ok(Json(DebugFormatsResponse {
+        url: query.url,
+        exit_code,
+        stdout,
+        stderr,
+    }))
+}
+
+/// Where a short link actually goes, hop by hop. `chain` starts with the
+/// requested URL and ends with `final_url`.
+#[derive(Debug, Serialize)]
+pub struct DebugResolveResponse {
+    pub chain: Vec<String>,
+    pub final_url: String,
+    /// Whether the final URL would pass this server's TikTok URL check.
+    pub is_tiktok_url: bool,
+}
+
+/// Redirect chains are short in practice (vm.tiktok.com resolves in one
+/// hop); past this something is looping.
+const MAX_RESOLVE_HOPS: usize = 10;
+
+/// Admin-only: walk a URL's redirect chain one Location at a time and
+/// report every hop. The normal flow follows redirects silently inside
+/// reqwest, which is exactly what you don't want when a short link lands
+/// somewhere unexpected.
+pub async fn debug_resolve(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<DebugResolveQuery>,
+) -> Result<Json<DebugResolveResponse>, AppError> {
+    require_admin(&state.config, &headers)?;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(15))
+        .build()
+        .map_err(|e| AppError::internal(format!("Failed to build HTTP client: {e}")))?;
+    let chain = follow_redirect_chain(&client, &query.url, MAX_RESOLVE_HOPS).await?;
+    let final_url = chain.last().cloned().unwrap_or_else(|| query.url.clone());
+    let is_tiktok_url = validate_video_url(&final_url).is_ok();
+    Ok(Json(DebugResolveResponse {
+        chain,
+        final_url,
+        is_tiktok_url,
+    }))
+}
+
+/// Issue plain GETs with redirects disabled, collecting each Location
+/// until a non-redirect response or the hop limit. Relative Locations are
+/// resolved against the URL that served them, as a browser would.
+async fn follow_redirect_chain(
+    client: &reqwest::Client,
+    start: &str,
+    max_hops: usize,
+) -> Result<Vec<String>, AppError> {
+    let mut current = url::Url::parse(start)
+        .map_err(|_| AppError::BadRequest("Invalid URL".to_string()))?;
+    if !matches!(current.scheme(), "http" | "https") {
+        return Err(AppError::BadRequest(
+            "Only http and https URLs can be resolved".to_string(),
+        ));
+    }
+    let mut chain = vec![current.to_string()];
+    for _ in 0..max_hops {
+        let response = client
+            .get(current.clone())
+            .send()
+            .await
+            .map_err(|e| AppError::BadRequest(format!("Failed to fetch {current}: {e}")))?;
+        if !response.status().is_redirection() {
+            return Ok(chain);
+        }
+        let Some(location) = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return Ok(chain);
+        };
+        current = current
+            .join(location)
+            .map_err(|_| AppError::BadRequest(format!("Unparseable Location header: {location}")))?;
+        chain.push(current.to_string());
+    }
+    Err(AppError::BadRequest(format!(
+        "Gave up after {max_hops} redirects; the chain appears to loop"
+    )))
+}
+
+pub async fn health() -> impl IntoResponse {
+    Json(json!({
+        "status": "ok",
+        // Counter holds the next number; totals served is one less.
+        "total_downloads": DOWNLOAD_COUNTER.load(Ordering::SeqCst) - 1,
+    }))
+}
+
+/// Build a Content-Disposition value. Plain-ASCII names use the simple
+/// `filename=` form; anything else additionally carries an RFC 5987
+/// `filename*=UTF-8''...` parameter so browsers restore the original
+/// unicode name, with an ASCII fallback for clients that ignore it.
+/// Names are scrubbed first: titles reach us from yt-dlp, and a quote or
+/// CRLF smuggled through the quoted-string would corrupt (or inject)
+/// headers.
+fn content_disposition_value(disposition: &str, filename: &str) -> String {
+    let filename = header_safe_filename(filename);
+    if filename.is_ascii() {
+        return format!("{disposition}; filename=\"{filename}\"");
+    }
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    format!(
+        "{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        rfc5987_encode(&filename)
+    )
+}
+
+/// Drop control characters (CR/LF included) and neutralize the two
+/// characters with meaning inside an HTTP quoted-string.
+fn header_safe_filename(filename: &str) -> String {
+    filename
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Percent-encode everything outside RFC 5987's attr-char set.
+fn rfc5987_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'0'..=b'9'
+            | b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+/// Turn a request-supplied cookie string into a temp cookie jar, honoring
+/// the server-level opt-in. The jar deletes itself when dropped.
+fn request_cookie_file(
+    config: &crate::config::AppConfig,
+    cookies: Option<&str>,
+) -> Result<Option<CookieFile>, AppError> {
+    match cookies {
+        None => Ok(None),
+        Some(_) if !config.allow_request_cookies => Err(AppError::BadRequest(
+            "Per-request cookies are not enabled on this server".to_string(),
+        )),
+        Some(contents) => Ok(Some(CookieFile::write(contents)?)),
+    }
+}
+
+/// Server-side quality ceiling: formats taller than the configured maximum
+/// are refused, regardless of what yt-dlp lists for the video.
+fn format_within_height_cap(height: Option<u32>, cap: Option<u32>) -> bool {
+    match (height, cap) {
+        (Some(height), Some(cap)) => height <= cap,
+        _ => true,
+    }
+}
+
+fn validate_video_url(url: &str) -> Result<(), AppError> {
+    if is_live_url(url) {
+        return Err(AppError::BadRequest(
+            "LIVE streams and LIVE replays are not supported; only regular video posts can be downloaded"
+                .to_string(),
+        ));
+    }
+    if is_valid_tiktok_url(url) {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(
+            "Invalid TikTok video URL".to_string(),
+        ))
+    }
+}
+
+fn validate_profile_url(url: &str) -> Result<(), AppError> {
+    if is_valid_profile_url(url) {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(
+            "Invalid TikTok profile URL".to_string(),
+        ))
+    }
+}
+
+pub async fn video_info(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    headers: HeaderMap,
+    Json(request): Json<VideoInfoRequest>,
+) -> Result<Response, AppError> {
+    // Playlist URLs are welcome here — they come back as a listing — but
+    // everything else goes through the usual single-video validation.
+    if !matches!(classify_url(&request.url), UrlKind::Playlist) {
+        validate_video_url(&request.url)?;
+    }
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    // Cache-Control: no-cache is the header-level spelling of refresh=true.
+    let no_cache = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.to_lowercase().contains("no-cache"));
+    let cookie_file = request_cookie_file(&state.config, request.cookies.as_deref())?;
+    let service = &state.service;
+    let media = service
+        .get_media_info_with_cookies(
+            &request.url,
+            request.refresh || no_cache,
+            cookie_file.as_ref(),
+        )
+        .await?;
+    Ok(match media {
+        MediaInfo::Single(info) => {
+            let mut info = *info;
+            if request.inline_thumbnails {
+                inline_video_thumbnails(&mut info).await;
+            }
+            Json(info).into_response()
+        }
+        // URLs yt-dlp expands into multiple entries get the same compact
+        // shape as profile listings.
+        MediaInfo::Playlist(mut entries) => {
+            if request.inline_thumbnails {
+                inline_listing_thumbnails(&mut entries).await;
+            }
+            Json(entries).into_response()
+        }
+    })
+}
+
+pub async fn batch_info(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Json(request): Json<BatchInfoRequest>,
+) -> Result<Json<Vec<BatchInfoResult>>, AppError> {
+    if request.urls.is_empty() || request.urls.len() > MAX_BATCH_URLS {
+        return Err(AppError::BadRequest(format!(
+            "Provide between 1 and {MAX_BATCH_URLS} URLs"
+        )));
+    }
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let service = &state.service;
+
+    let results = run_bounded(
+        request.urls.iter().cloned(),
+        state.config.batch_concurrency,
+        |url| {
+            let service = &service;
+            async move {
+                validate_video_url(&url)?;
+                service.get_video_info(&url).await
+            }
+        },
+    )
+    .await;
+
+    let body = request
+        .urls
+        .into_iter()
+        .zip(results)
+        .map(|(url, result)| match result {
+            Ok(info) => BatchInfoResult {
+                url,
+                info: Some(info),
+                error: None,
+            },
+            Err(e) => BatchInfoResult {
+                url,
+                info: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+    Ok(Json(body))
+}
+
+pub async fn batch_estimate(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Json(request): Json<BatchInfoRequest>,
+) -> Result<Json<Vec<EstimateResult>>, AppError> {
+    if request.urls.is_empty() || request.urls.len() > MAX_BATCH_URLS {
+        return Err(AppError::BadRequest(format!(
+            "Provide between 1 and {MAX_BATCH_URLS} URLs"
+        )));
+    }
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let service = &state.service;
+
+    let results = run_bounded(
+        request.urls.iter().cloned(),
+        state.config.batch_concurrency,
+        |url| {
+            let service = &service;
+            async move {
+                validate_video_url(&url)?;
+                service.estimate_video_size(&url).await
+            }
+        },
+    )
+    .await;
+
+    let body = request
+        .urls
+        .into_iter()
+        .zip(results)
+        .map(|(url, result)| match result {
+            Ok(filesize) => EstimateResult {
+                url,
+                filesize,
+                error: None,
+            },
+            Err(e) => EstimateResult {
+                url,
+                filesize: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+    Ok(Json(body))
+}
+
+/// Validate the optional `disposition` query param, defaulting to
+/// "attachment" so existing clients keep forcing a download.
+fn parse_disposition(raw: Option<&str>) -> Result<&'static str, AppError> {
+    match raw.unwrap_or("attachment") {
+        "attachment" => Ok("attachment"),
+        "inline" => Ok("inline"),
+        other => Err(AppError::BadRequest(format!(
+            "Invalid disposition '{other}'; use \"attachment\" or \"inline\""
+        ))),
+    }
+}
+
+/// Take a global download slot according to the configured load-shedding
+/// strategy.
+async fn acquire_download_permit(
+    state: &AppState,
+) -> Result<tokio::sync::OwnedSemaphorePermit, AppError> {
+    acquire_download_slot(
+        state.config.load_shed_strategy,
+        Duration::from_secs(state.config.download_queue_timeout),
+        state.download_semaphore.clone(),
+    )
+    .await
+}
+
+/// Reject fails fast; queue waits up to `wait` for a running download to
+/// finish before giving up with the same 503.
+async fn acquire_download_slot(
+    strategy: LoadShedStrategy,
+    wait: Duration,
+    semaphore: std::sync::Arc<tokio::sync::Semaphore>,
+) -> Result<tokio::sync::OwnedSemaphorePermit, AppError> {
+    let busy = || {
+        AppError::ServiceUnavailable(
+            "Too many downloads in progress, try again shortly".to_string(),
+        )
+    };
+    match strategy {
+        LoadShedStrategy::Reject => semaphore.try_acquire_owned().map_err(|_| busy()),
+        LoadShedStrategy::Queue => tokio::time::timeout(wait, semaphore.acquire_owned())
+            .await
+            .map_err(|_| busy())?
+            .map_err(|_| busy()),
+    }
+}
+
+/// Characters a yt-dlp format selector may contain. Command runs without a
+/// shell, so this is defense in depth against selectors ending up in logs,
+/// filenames or a future shell invocation, not a load-bearing barrier.
+fn validated_format_selector(expression: &str) -> Result<String, AppError> {
+    let allowed = |c: char| {
+        c.is_ascii_alphanumeric()
+            || matches!(
+                c,
+                '[' | ']' | '(' | ')' | '<' | '>' | '=' | '+' | '/' | ',' | '.' | '*' | '?'
+                    | '!' | ':' | '^' | '-' | '_' | ' '
+            )
+    };
+    if expression.is_empty() || expression.len() > 256 || !expression.chars().all(allowed) {
+        return Err(AppError::BadRequest(
+            "format_selector contains unsupported characters".to_string(),
+        ));
+    }
+    Ok(expression.to_string())
+}
+
+/// The format used when the caller names none: the top of the parsed
+/// list, which the service already orders best-first.
+fn default_format(formats: &[crate::models::FormatOption]) -> Option<&crate::models::FormatOption> {
+    formats.first()
+}
+
+/// Shared implementation behind the GET streaming endpoint and the
+/// deprecated POST download endpoint. Every parameter mirrors one query
+/// field, so the count grows with the endpoint's surface; bundling them
+/// into a struct would just rename the problem.
+#[allow(clippy::too_many_arguments)]
+async fn stream_video_response(
+    state: &AppState,
+    client_ip: IpAddr,
+    url: &str,
+    format_id: Option<&str>,
+    format_selector: Option<&str>,
+    audio_track: Option<&str>,
+    best_quality: bool,
+    embed_subs: bool,
+    mute: bool,
+    strip_metadata: bool,
+    container: Option<&str>,
+    target_filesize: Option<u64>,
+    sub_langs: Option<&str>,
+    cookies: Option<&str>,
+    disposition: &str,
+    trim: (Option<f64>, Option<f64>),
+    recaptcha_token: Option<&str>,
+) -> Result<Response, AppError> {
+    validate_video_url(url)?;
+    let container = container.map(validated_container).transpose()?;
+    state
+        .recaptcha
+        .verify_token(recaptcha_token, Some(&client_ip.to_string()))
+        .await?;
+
+    let permit = acquire_download_permit(state).await?;
+
+    let cookie_file = request_cookie_file(&state.config, cookies)?;
+    let service = &state.service;
+    let info = service
+        .get_video_info_with_cookies(url, false, cookie_file.as_ref())
+        .await?;
+
+    // best_quality overrides format_id with the archival selector; without
+    // ffmpeg the merge is impossible, so degrade to the best single stream
+    // and say so in a response header.
+    let mut quality_note = None;
+    // Plainly streamed formats keep their real container; every ffmpeg
+    // path and selector merge below produces mp4.
+    let mut download_ext = "mp4".to_string();
+    // Separate-track selectors cannot ride the raw stdout pipe — yt-dlp
+    // refuses to merge to '-' — so when one is chosen the plain streaming
+    // path at the bottom switches to the ffmpeg mux pipeline. The merged
+    // selector itself is still what the file-based branches (trim, remux,
+    // …) pass to yt-dlp, which merges into a file just fine.
+    let mut needs_mux = false;
+    let mut mux_track: Option<String> = None;
+    let selector = if let Some(expression) = format_selector {
+        // A raw selector bypasses the parsed-format lookup (it's dynamic by
+        // nature) and with it the height cap; yt-dlp reports any errors.
+        validated_format_selector(expression)?
+    } else if let Some(track) = audio_track {
+        // An explicit dub: best video muxed with the chosen audio-only
+        // track. The track must be one the info pass actually listed.
+        if !info.audio_tracks.iter().any(|t| t.format_id == track) {
+            return Err(AppError::BadRequest(format!(
+                "Unknown audio track '{track}' for this video"
+            )));
+        }
+        if !service.ffmpeg_available().await {
+            return Err(AppError::BadRequest(
+                "Selecting an audio track requires ffmpeg, which is not installed on this server"
+                    .to_string(),
+            ));
+        }
+        needs_mux = true;
+        mux_track = Some(track.to_string());
+        format!("bestvideo+{track}")
+    } else if best_quality {
+        if service.ffmpeg_available().await {
+            needs_mux = true;
+            service.best_quality_selector()
+        } else {
+            quality_note =
+                Some("ffmpeg unavailable; served best single stream without muxing");
+            BEST_SINGLE_SELECTOR.to_string()
+        }
+    } else {
+        // target_filesize picks the format for the caller; an explicit
+        // format_id is looked up; with neither, the best parsed format
+        // wins so a bare URL is a complete request. Either way the height
+        // cap and size limit below apply to what was chosen.
+        let format = if let Some(target) = target_filesize {
+            Some(select_format_by_size(&info.formats, target).ok_or_else(|| {
+                AppError::BadRequest("No downloadable formats are available for this video".to_string())
+            })?)
+        } else if let Some(format_id) = format_id {
+            Some(
+                info.formats
+                    .iter()
+                    .find(|f| f.format_id == format_id)
+                    .ok_or_else(|| {
+                        AppError::BadRequest(format!(
+                            "Unknown format_id '{format_id}' for this video"
+                        ))
+                    })?,
+            )
+        } else {
+            default_format(&info.formats)
+        };
+        match format {
+            Some(format) => {
+                if !format_within_height_cap(format.height, state.config.max_download_height) {
+                    return Err(AppError::BadRequest(format!(
+                        "Requested format exceeds the allowed maximum of {}p",
+                        state.config.max_download_height.unwrap_or_default()
+                    )));
+                }
+                let max_file_size = state.config.max_file_size;
+                if max_file_size > 0 && format.filesize.is_some_and(|size| size > max_file_size) {
+                    return Err(AppError::BadRequest(format!(
+                        "This format is larger than the {max_file_size}-byte download limit"
+                    )));
+                }
+                download_ext = format.ext.clone();
+                format.format_id.clone()
+            }
+            // Nothing parsed at all: let yt-dlp's own "best" decide.
+            None => BEST_SINGLE_SELECTOR.to_string(),
+        }
+    };
+
+    let counter = next_download_number(&state.config);
+    let title = title_or_id(&info.title, &info.id, state.config.filename_policy);
+
+    // Mute serves the video track only. A real video-only format streams
+    // directly; muxed-only videos (the usual TikTok case) get the audio
+    // stripped by ffmpeg, which means the file-then-stream path.
+    if mute {
+        if trim != (None, None) || embed_subs {
+            return Err(AppError::BadRequest(
+                "mute cannot be combined with trimming or embed_subs".to_string(),
+            ));
+        }
+        let filename = format!("{title}_{counter}_muted.mp4");
+        if info.formats.iter().any(|f| f.video_only) {
+            let stream =
+                service.spawn_video_stream(url, VIDEO_ONLY_SELECTOR, cookie_file.as_ref())?;
+            let body = audited_body(
+                state,
+                client_ip,
+                "/api/video/stream",
+                url,
+                VIDEO_ONLY_SELECTOR,
+                stream.map(move |chunk| {
+                    let _permit = &permit;
+                    let _cookies = &cookie_file;
+                    chunk
+                }),
+            );
+            return Ok((
+                [
+                    (header::CONTENT_TYPE, "video/mp4".to_string()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        content_disposition_value(disposition, &filename),
+                    ),
+                ],
+                body,
+            )
+                .into_response());
+        }
+        if !service.ffmpeg_available().await {
+            return Err(AppError::BadRequest(
+                "Removing the audio track requires ffmpeg, which is not installed on this server"
+                    .to_string(),
+            ));
+        }
+        let path = service.download_muted_video(url, &selector).await?;
+        if state.config.preserve_timestamps {
+            apply_upload_mtime(&path, info.upload_date.as_deref());
+        }
+        // Open first, then remove the session dir; see the trim path.
+        let file = tokio::fs::File::open(&path).await?;
+        if let Some(session_dir) = path.parent() {
+            let _ = std::fs::remove_dir_all(session_dir);
+        }
+        let body = audited_body(
+            state,
+            client_ip,
+            "/api/video/stream",
+            url,
+            &selector,
+            tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+                let _permit = &permit;
+                chunk
+            }),
+        );
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "video/mp4".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    content_disposition_value(disposition, &filename),
+                ),
+            ],
+            body,
+        )
+            .into_response());
+    }
+
+    // Metadata scrubbing rewrites the container with ffmpeg, so like mute
+    // it trades the fast piped path for file-then-stream.
+    if strip_metadata {
+        if trim != (None, None) || embed_subs || mute {
+            return Err(AppError::BadRequest(
+                "strip_metadata cannot be combined with trimming, embed_subs or mute"
+                    .to_string(),
+            ));
+        }
+        if !service.ffmpeg_available().await {
+            return Err(AppError::BadRequest(
+                "Stripping metadata requires ffmpeg, which is not installed on this server"
+                    .to_string(),
+            ));
+        }
+        let path = service.download_scrubbed_video(url, &selector).await?;
+        if state.config.preserve_timestamps {
+            apply_upload_mtime(&path, info.upload_date.as_deref());
+        }
+        // Open first, then remove the session dir; see the trim path.
+        let file = tokio::fs::File::open(&path).await?;
+        if let Some(session_dir) = path.parent() {
+            let _ = std::fs::remove_dir_all(session_dir);
+        }
+        let filename = format!("{title}_{counter}_clean.mp4");
+        let body = audited_body(
+            state,
+            client_ip,
+            "/api/video/stream",
+            url,
+            &selector,
+            tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+                let _permit = &permit;
+                chunk
+            }),
+        );
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "video/mp4".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    content_disposition_value(disposition, &filename),
+                ),
+            ],
+            body,
+        )
+            .into_response());
+    }
+
+    // A container remux needs the finished file, so it also trades the
+    // piped path for file-then-stream. The streams are copied, not
+    // re-encoded, so this stays much cheaper than trimming or muting.
+    if let Some(container) = container {
+        if trim != (None, None) || embed_subs || mute || strip_metadata {
+            return Err(AppError::BadRequest(
+                "container cannot be combined with trimming, embed_subs, mute or strip_metadata"
+                    .to_string(),
+            ));
+        }
+        if !service.ffmpeg_available().await {
+            return Err(AppError::BadRequest(
+                "Remuxing requires ffmpeg, which is not installed on this server".to_string(),
+            ));
+        }
+        let path = service
+            .download_remuxed_video(url, &selector, container)
+            .await?;
+        if state.config.preserve_timestamps {
+            apply_upload_mtime(&path, info.upload_date.as_deref());
+        }
+        // Open first, then remove the session dir; see the trim path.
+        let file = tokio::fs::File::open(&path).await?;
+        if let Some(session_dir) = path.parent() {
+            let _ = std::fs::remove_dir_all(session_dir);
+        }
+        let filename = format!("{title}_{counter}.{container}");
+        let body = audited_body(
+            state,
+            client_ip,
+            "/api/video/stream",
+            url,
+            &selector,
+            tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+                let _permit = &permit;
+                chunk
+            }),
+        );
+        return Ok((
+            [
+                (
+                    header::CONTENT_TYPE,
+                    video_container_content_type(container).to_string(),
+                ),
+                (
+                    header::CONTENT_DISPOSITION,
+                    content_disposition_value(disposition, &filename),
+                ),
+            ],
+            body,
+        )
+            .into_response());
+    }
+
+    // Trimmed downloads need ffmpeg post-processing and therefore the
+    // file-then-stream path instead of piping yt-dlp's stdout.
+    if trim != (None, None) {
+        let start = trim.0.unwrap_or(0.0);
+        let end = match (trim.1, info.duration) {
+            (Some(end), _) => end,
+            (None, Some(duration)) => duration,
+            (None, None) => {
+                return Err(AppError::BadRequest(
+                    "end_time is required when the video duration is unknown".to_string(),
+                ))
+            }
+        };
+        if start < 0.0 || start >= end {
+            return Err(AppError::BadRequest(
+                "start_time must be non-negative and before end_time".to_string(),
+            ));
+        }
+        if let Some(duration) = info.duration {
+            if end > duration {
+                return Err(AppError::BadRequest(format!(
+                    "end_time exceeds the video duration ({duration:.1}s)"
+                )));
+            }
+        }
+        if !service.ffmpeg_available().await {
+            return Err(AppError::BadRequest(
+                "Trimming requires ffmpeg, which is not installed on this server".to_string(),
+            ));
+        }
+
+        let path = service
+            .download_trimmed_video(url, &selector, start, end)
+            .await?;
+        if state.config.preserve_timestamps {
+            apply_upload_mtime(&path, info.upload_date.as_deref());
+        }
+        // Open first, then remove the session dir: the fd keeps the unlinked
+        // file readable while we stream it, and the shared service's temp dir
+        // never accumulates finished sessions.
+        let file = tokio::fs::File::open(&path).await?;
+        if let Some(session_dir) = path.parent() {
+            let _ = std::fs::remove_dir_all(session_dir);
+        }
+        let filename = format!("{title}_{counter}_clip.mp4");
+        let body = audited_body(
+            state,
+            client_ip,
+            "/api/video/stream",
+            url,
+            &selector,
+            tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+                let _permit = &permit;
+                chunk
+            }),
+        );
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "video/mp4".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    content_disposition_value(disposition, &filename),
+                ),
+            ],
+            body,
+        )
+            .into_response());
+    }
+
+    // Embedding subtitles needs ffmpeg's muxer, so like trimming it takes
+    // the file-then-stream path. Videos without captions fall through to the
+    // plain streaming path with a header explaining why nothing was embedded.
+    let mut subs_note = None;
+    if embed_subs {
+        if !service.ffmpeg_available().await {
+            return Err(AppError::BadRequest(
+                "Embedding subtitles requires ffmpeg, which is not installed on this server"
+                    .to_string(),
+            ));
+        }
+        if info.subtitle_languages.is_empty() {
+            subs_note = Some("video has no captions; none were embedded");
+        } else {
+            let path = service
+                .download_video_with_subs(url, &selector, sub_langs)
+                .await?;
+            if state.config.preserve_timestamps {
+                apply_upload_mtime(&path, info.upload_date.as_deref());
+            }
+            // Open first, then remove the session dir; see the trim path.
+            let file = tokio::fs::File::open(&path).await?;
+            if let Some(session_dir) = path.parent() {
+                let _ = std::fs::remove_dir_all(session_dir);
+            }
+            let filename = format!("{title}_{counter}.mp4");
+            let body = audited_body(
+                state,
+                client_ip,
+                "/api/video/stream",
+                url,
+                &selector,
+                tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+                    let _permit = &permit;
+                    chunk
+                }),
+            );
+            return Ok((
+                [
+                    (header::CONTENT_TYPE, "video/mp4".to_string()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        content_disposition_value(disposition, &filename),
+                    ),
+                ],
+                body,
+            )
+                .into_response());
+        }
+    }
+
+    let filename = format!("{title}_{counter}.{download_ext}");
+
+    let stream = if needs_mux {
+        service
+            .spawn_muxed_stream(
+                url,
+                &service.best_video_only_selector(),
+                mux_track.as_deref(),
+                cookie_file.as_ref(),
+            )
+            .await?
+    } else {
+        service.spawn_video_stream(url, &selector, cookie_file.as_ref())?
+    };
+    // The permit rides along with the body so the slot frees when the
+    // client finishes (or disconnects); the cookie jar comes too so it isn't
+    // deleted while yt-dlp may still read it.
+    let body = audited_body(
+        state,
+        client_ip,
+        "/api/video/stream",
+        url,
+        &selector,
+        stream.map(move |chunk| {
+            let _permit = &permit;
+            let _cookies = &cookie_file;
+            chunk
+        }),
+    );
+
+    let mut response = (
+        [
+            (
+                header::CONTENT_TYPE,
+                video_container_content_type(&download_ext).to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_value(disposition, &filename),
+            ),
+        ],
+        body,
+    )
+        .into_response();
+    if let Some(note) = quality_note {
+        response
+            .headers_mut()
+            .insert("x-quality-note", axum::http::HeaderValue::from_static(note));
+    }
+    if let Some(note) = subs_note {
+        response
+            .headers_mut()
+            .insert("x-subtitle-note", axum::http::HeaderValue::from_static(note));
+    }
+    Ok(response)
+}
+
+pub async fn stream_video_download(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    method: axum::http::Method,
+    Query(query): Query<StreamDownloadQuery>,
+) -> Result<Response, AppError> {
+    let disposition = parse_disposition(query.disposition.as_deref())?;
+    // Download managers probe with HEAD before committing to the GET;
+    // answer from metadata alone instead of spawning a download.
+    if method == axum::http::Method::HEAD {
+        return stream_video_head(&state, client_ip, &query, disposition).await;
+    }
+    stream_video_response(
+        &state,
+        client_ip,
+        &query.url,
+        query.format_id.as_deref(),
+        query.format_selector.as_deref(),
+        query.audio_track.as_deref(),
+        query.best_quality,
+        query.embed_subs,
+        query.mute,
+        query.strip_metadata,
+        query.container.as_deref(),
+        query.target_filesize,
+        query.sub_langs.as_deref(),
+        query.cookies.as_deref(),
+        disposition,
+        (query.start_time, query.end_time),
+        query.recaptcha_token.as_deref(),
+    )
+    .await
+}
+
+/// The HEAD half of /api/video/stream: same Content-Type and
+/// Content-Disposition the GET would send, plus Content-Length when the
+/// chosen format's size is known, with no yt-dlp download spawned.
+async fn stream_video_head(
+    state: &AppState,
+    client_ip: IpAddr,
+    query: &StreamDownloadQuery,
+    disposition: &str,
+) -> Result<Response, AppError> {
+    validate_video_url(&query.url)?;
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let cookie_file = request_cookie_file(&state.config, query.cookies.as_deref())?;
+    let info = state
+        .service
+        .get_video_info_with_cookies(&query.url, false, cookie_file.as_ref())
+        .await?;
+
+    // Only a plainly streamed format has a knowable size; merges, trims,
+    // transcodes and scrubs all change the byte count.
+    let transformed = query.best_quality
+        || query.format_selector.is_some()
+        || query.mute
+        || query.strip_metadata
+        || query.embed_subs
+        || query.start_time.is_some()
+        || query.end_time.is_some();
+    let chosen = if transformed {
+        None
+    } else if let Some(target) = query.target_filesize {
+        select_format_by_size(&info.formats, target)
+    } else if let Some(format_id) = query.format_id.as_deref() {
+        info.formats.iter().find(|f| f.format_id == format_id)
+    } else {
+        default_format(&info.formats)
+    };
+    let content_length = chosen.and_then(|f| f.filesize);
+    // Same container logic as the GET: a plainly streamed format keeps
+    // its real extension, everything transformed comes out as mp4.
+    let ext = chosen.map(|f| f.ext.as_str()).unwrap_or("mp4");
+
+    let counter = peek_download_number();
+    let title = title_or_id(&info.title, &info.id, state.config.filename_policy);
+    let filename = format!("{title}_{counter}.{ext}");
+    Ok(head_response(
+        video_container_content_type(ext),
+        &content_disposition_value(disposition, &filename),
+        content_length,
+    ))
+}
+
+/// Route a response stream through the audit byte counter when
+/// AUDIT_LOG_FILE is configured; a plain body otherwise. The record is
+/// written when the body is dropped, completed or not.
+fn audited_body<S>(
+    state: &AppState,
+    client_ip: IpAddr,
+    endpoint: &str,
+    url: &str,
+    format: &str,
+    stream: S,
+) -> Body
+where
+    S: futures::Stream<Item = Result<bytes::Bytes, std::io::Error>> + Unpin + Send + 'static,
+{
+    match &state.config.audit_log_file {
+        Some(log_file) => Body::from_stream(AuditedStream::new(
+            stream,
+            AuditContext {
+                log_file: log_file.clone(),
+                max_bytes: state.config.audit_log_max_bytes,
+                client_ip: client_ip.to_string(),
+                endpoint: endpoint.to_string(),
+                url: url.to_string(),
+                format: format.to_string(),
+            },
+        )),
+        None => Body::from_stream(stream),
+    }
+}
+
+/// 200 with headers and an empty body, for HEAD probes.
+fn head_response(content_type: &str, disposition_value: &str, content_length: Option<u64>) -> Response {
+    let mut response = ([
+        (header::CONTENT_TYPE, content_type.to_string()),
+        (header::CONTENT_DISPOSITION, disposition_value.to_string()),
+    ])
+    .into_response();
+    if let Some(length) = content_length {
+        if let Ok(value) = length.to_string().parse() {
+            response.headers_mut().insert(header::CONTENT_LENGTH, value);
+        }
+    }
+    response
+}
+
+/// Deprecated: POST body variant kept for old clients; prefer
+/// GET /api/video/stream.
+pub async fn download_video(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Json(request): Json<VideoDownloadRequest>,
+) -> Result<Response, AppError> {
+    if !state.config.legacy_download_enabled {
+        return Err(AppError::Gone(
+            "This endpoint has been retired; use GET /api/video/stream instead".to_string(),
+        ));
+    }
+    tracing::warn!("deprecated /api/video/download called; use /api/video/stream");
+    stream_video_response(
+        &state,
+        client_ip,
+        &request.url,
+        Some(&request.format_id),
+        None,
+        None,
+        false,
+        false,
+        false,
+        false,
+        None,
+        None,
+        None,
+        None,
+        "attachment",
+        (None, None),
+        request.recaptcha_token.as_deref(),
+    )
+    .await
+}
+
+const SUPPORTED_AUDIO_FORMATS: &[&str] = &["mp3", "m4a", "aac", "wav", "opus"];
+
+/// Enforce the operator's ALLOWED_AUDIO_FORMATS policy on a format that
+/// already passed the supported check. An empty list means no policy.
+fn ensure_audio_format_allowed(allowed: &[String], format: &str) -> Result<(), AppError> {
+    if allowed.is_empty() || allowed.iter().any(|a| a == format) {
+        return Ok(());
+    }
+    Err(AppError::BadRequest(format!(
+        "Audio format '{format}' is not enabled on this server; allowed: {}",
+        allowed.join(", ")
+    )))
+}
+
+/// Parse one requested bundle rendition: "video:best", "video:<height>"
+/// or "audio:<format>".
+fn parse_bundle_output(raw: &str) -> Result<BundleOutput, AppError> {
+    match raw.split_once(':') {
+        Some(("video", "best")) => Ok(BundleOutput::Video(None)),
+        Some(("video", height)) => height.parse().map(|h| BundleOutput::Video(Some(h))).map_err(|_| {
+            AppError::BadRequest(format!(
+                "Invalid bundle output '{raw}'; use video:best, video:<height> or audio:<format>"
+            ))
+        }),
+        Some(("audio", format)) if SUPPORTED_AUDIO_FORMATS.contains(&format) => {
+            Ok(BundleOutput::Audio(format.to_string()))
+        }
+        Some(("audio", _)) => Err(AppError::BadRequest(format!(
+            "Unsupported audio format in '{raw}'; supported: {}",
+            SUPPORTED_AUDIO_FORMATS.join(", ")
+        ))),
+        _ => Err(AppError::BadRequest(format!(
+            "Invalid bundle output '{raw}'; use video:best, video:<height> or audio:<format>"
+        ))),
+    }
+}
+
+/// Several renditions of one video in a single ZIP — typically the MP4
+/// plus the MP3 — so clients don't need a round trip per format.
+pub async fn video_bundle(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Json(request): Json<BundleRequest>,
+) -> Result<Response, AppError> {
+    validate_video_url(&request.url)?;
+    if request.outputs.is_empty() || request.outputs.len() > 4 {
+        return Err(AppError::BadRequest(
+            "Request between 1 and 4 outputs".to_string(),
+        ));
+    }
+    let outputs = request
+        .outputs
+        .iter()
+        .map(|raw| parse_bundle_output(raw))
+        .collect::<Result<Vec<_>, _>>()?;
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+
+    let permit = acquire_download_permit(&state).await?;
+
+    let service = &state.service;
+    let counter = next_download_number(&state.config);
+    let zip_path = service.download_bundle(&request.url, &outputs).await?;
+    // Open first, then remove the session dir; see the trim path in
+    // stream_video_response.
+    let file = tokio::fs::File::open(&zip_path).await?;
+    if let Some(session_dir) = zip_path.parent() {
+        let _ = std::fs::remove_dir_all(session_dir);
+    }
+
+    let filename = format!("tiktok_bundle_{counter}.zip");
+    let body = audited_body(
+        &state,
+        client_ip,
+        "/api/video/bundle",
+        &request.url,
+        "bundle",
+        tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+            let _permit = &permit;
+            chunk
+        }),
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_value("attachment", &filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// The containers --remux-video is allowed to target here.
+const SUPPORTED_CONTAINERS: &[&str] = &["mp4", "mkv", "webm"];
+
+fn validated_container(value: &str) -> Result<&str, AppError> {
+    if SUPPORTED_CONTAINERS.contains(&value) {
+        Ok(value)
+    } else {
+        Err(AppError::BadRequest(format!(
+            "Unsupported container '{value}'; use one of: {}",
+            SUPPORTED_CONTAINERS.join(", ")
+        )))
+    }
+}
+
+fn video_container_content_type(container: &str) -> &'static str {
+    match container {
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        _ => "video/mp4",
+    }
+}
+
+fn audio_content_type(format: &str) -> &'static str {
+    match format {
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "aac" => "audio/aac",
+        "wav" => "audio/wav",
+        "opus" => "audio/opus",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Stream a server-side mux of separate bestvideo+bestaudio tracks as a
+/// fragmented MP4, for posts where no combined format exists. See
+/// `spawn_muxed_stream` for the pipeline; this handler only does the
+/// usual gatekeeping and naming.
+pub async fn stream_muxed_video(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    method: axum::http::Method,
+    Query(query): Query<MuxedStreamQuery>,
+) -> Result<Response, AppError> {
+    validate_video_url(&query.url)?;
+    let disposition = parse_disposition(query.disposition.as_deref())?;
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let service = &state.service;
+    if !service.ffmpeg_available().await {
+        return Err(AppError::BadRequest(
+            "Muxed streaming requires ffmpeg, which is not installed on this server".to_string(),
+        ));
+    }
+
+    // HEAD probe: the muxed size is unknowable, but type and filename are
+    // worth answering without spawning the pipeline.
+    if method == axum::http::Method::HEAD {
+        let info = service.get_video_info(&query.url).await?;
+        let title = title_or_id(&info.title, &info.id, state.config.filename_policy);
+        let filename = format!("{title}_{}.mp4", peek_download_number());
+        return Ok(head_response(
+            "video/mp4",
+            &content_disposition_value(disposition, &filename),
+            None,
+        ));
+    }
+
+    let permit = acquire_download_permit(&state).await?;
+    let info = service.get_video_info(&query.url).await?;
+
+    let counter = next_download_number(&state.config);
+    let title = title_or_id(&info.title, &info.id, state.config.filename_policy);
+    let filename = format!("{title}_{counter}.mp4");
+
+    let stream = service
+        .spawn_muxed_stream(&query.url, &service.best_video_only_selector(), None, None)
+        .await?;
+    let body = audited_body(
+        &state,
+        client_ip,
+        "/api/video/stream-muxed",
+        &query.url,
+        "muxed",
+        stream.map(move |chunk| {
+            let _permit = &permit;
+            chunk
+        }),
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "video/mp4".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_value(disposition, &filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+pub async fn stream_audio_download(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    method: axum::http::Method,
+    Query(query): Query<AudioStreamQuery>,
+) -> Result<Response, AppError> {
+    validate_video_url(&query.url)?;
+    let disposition = parse_disposition(query.disposition.as_deref())?;
+    let audio_format = query.format.as_deref().unwrap_or("mp3");
+    if !SUPPORTED_AUDIO_FORMATS.contains(&audio_format) {
+        return Err(AppError::BadRequest(format!(
+            "Unsupported audio format '{audio_format}'; supported: {}",
+            SUPPORTED_AUDIO_FORMATS.join(", ")
+        )));
+    }
+    ensure_audio_format_allowed(&state.config.allowed_audio_formats, audio_format)?;
+    // yt-dlp shells out to ffmpeg for the extraction; without this check a
+    // missing ffmpeg surfaces as a stream that dies partway through.
+    if !state.service.ffmpeg_available().await {
+        return Err(AppError::BadRequest(
+            "Audio extraction requires ffmpeg, which is not installed on this server".to_string(),
+        ));
+    }
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+
+    // HEAD probe: the transcoded size is unknowable, but type and filename
+    // are worth answering without spawning yt-dlp.
+    if method == axum::http::Method::HEAD {
+        let info = state.service.get_video_info(&query.url).await?;
+        let title = title_or_id(&info.title, &info.id, state.config.filename_policy);
+        let filename = format!("{title}_{}.{audio_format}", peek_download_number());
+        return Ok(head_response(
+            audio_content_type(audio_format),
+            &content_disposition_value(disposition, &filename),
+            None,
+        ));
+    }
+
+    let permit = acquire_download_permit(&state).await?;
+
+    let service = &state.service;
+    let info = service.get_video_info(&query.url).await?;
+
+    let counter = next_download_number(&state.config);
+    let title = title_or_id(&info.title, &info.id, state.config.filename_policy);
+    let filename = format!("{title}_{counter}.{audio_format}");
+
+    let stream = service.spawn_audio_stream(&query.url, audio_format)?;
+    let body = audited_body(
+        &state,
+        client_ip,
+        "/api/audio/stream",
+        &query.url,
+        audio_format,
+        stream.map(move |chunk| {
+            let _permit = &permit;
+            chunk
+        }),
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, audio_content_type(audio_format).to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_value(disposition, &filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Quote a CSV field per RFC 4180 when it contains a separator, quote or
+/// line break.
+fn csv_escape(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') || field.contains('\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Export a profile's video list as a downloadable JSON or CSV file for
+/// spreadsheet / analytics use.
+/// 403 for every profile-level endpoint when the operator has turned the
+/// surface off; single-video endpoints are unaffected.
+fn ensure_profile_downloads_enabled(config: &crate::config::AppConfig) -> Result<(), AppError> {
+    if config.profile_downloads_enabled {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(
+            "Profile downloads are disabled on this server; single-video endpoints remain available"
+                .to_string(),
+        ))
+    }
+}
+
+pub async fn profile_export(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Json(request): Json<ProfileExportRequest>,
+) -> Result<Response, AppError> {
+    ensure_profile_downloads_enabled(&state.config)?;
+    validate_profile_url(&request.profile_url)?;
+    if request.format != "json" && request.format != "csv" {
+        return Err(AppError::BadRequest(
+            "Invalid export format; use \"json\" or \"csv\"".to_string(),
+        ));
+    }
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+
+    let service = &state.service;
+    let videos = service
+        .get_profile_video_list(&request.profile_url)
+        .await?
+        .videos;
+    let username = crate::url_validator::extract_username(&request.profile_url)
+        .unwrap_or_else(|| "profile".to_string());
+
+    let (content_type, body) = if request.format == "json" {
+        let body = serde_json::to_string_pretty(&videos)
+            .map_err(|e| AppError::internal(format!("export serialization failed: {e}")))?;
+        ("application/json", body)
+    } else {
+        let mut csv = String::from("id,url,title,duration,view_count,upload_date\n");
+        for video in &videos {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                csv_escape(&video.id),
+                csv_escape(&video.url),
+                csv_escape(&video.title),
+                video
+                    .duration
+                    .map(|d| d.to_string())
+                    .unwrap_or_default(),
+                video
+                    .view_count
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+                video.upload_date.clone().unwrap_or_default(),
+            ));
+        }
+        ("text/csv", csv)
+    };
+
+    let filename = format!("tiktok_{}_videos.{}", username, request.format);
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_value("attachment", &filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+pub async fn profile_info(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Json(request): Json<ProfileInfoRequest>,
+) -> Result<Json<ProfileInfo>, AppError> {
+    ensure_profile_downloads_enabled(&state.config)?;
+    validate_profile_url(&request.profile_url)?;
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let service = &state.service;
+    let mut info = service.get_profile_info(&request.profile_url).await?;
+    // Optional pinned/regular filter; videos the extractor doesn't label are
+    // treated as regular so the filter stays useful on older yt-dlp output.
+    if let Some(want_pinned) = request.pinned {
+        info.videos
+            .retain(|v| v.pinned.unwrap_or(false) == want_pinned);
+        info.video_count = info.videos.len();
+    }
+    if request.inline_thumbnails {
+        inline_listing_thumbnails(&mut info.videos).await;
+    }
+    Ok(Json(info))
+}
+
+/// Fold a video listing into the aggregate numbers analysts ask for.
+/// Videos missing a field are excluded from that metric and reflected in
+/// the corresponding `videos_with_*` count instead of skewing the totals.
+fn compute_profile_stats(username: String, videos: &[crate::models::ProfileVideoInfo]) -> ProfileStats {
+    let views: Vec<u64> = videos.iter().filter_map(|v| v.view_count).collect();
+    let likes: Vec<u64> = videos.iter().filter_map(|v| v.like_count).collect();
+    let durations: Vec<f64> = videos.iter().filter_map(|v| v.duration).collect();
+    ProfileStats {
+        username,
+        video_count: videos.len(),
+        total_views: views.iter().sum(),
+        videos_with_views: views.len(),
+        total_likes: likes.iter().sum(),
+        videos_with_likes: likes.len(),
+        average_duration: (!durations.is_empty())
+            .then(|| durations.iter().sum::<f64>() / durations.len() as f64),
+        videos_with_duration: durations.len(),
+    }
+}
+
+/// Aggregate statistics across a profile's enumerated videos. Bounded by
+/// the same MAX_PROFILE_VIDEOS cap as every other profile listing, so a
+/// 10k-video account yields stats over its most recent slice, not an
+/// unbounded crawl.
+pub async fn profile_stats(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Json(request): Json<ProfileStatsRequest>,
+) -> Result<Json<ProfileStats>, AppError> {
+    ensure_profile_downloads_enabled(&state.config)?;
+    validate_profile_url(&request.profile_url)?;
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let username = extract_username(&request.profile_url)
+        .ok_or_else(|| AppError::BadRequest("Invalid TikTok profile URL".to_string()))?;
+    let videos = state
+        .service
+        .get_profile_video_list(&request.profile_url)
+        .await?
+        .videos;
+    Ok(Json(compute_profile_stats(username, &videos)))
+}
+
+/// Kick off a full-profile download as a background job and return its id
+/// immediately; clients poll /api/profile/status/{id}.
+pub async fn profile_download(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    headers: HeaderMap,
+    Json(request): Json<ProfileDownloadRequest>,
+) -> Result<Json<ProfileDownloadResponse>, AppError> {
+    ensure_profile_downloads_enabled(&state.config)?;
+    validate_profile_url(&request.profile_url)?;
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty());
+    if request.split_size_bytes == Some(0) {
+        return Err(AppError::BadRequest(
+            "split_size_bytes must be greater than zero".to_string(),
+        ));
+    }
+    if let Some(date) = request.since_date.as_deref() {
+        validate_since_date(date)?;
+    }
+    // Translated here only to reject bad templates with a 400; a broken
+    // template inside the spawned job would surface as a failed poll.
+    if let Some(template) = request.filename_template.as_deref() {
+        ytdlp_output_template(template)?;
+    }
+    let fingerprint = format!(
+        "{}|{}|{}|{:?}|{:?}|{:?}|{:?}|{:?}",
+        request.profile_url,
+        request.include_metadata,
+        request.include_manifest,
+        request.naming,
+        request.order,
+        request.split_size_bytes,
+        request.since_date,
+        request.filename_template
+    );
+    if let Some(key) = idempotency_key {
+        if let Some(existing_id) = idempotent_job_id(key, &fingerprint)? {
+            let status = JOB_REGISTRY
+                .lock()
+                .unwrap()
+                .get(&existing_id)
+                .map(|job| job.status.clone());
+            if let Some(status) = status {
+                return Ok(Json(ProfileDownloadResponse {
+                    download_id: existing_id,
+                    status,
+                }));
+            }
+        }
+    }
+
+    let download_id = uuid::Uuid::new_v4().to_string();
+    let job = ProfileJob {
+        download_id: download_id.clone(),
+        profile_url: request.profile_url.clone(),
+        status: JobStatus::Pending,
+    };
+    JOB_REGISTRY
+        .lock()
+        .unwrap()
+        .insert(download_id.clone(), job);
+    persist_job_registry(&state.config);
+    if let Some(key) = idempotency_key {
+        remember_idempotency_key(key, &fingerprint, &download_id);
+    }
+
+    let config = state.config.clone();
+    let service = state.service.clone();
+    let task_id = download_id.clone();
+    tokio::spawn(async move {
+        set_job_status(&config, &task_id, JobStatus::InProgress);
+        let result = service
+            .download_all_profile_videos(
+                &request.profile_url,
+                request.include_metadata,
+                request.include_manifest,
+                request.naming,
+                request.order,
+                request.split_size_bytes,
+                request.since_date.as_deref(),
+                request.filename_template.as_deref(),
+            )
+            .await;
+        match result {
+            Ok(parts) => set_job_status(&config, &task_id, completed_status(parts)),
+            Err(e) => {
+                tracing::error!(error = %e, "profile download failed");
+                set_job_status(
+                    &config,
+                    &task_id,
+                    JobStatus::Failed {
+                        reason: e.to_string(),
+                    },
+                );
+            }
+        }
+    });
+
+    Ok(Json(ProfileDownloadResponse {
+        download_id,
+        status: JobStatus::Pending,
+    }))
+}
+
+/// Check a `since_date` value is a plausible YYYYMMDD day. yt-dlp would
+/// reject a malformed date too, but only after the job is already running
+/// in the background, where the error is much harder to surface.
+fn validate_since_date(date: &str) -> Result<(), AppError> {
+    let digits = date.len() == 8 && date.bytes().all(|b| b.is_ascii_digit());
+    let plausible = digits && {
+        let month: u32 = date[4..6].parse().unwrap_or(0);
+        let day: u32 = date[6..8].parse().unwrap_or(0);
+        (1..=12).contains(&month) && (1..=31).contains(&day)
+    };
+    if plausible {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(format!(
+            "Invalid since_date '{date}'; expected YYYYMMDD"
+        )))
+    }
+}
+
+/// A lone archive keeps the original `completed` shape so existing
+/// pollers are unaffected; only genuinely split downloads report parts.
+fn completed_status(parts: Vec<(std::path::PathBuf, u64)>) -> JobStatus {
+    if parts.len() == 1 {
+        let (zip_path, size) = parts.into_iter().next().unwrap();
+        JobStatus::Completed {
+            zip_path: zip_path.to_string_lossy().into_owned(),
+            size,
+        }
+    } else {
+        JobStatus::CompletedParts {
+            parts: parts
+                .into_iter()
+                .map(|(zip_path, size)| ZipPart {
+                    zip_path: zip_path.to_string_lossy().into_owned(),
+                    size,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn set_job_status(config: &crate::config::AppConfig, download_id: &str, status: JobStatus) {
+    update_job_status(download_id, status);
+    persist_job_registry(config);
+}
+
+fn update_job_status(download_id: &str, status: JobStatus) {
+    if let Some(job) = JOB_REGISTRY.lock().unwrap().get_mut(download_id) {
+        job.status = status;
+    }
+}
+
+/// Snapshot the job registry to the configured state file so jobs survive
+/// a restart. Best-effort: a write failure is logged, not surfaced.
+fn persist_job_registry(config: &crate::config::AppConfig) {
+    let Some(path) = &config.job_state_file else {
+        return;
+    };
+    let jobs: Vec<ProfileJob> = JOB_REGISTRY.lock().unwrap().values().cloned().collect();
+    match serde_json::to_string(&jobs) {
+        Ok(body) => {
+            if let Err(e) = std::fs::write(path, body) {
+                tracing::warn!(error = %e, path, "failed to persist job registry");
+            }
+        }
+        Err(e) => tracing::warn!(error = %e, "failed to serialize job registry"),
+    }
+}
+
+/// Reload persisted jobs at startup. A job that was still pending or running
+/// when the server died cannot be resumed — its scratch space is gone — so it
+/// is marked Failed; clients polling its id learn the job died instead of
+/// waiting on a vanished registry entry forever.
+pub fn restore_job_registry(config: &crate::config::AppConfig) {
+    let Some(path) = &config.job_state_file else {
+        return;
+    };
+    let body = match std::fs::read_to_string(path) {
+        Ok(body) => body,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            tracing::warn!(error = %e, path, "failed to read persisted job registry");
+            return;
+        }
+    };
+    let jobs: Vec<ProfileJob> = match serde_json::from_str(&body) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            tracing::warn!(error = %e, path, "ignoring corrupt job registry file");
+            return;
+        }
+    };
+
+    let mut registry = JOB_REGISTRY.lock().unwrap();
+    for mut job in jobs {
+        if matches!(job.status, JobStatus::Pending | JobStatus::InProgress) {
+            job.status = JobStatus::Failed {
+                reason: "The server restarted before this download finished".to_string(),
+            };
+        }
+        registry.insert(job.download_id.clone(), job);
+    }
+    tracing::info!(count = registry.len(), "restored persisted profile jobs");
+}
+
+pub async fn profile_download_status(
+    Path(download_id): Path<String>,
+) -> Result<Json<ProfileJob>, AppError> {
+    JOB_REGISTRY
+        .lock()
+        .unwrap()
+        .get(&download_id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound("Unknown download id".to_string()))
+}
+
+/// Download only the videos the user ticked and stream them back as a ZIP
+/// built on the fly: each video is appended to the archive as its download
+/// finishes, so the first bytes arrive long before the last video does.
+pub async fn profile_download_selected(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Json(request): Json<SelectedDownloadRequest>,
+) -> Result<Response, AppError> {
+    ensure_profile_downloads_enabled(&state.config)?;
+    validate_profile_url(&request.profile_url)?;
+    if request.urls.is_empty() || request.urls.len() > state.config.max_profile_videos {
+        return Err(AppError::BadRequest(format!(
+            "Select between 1 and {} videos",
+            state.config.max_profile_videos
+        )));
+    }
+    for url in &request.urls {
+        validate_video_url(url)?;
+    }
+    state
+        .recaptcha
+        .verify_token(request.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+
+    let username =
+        extract_username(&request.profile_url).unwrap_or_else(|| "profile".to_string());
+    let filename = format!("tiktok_{username}_selected.zip");
+
+    // An async pipe between the download/zip task and the response body;
+    // backpressure from a slow client propagates through the duplex buffer
+    // into the ZIP writer.
+    let (writer, reader) = tokio::io::duplex(state.config.stream_buffer_size.max(1024));
+    let service = state.service.clone();
+    tokio::spawn(async move {
+        if let Err(e) = service
+            .stream_selected_videos(
+                &request.profile_url,
+                &request.urls,
+                request.include_metadata,
+                request.naming,
+                writer,
+            )
+            .await
+        {
+            // Too late for an HTTP error; the dropped writer truncates the
+            // stream so the client at least sees a broken archive.
+            tracing::error!(error = %e, "streamed selected download failed");
+        }
+    });
+
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(reader));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_value("attachment", &filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Stream a previously built profile ZIP back to the client.
+/// Reject zip_path values that point outside the downloads directory; this
+/// endpoint is public, so the parameter must never be able to name an
+/// arbitrary file.
+///
+/// Two layers: a lexical screen catches traversal components and foreign
+/// roots up front, then canonicalization catches anything the lexical check
+/// can't see, such as a symlink inside the directory pointing elsewhere.
+fn zip_path_in_downloads_dir(
+    config: &crate::config::AppConfig,
+    raw: &str,
+) -> Result<PathBuf, AppError> {
+    let path = PathBuf::from(raw);
+    let inside = path.starts_with(&config.downloads_dir)
+        && path
+            .components()
+            .all(|c| !matches!(c, std::path::Component::ParentDir));
+    if !inside {
+        return Err(AppError::BadRequest(
+            "zip_path must point inside the downloads directory".to_string(),
+        ));
+    }
+
+    // If the directory itself is missing nothing under it can exist either;
+    // let the open() report that.
+    let Ok(real_dir) = std::fs::canonicalize(&config.downloads_dir) else {
+        return Ok(path);
+    };
+    match std::fs::canonicalize(&path) {
+        Ok(real) if real.starts_with(&real_dir) => Ok(path),
+        Ok(_) => Err(AppError::Unauthorized(
+            "zip_path resolves outside the downloads directory".to_string(),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(path),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a completed job ever produced this archive; distinguishes a ZIP
+/// that expired from a path that never existed.
+fn job_produced_zip(zip_path: &str) -> bool {
+    JOB_REGISTRY.lock().unwrap().values().any(|job| match &job.status {
+        JobStatus::Completed { zip_path: p, .. } => p == zip_path,
+        JobStatus::CompletedParts { parts } => parts.iter().any(|part| part.zip_path == zip_path),
+        _ => false,
+    })
+}
+
+pub async fn stream_profile_zip(
+    State(state): State<AppState>,
+    Query(query): Query<ProfileStreamQuery>,
+) -> Result<Response, AppError> {
+    ensure_profile_downloads_enabled(&state.config)?;
+    let path = zip_path_in_downloads_dir(&state.config, &query.zip_path)?;
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(if job_produced_zip(&query.zip_path) {
+                AppError::Gone(
+                    "This archive expired and was cleaned up; start a new download".to_string(),
+                )
+            } else {
+                AppError::NotFound("No such archive".to_string())
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let filename = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("tiktok_videos.zip")
+        .to_string();
+
+    // Offloaded serving: hand the front proxy the file reference and an
+    // empty body; it reads the bytes off disk itself. The open() above
+    // still did the existence check and 404/410 split.
+    if let Some(header_name) = &state.config.sendfile_header {
+        drop(file);
+        return sendfile_response(&state.config, header_name, &path, &filename);
+    }
+
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(file));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_value("attachment", &filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// An empty-bodied 200 carrying the configured sendfile header, so nginx
+/// (X-Accel-Redirect, pointing into an internal location) or Apache
+/// (X-Sendfile, taking the filesystem path) serves the archive bytes.
+fn sendfile_response(
+    config: &crate::config::AppConfig,
+    header_name: &str,
+    path: &std::path::Path,
+    filename: &str,
+) -> Result<Response, AppError> {
+    let value = if header_name.eq_ignore_ascii_case("x-accel-redirect") {
+        format!("{}/{}", config.sendfile_location.trim_end_matches('/'), filename)
+    } else {
+        path.to_string_lossy().into_owned()
+    };
+    let mut response = ([
+        (header::CONTENT_TYPE, "application/zip".to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            content_disposition_value("attachment", filename),
+        ),
+    ])
+    .into_response();
+    let name = axum::http::HeaderName::from_bytes(header_name.as_bytes())
+        .map_err(|_| AppError::internal("SENDFILE_HEADER is not a valid header name".to_string()))?;
+    response.headers_mut().insert(
+        name,
+        value
+            .parse()
+            .map_err(|_| AppError::internal("sendfile path is not a valid header value".to_string()))?,
+    );
+    Ok(response)
+}
+
+/// At most this many thumbnails are inlined per response; anything past
+/// the cap keeps its remote URL. Together with the per-fetch timeout this
+/// bounds how much extra work `inline_thumbnails` can pull into one
+/// request.
+const MAX_INLINE_THUMBNAILS: usize = 10;
+
+const INLINE_THUMBNAIL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `data:` URI form of an already-fetched image.
+fn thumbnail_data_uri(content_type: &str, bytes: &[u8]) -> String {
+    use base64::Engine;
+    format!(
+        "data:{content_type};base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    )
+}
+
+/// Fetch one thumbnail and encode it as a data URI. The URL comes from
+/// yt-dlp's metadata, not the client, so no host allowlist applies here.
+/// Failures leave the remote URL in place rather than failing the info
+/// request.
+async fn fetch_thumbnail_data_uri(url: &str) -> Option<String> {
+    let response = crate::service::shared_http_client()
+        .get(url)
+        .timeout(INLINE_THUMBNAIL_TIMEOUT)
+        .send()
+        .await
+        .ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("image/jpeg")
+        .to_string();
+    let bytes = response.bytes().await.ok()?;
+    Some(thumbnail_data_uri(&content_type, &bytes))
+}
+
+/// Inline a single video's thumbnails, best-first, within the global cap.
+async fn inline_video_thumbnails(info: &mut VideoInfo) {
+    let mut budget = MAX_INLINE_THUMBNAILS;
+    if let Some(url) = info.thumbnail_url.clone() {
+        if let Some(uri) = fetch_thumbnail_data_uri(&url).await {
+            info.thumbnail_url = Some(uri);
+        }
+        budget -= 1;
+    }
+    for thumbnail in info.thumbnails.iter_mut().take(budget) {
+        if let Some(uri) = fetch_thumbnail_data_uri(&thumbnail.url).await {
+            thumbnail.url = uri;
+        }
+    }
+}
+
+/// Inline the primary thumbnail of the first few listing entries.
+async fn inline_listing_thumbnails(videos: &mut [crate::models::ProfileVideoInfo]) {
+    for video in videos.iter_mut().take(MAX_INLINE_THUMBNAILS) {
+        if let Some(url) = video.thumbnail_url.clone() {
+            if let Some(uri) = fetch_thumbnail_data_uri(&url).await {
+                video.thumbnail_url = Some(uri);
+            }
+        }
+    }
+}
+
+fn is_allowed_thumbnail_host(host: &str) -> bool {
+    ALLOWED_THUMBNAIL_HOSTS
+        .iter()
+        .any(|allowed| host == *allowed || host.ends_with(&format!(".{allowed}")))
+}
+
+/// Fetch an image from a TikTok CDN host and relay it with the upstream
+/// Content-Type. The host allowlist keeps this from becoming an open proxy.
+async fn proxy_allowed_image(raw_url: &str) -> Result<Response, AppError> {
+    let url = reqwest::Url::parse(raw_url)
+        .map_err(|_| AppError::BadRequest("Invalid image URL".to_string()))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| AppError::BadRequest("Invalid image URL".to_string()))?;
+    if url.scheme() != "https" || !is_allowed_thumbnail_host(host) {
+        return Err(AppError::BadRequest(
+            "Image host is not allowed".to_string(),
+        ));
+    }
+
+    let response = crate::service::shared_http_client()
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| AppError::internal(format!("image fetch failed: {e}")))?;
+    if !response.status().is_success() {
+        return Err(AppError::BadRequest(
+            "Image could not be fetched".to_string(),
+        ));
+    }
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("image/jpeg")
+        .to_string();
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| AppError::internal(format!("image fetch failed: {e}")))?;
+
+    Ok(([(header::CONTENT_TYPE, content_type)], bytes).into_response())
+}
+
+/// Fetch a TikTok CDN thumbnail server-side so the browser doesn't need
+/// cross-origin access to the CDN. Restricted to known TikTok hosts.
+pub async fn thumbnail_proxy(
+    Query(query): Query<ThumbnailProxyQuery>,
+) -> Result<Response, AppError> {
+    proxy_allowed_image(&query.url).await
+}
+
+/// Serve one slide of a photo carousel by index, relayed from TikTok's
+/// CDN with the upstream Content-Type — for previews and users who want a
+/// single image without the whole ZIP.
+pub async fn photo_image(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Query(query): Query<PhotoImageQuery>,
+) -> Result<Response, AppError> {
+    if !matches!(classify_url(&query.url), UrlKind::Photo) {
+        return Err(AppError::BadRequest(
+            "Not a TikTok photo post URL".to_string(),
+        ));
+    }
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let images = state.service.get_photo_images(&query.url).await?;
+    let Some(image_url) = images.get(query.index) else {
+        return Err(AppError::NotFound(format!(
+            "No image at index {}; this post has {} image(s)",
+            query.index,
+            images.len()
+        )));
+    };
+    proxy_allowed_image(image_url).await
+}
+
+/// Resolve the direct CDN URL for a format so bandwidth-sensitive clients
+/// can fetch from TikTok's CDN instead of proxying bytes through us.
+pub async fn direct_url(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Query(query): Query<DirectUrlQuery>,
+) -> Result<Json<DirectUrlResponse>, AppError> {
+    validate_video_url(&query.url)?;
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let service = &state.service;
+    let response = service.get_direct_url(&query.url, &query.format_id).await?;
+    Ok(Json(response))
+}
+
+/// JSON 404 for misses under the static /api/downloads mount, keeping its
+/// errors consistent with the rest of the API.
+pub async fn download_not_found() -> AppError {
+    AppError::NotFound(
+        "No archive by that name exists; it may have been cleaned up".to_string(),
+    )
+}
+
+/// Classify pasted links offline — video, profile, playlist, photo, live
+/// or invalid — so the UI can sort a pasted list without one yt-dlp round
+/// trip per URL.
+pub async fn validate_urls(
+    Json(request): Json<ValidateRequest>,
+) -> Result<Json<Vec<ValidateResult>>, AppError> {
+    if request.urls.is_empty() || request.urls.len() > MAX_VALIDATE_URLS {
+        return Err(AppError::BadRequest(format!(
+            "Provide between 1 and {MAX_VALIDATE_URLS} URLs"
+        )));
+    }
+    let results = request
+        .urls
+        .into_iter()
+        .map(|url| ValidateResult {
+            kind: classify_url(&url),
+            url,
+        })
+        .collect();
+    Ok(Json(results))
+}
+
+/// Download a video with the operator's watermark image composited at a
+/// corner, then stream the result. ffmpeg-dependent and re-encoding, so
+/// strictly file-then-stream.
+pub async fn watermark_video(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Query(query): Query<WatermarkQuery>,
+) -> Result<Response, AppError> {
+    validate_video_url(&query.url)?;
+    let disposition = parse_disposition(query.disposition.as_deref())?;
+    let position = query
+        .position
+        .as_deref()
+        .unwrap_or("bottom-right")
+        .parse::<WatermarkPosition>()
+        .map_err(|_| {
+            AppError::BadRequest(
+                "Invalid position; use top-left, top-right, bottom-left or bottom-right"
+                    .to_string(),
+            )
+        })?;
+    let opacity = query.opacity.unwrap_or(1.0);
+    if !(0.0..=1.0).contains(&opacity) {
+        return Err(AppError::BadRequest(
+            "opacity must be between 0.0 and 1.0".to_string(),
+        ));
+    }
+    let Some(image) = state.config.watermark_image.clone() else {
+        return Err(AppError::BadRequest(
+            "No watermark image is configured on this server".to_string(),
+        ));
+    };
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+
+    let permit = acquire_download_permit(&state).await?;
+    let service = &state.service;
+    if !service.ffmpeg_available().await {
+        return Err(AppError::BadRequest(
+            "Watermarking requires ffmpeg, which is not installed on this server".to_string(),
+        ));
+    }
+    let info = service.get_video_info(&query.url).await?;
+
+    let path = service
+        .download_watermarked_video(
+            &query.url,
+            BEST_SINGLE_SELECTOR,
+            std::path::Path::new(&image),
+            position,
+            opacity,
+        )
+        .await?;
+    if state.config.preserve_timestamps {
+        apply_upload_mtime(&path, info.upload_date.as_deref());
+    }
+    // Open first, then remove the session dir; see the trim path.
+    let file = tokio::fs::File::open(&path).await?;
+    if let Some(session_dir) = path.parent() {
+        let _ = std::fs::remove_dir_all(session_dir);
+    }
+    let counter = next_download_number(&state.config);
+    let title = title_or_id(&info.title, &info.id, state.config.filename_policy);
+    let filename = format!("{title}_{counter}_branded.mp4");
+    let body = audited_body(
+        &state,
+        client_ip,
+        "/api/video/watermark",
+        &query.url,
+        BEST_SINGLE_SELECTOR,
+        tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+            let _permit = &permit;
+            chunk
+        }),
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "video/mp4".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_value(disposition, &filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Hand back the best HLS/DASH manifest URL (plus required headers) so a
+/// browser player can do adaptive playback without downloading the file.
+pub async fn video_manifest(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Query(query): Query<ManifestQuery>,
+) -> Result<Json<ManifestResponse>, AppError> {
+    validate_video_url(&query.url)?;
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let response = state.service.get_playback_manifest(&query.url).await?;
+    Ok(Json(response))
+}
+
+/// Return a video's captions as clean plain text: cue timing, numbering
+/// and rolling-caption repeats are stripped, leaving readable prose.
+pub async fn video_transcript(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Query(query): Query<TranscriptQuery>,
+) -> Result<Response, AppError> {
+    validate_video_url(&query.url)?;
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+    let service = &state.service;
+    let text = service
+        .fetch_transcript(&query.url, query.lang.as_deref())
+        .await?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        text,
+    )
+        .into_response())
+}
+
+/// Resolve a video's best cover image and stream the bytes back so users
+/// can save the original-resolution cover, not just view it.
+pub async fn video_cover(
+    State(state): State<AppState>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    Query(query): Query<CoverQuery>,
+) -> Result<Response, AppError> {
+    validate_video_url(&query.url)?;
+    state
+        .recaptcha
+        .verify_token(query.recaptcha_token.as_deref(), Some(&client_ip.to_string()))
+        .await?;
+
+    let service = &state.service;
+    let info = service.get_video_info(&query.url).await?;
+    let cover_url = info
+        .thumbnail_url
+        .ok_or_else(|| AppError::NotFound("This video has no cover image".to_string()))?;
+
+    proxy_allowed_image(&cover_url).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn downloads_mount_misses_get_a_json_404() {
+        let response = download_not_found().await.into_response();
+        assert_eq!(response.status(), axum::http::StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "not_found");
+    }
+
+    #[test]
+    fn omitted_format_id_defaults_to_the_top_parsed_format() {
+        let formats = vec![
+            crate::models::FormatOption {
+                format_id: "hd".to_string(),
+                label: "1080p".to_string(),
+                ext: "mp4".to_string(),
+                filesize: None,
+                height: Some(1080),
+                video_only: false,
+                direct_url: None,
+            },
+            crate::models::FormatOption {
+                format_id: "sd".to_string(),
+                label: "540p".to_string(),
+                ext: "mp4".to_string(),
+                filesize: None,
+                height: Some(540),
+                video_only: false,
+                direct_url: None,
+            },
+        ];
+        assert_eq!(default_format(&formats).unwrap().format_id, "hd");
+        assert!(default_format(&[]).is_none());
+
+        // The query itself no longer requires format_id.
+        let query: StreamDownloadQuery =
+            serde_json::from_value(json!({ "url": "https://www.tiktok.com/@u/video/1" }))
+                .unwrap();
+        assert!(query.format_id.is_none());
+        // Off by default: a plain request keeps the fast piped path.
+        assert!(!query.strip_metadata);
+    }
+
+    #[test]
+    fn format_selectors_pass_through_verbatim_or_reject() {
+        // A legitimate selector is handed to -f unchanged.
+        let expression = "bv*[height<=1080]+ba/b";
+        assert_eq!(validated_format_selector(expression).unwrap(), expression);
+
+        // Shell metacharacters and empty input are refused up front.
+        for bad in ["best; rm -rf /", "best|cat", "best`id`", "$PATH", ""] {
+            assert!(validated_format_selector(bad).is_err(), "accepted: {bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn head_responses_carry_headers_and_no_body() {
+        let response = head_response(
+            "video/mp4",
+            "attachment; filename=\"clip_7.mp4\"",
+            Some(1234),
+        );
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let headers = response.headers().clone();
+        assert_eq!(headers[header::CONTENT_TYPE.as_str()], "video/mp4");
+        assert_eq!(headers[header::CONTENT_LENGTH.as_str()], "1234");
+        assert!(headers[header::CONTENT_DISPOSITION.as_str()]
+            .to_str()
+            .unwrap()
+            .contains("clip_7.mp4"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn disabled_profile_downloads_get_a_403_and_leave_videos_alone() {
+        let mut config = crate::config::AppConfig::from_env();
+        config.profile_downloads_enabled = false;
+        let err = ensure_profile_downloads_enabled(&config).unwrap_err();
+        assert_eq!(err.status_code(), axum::http::StatusCode::FORBIDDEN);
+
+        // Default posture keeps profile endpoints available.
+        config.profile_downloads_enabled = true;
+        assert!(ensure_profile_downloads_enabled(&config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reject_strategy_fails_fast_when_slots_are_gone() {
+        let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(1));
+        let _held = semaphore.clone().try_acquire_owned().unwrap();
+        let result =
+            acquire_download_slot(LoadShedStrategy::Reject, Duration::from_secs(5), semaphore)
+                .await;
+        assert!(matches!(result, Err(AppError::ServiceUnavailable(_))));
+    }
+
+    #[tokio::test]
+    async fn queue_strategy_waits_for_a_slot_to_free() {
+        let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(1));
+        let held = semaphore.clone().try_acquire_owned().unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(held);
+        });
+        let permit =
+            acquire_download_slot(LoadShedStrategy::Queue, Duration::from_secs(5), semaphore)
+                .await;
+        assert!(permit.is_ok());
+
+        // An empty semaphore that never frees still times out into a 503.
+        let exhausted = std::sync::Arc::new(tokio::sync::Semaphore::new(0));
+        let result = acquire_download_slot(
+            LoadShedStrategy::Queue,
+            Duration::from_millis(10),
+            exhausted,
+        )
+        .await;
+        assert!(matches!(result, Err(AppError::ServiceUnavailable(_))));
+    }
+
+    #[test]
+    fn csv_escaping_handles_special_characters() {
+        assert_eq!(csv_escape("plain"), "plain");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_escape("line\nbreak"), "\"line\nbreak\"");
+    }
+
+    #[test]
+    fn height_cap_rejects_only_taller_formats() {
+        // No cap configured: everything passes.
+        assert!(format_within_height_cap(Some(1080), None));
+        // At or below the cap is allowed, above it is not.
+        assert!(format_within_height_cap(Some(720), Some(720)));
+        assert!(!format_within_height_cap(Some(1080), Some(720)));
+        // Formats with no reported height can't be judged; let them through.
+        assert!(format_within_height_cap(None, Some(720)));
+    }
+
+    #[test]
+    fn content_disposition_uses_rfc5987_for_unicode_names() {
+        assert_eq!(
+            content_disposition_value("attachment", "video_1.mp4"),
+            "attachment; filename=\"video_1.mp4\""
+        );
+        let value = content_disposition_value("inline", "\u{52d5}\u{753b}_1.mp4");
+        assert!(value.starts_with("inline; filename=\"___1.mp4\""));
+        assert!(value.contains("filename*=UTF-8''%E5%8B%95%E7%94%BB_1.mp4"));
+    }
+
+    #[test]
+    fn hostile_titles_cannot_break_out_of_the_disposition_header() {
+        // Quotes and backslashes would end the quoted-string early.
+        assert_eq!(
+            content_disposition_value("attachment", "a\"b\\c.mp4"),
+            "attachment; filename=\"a_b_c.mp4\""
+        );
+        // CRLF in a header value is header injection; control characters
+        // are dropped outright.
+        assert_eq!(
+            content_disposition_value("attachment", "x\r\nSet-Cookie: pwned=1.mp4"),
+            "attachment; filename=\"xSet-Cookie: pwned=1.mp4\""
+        );
+        // Unicode names are scrubbed before the RFC 5987 split, so a
+        // hostile quote can't ride along in the fallback.
+        let value = content_disposition_value("attachment", "\u{52d5}\"\n.mp4");
+        assert!(value.starts_with("attachment; filename=\"__.mp4\""));
+        assert!(value.contains("filename*=UTF-8''%E5%8B%95_.mp4"));
+    }
+
+    #[test]
+    fn bundle_outputs_parse_and_reject_unknown_kinds() {
+        assert_eq!(parse_bundle_output("video:best").unwrap(), BundleOutput::Video(None));
+        assert_eq!(
+            parse_bundle_output("video:720").unwrap(),
+            BundleOutput::Video(Some(720))
+        );
+        assert_eq!(
+            parse_bundle_output("audio:mp3").unwrap(),
+            BundleOutput::Audio("mp3".to_string())
+        );
+        assert!(parse_bundle_output("audio:xyz").is_err());
+        assert!(parse_bundle_output("video:tall").is_err());
+        assert!(parse_bundle_output("subtitles:en").is_err());
+        assert!(parse_bundle_output("junk").is_err());
+    }
+
+    #[test]
+    fn debug_endpoints_require_the_admin_key() {
+        let mut config = crate::config::AppConfig::from_env();
+        let mut headers = HeaderMap::new();
+
+        // Without a configured key the endpoint doesn't exist at all.
+        config.admin_api_key = None;
+        assert!(matches!(
+            require_admin(&config, &headers),
+            Err(AppError::NotFound(_))
+        ));
+
+        config.admin_api_key = Some("s3cret".to_string());
+        // Missing or wrong key is rejected.
+        assert!(matches!(
+            require_admin(&config, &headers),
+            Err(AppError::Unauthorized(_))
+        ));
+        headers.insert("x-api-key", "wrong".parse().unwrap());
+        assert!(matches!(
+            require_admin(&config, &headers),
+            Err(AppError::Unauthorized(_))
+        ));
+        headers.insert("x-api-key", "s3cret".parse().unwrap());
+        assert!(require_admin(&config, &headers).is_ok());
+    }
+
+    #[tokio::test]
+    async fn sendfile_mode_sets_the_header_and_sends_no_body() {
+        let mut config = crate::config::AppConfig::from_env();
+        config.sendfile_header = Some("X-Accel-Redirect".to_string());
+        config.sendfile_location = "/protected-downloads".to_string();
+
+        let path = std::path::Path::new("/srv/downloads/tiktok_user_abc.zip");
+        let response =
+            sendfile_response(&config, "X-Accel-Redirect", path, "tiktok_user_abc.zip").unwrap();
+        assert_eq!(
+            response.headers()["x-accel-redirect"],
+            "/protected-downloads/tiktok_user_abc.zip"
+        );
+        assert_eq!(response.headers()[header::CONTENT_TYPE.as_str()], "application/zip");
+        let body = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+        assert!(body.is_empty());
+
+        // X-Sendfile takes the filesystem path instead of a location.
+        let response =
+            sendfile_response(&config, "X-Sendfile", path, "tiktok_user_abc.zip").unwrap();
+        assert_eq!(
+            response.headers()["x-sendfile"],
+            "/srv/downloads/tiktok_user_abc.zip"
+        );
+    }
+
+    #[test]
+    fn capabilities_reflect_config() {
+        let mut config = crate::config::AppConfig::from_env();
+        config.recaptcha_secret = Some("secret".to_string());
+        let probe = ToolProbe {
+            ffmpeg_available: true,
+            ffmpeg_version: Some("6.1.1".to_string()),
+            ytdlp_version: Some("2024.01.01".to_string()),
+        };
+        let caps = Capabilities::new(&config, &probe);
+        assert!(caps.recaptcha_enabled);
+        assert_eq!(caps.max_profile_videos, config.max_profile_videos);
+
+        let body = serde_json::to_value(&caps).unwrap();
+        for key in [
+            "recaptcha_enabled",
+            "ffmpeg_available",
+            "ffmpeg_version",
+            "ytdlp_version",
+            "max_profile_videos",
+            "max_batch_urls",
+            "supported_audio_formats",
+            "legacy_download_enabled",
+        ] {
+            assert!(body.get(key).is_some(), "missing capability field {key}");
+        }
+    }
+
+    #[test]
+    fn zip_path_must_stay_inside_downloads_dir() {
+        let mut config = crate::config::AppConfig::from_env();
+        config.downloads_dir = "./downloads".to_string();
+
+        assert!(zip_path_in_downloads_dir(&config, "./downloads/user_videos.zip").is_ok());
+        // Traversal and absolute paths outside the directory are rejected.
+        assert!(zip_path_in_downloads_dir(&config, "./downloads/../secret.zip").is_err());
+        assert!(zip_path_in_downloads_dir(&config, "/etc/passwd").is_err());
+        assert!(zip_path_in_downloads_dir(&config, "../downloads/user_videos.zip").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn zip_path_symlink_escape_is_unauthorized() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = crate::config::AppConfig::from_env();
+        config.downloads_dir = dir.path().to_string_lossy().into_owned();
+
+        let real = dir.path().join("real.zip");
+        std::fs::write(&real, b"zip").unwrap();
+        assert!(zip_path_in_downloads_dir(&config, &real.to_string_lossy()).is_ok());
+
+        // A symlink inside the directory pointing elsewhere passes the
+        // lexical screen but must fail canonicalization.
+        let outside = tempfile::NamedTempFile::new().unwrap();
+        let link = dir.path().join("escape.zip");
+        std::os::unix::fs::symlink(outside.path(), &link).unwrap();
+        assert!(matches!(
+            zip_path_in_downloads_dir(&config, &link.to_string_lossy()),
+            Err(AppError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn expired_archives_are_distinguished_from_unknown_paths() {
+        let zip_path = "./downloads/expired_test.zip";
+        assert!(!job_produced_zip(zip_path));
+        JOB_REGISTRY.lock().unwrap().insert(
+            "expired-test-job".to_string(),
+            ProfileJob {
+                download_id: "expired-test-job".to_string(),
+                profile_url: "https://www.tiktok.com/@user".to_string(),
+                status: JobStatus::Completed {
+                    zip_path: zip_path.to_string(),
+                    size: 1,
+                },
+            },
+        );
+        // The registry remembers the archive, so a missing file means it
+        // expired (410) rather than never existed (404).
+        assert!(job_produced_zip(zip_path));
+    }
+
+    #[test]
+    fn download_counter_persists_and_restores() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = crate::config::AppConfig::from_env();
+        config.downloads_dir = dir.path().to_string_lossy().into_owned();
+
+        std::fs::write(counter_path(&config), "41").unwrap();
+        restore_download_counter(&config);
+        let claimed = next_download_number(&config);
+        assert_eq!(claimed, 41);
+
+        // The persisted file now holds the next number to hand out.
+        let stored: u64 = std::fs::read_to_string(counter_path(&config))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(stored, 42);
+    }
+
+    #[test]
+    fn restart_marks_persisted_pending_jobs_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("jobs.json");
+        let jobs = vec![
+            ProfileJob {
+                download_id: "restore-pending".to_string(),
+                profile_url: "https://www.tiktok.com/@user".to_string(),
+                status: JobStatus::Pending,
+            },
+            ProfileJob {
+                download_id: "restore-done".to_string(),
+                profile_url: "https://www.tiktok.com/@user".to_string(),
+                status: JobStatus::Completed {
+                    zip_path: "a.zip".to_string(),
+                    size: 1,
+                },
+            },
+        ];
+        std::fs::write(&state_file, serde_json::to_string(&jobs).unwrap()).unwrap();
+
+        let mut config = crate::config::AppConfig::from_env();
+        config.job_state_file = Some(state_file.to_string_lossy().into_owned());
+        restore_job_registry(&config);
+
+        let registry = JOB_REGISTRY.lock().unwrap();
+        // The interrupted job is reported dead rather than left Pending.
+        assert!(matches!(
+            registry.get("restore-pending").unwrap().status,
+            JobStatus::Failed { .. }
+        ));
+        // Finished jobs come back untouched.
+        assert!(matches!(
+            registry.get("restore-done").unwrap().status,
+            JobStatus::Completed { .. }
+        ));
+    }
+
+    #[test]
+    fn idempotency_key_replays_the_same_job() {
+        let key = "test-key-replay";
+        let fingerprint = "https://www.tiktok.com/@user|true|Original";
+        assert_eq!(idempotent_job_id(key, fingerprint).unwrap(), None);
+        remember_idempotency_key(key, fingerprint, "job-1");
+        // The retried request gets the original job back.
+        assert_eq!(
+            idempotent_job_id(key, fingerprint).unwrap(),
+            Some("job-1".to_string())
+        );
+        // Reusing the key for a different request is rejected.
+        assert!(idempotent_job_id(key, "other|false|Numbered").is_err());
+    }
+
+    #[test]
+    fn profile_stats_exclude_missing_fields_from_the_aggregates() {
+        fn video(view_count: Option<u64>, like_count: Option<u64>, duration: Option<f64>) -> crate::models::ProfileVideoInfo {
+            crate::models::ProfileVideoInfo {
+                id: "1".to_string(),
+                url: "https://www.tiktok.com/@u/video/1".to_string(),
+                title: "t".to_string(),
+                duration,
+                view_count,
+                like_count,
+                upload_date: None,
+                thumbnail_url: None,
+                thumbnails: Vec::new(),
+                pinned: None,
+            }
+        }
+        let videos = vec![
+            video(Some(1000), Some(100), Some(10.0)),
+            video(Some(500), None, Some(20.0)),
+            video(None, None, None),
+        ];
+        let stats = compute_profile_stats("user".to_string(), &videos);
+        assert_eq!(stats.video_count, 3);
+        assert_eq!(stats.total_views, 1500);
+        assert_eq!(stats.videos_with_views, 2);
+        assert_eq!(stats.total_likes, 100);
+        assert_eq!(stats.videos_with_likes, 1);
+        assert_eq!(stats.average_duration, Some(15.0));
+        assert_eq!(stats.videos_with_duration, 2);
+
+        // An empty listing has no average at all.
+        let stats = compute_profile_stats("user".to_string(), &[]);
+        assert_eq!(stats.average_duration, None);
+        assert_eq!(stats.total_views, 0);
+    }
+
+    #[test]
+    fn webm_formats_keep_their_container_in_headers_and_filename() {
+        let format = crate::models::FormatOption {
+            format_id: "webm-720".to_string(),
+            label: "720p".to_string(),
+            ext: "webm".to_string(),
+            filesize: None,
+            height: Some(720),
+            video_only: false,
+            direct_url: None,
+        };
+        assert_eq!(video_container_content_type(&format.ext), "video/webm");
+        let filename = format!("title_1.{}", format.ext);
+        assert!(filename.ends_with(".webm"));
+        // Unknown containers still fall back to the mp4 default.
+        assert_eq!(video_container_content_type("mov"), "video/mp4");
+    }
+
+    #[test]
+    fn container_values_are_validated_and_typed() {
+        assert_eq!(validated_container("mkv").unwrap(), "mkv");
+        assert!(validated_container("avi").is_err());
+        assert_eq!(video_container_content_type("mkv"), "video/x-matroska");
+        assert_eq!(video_container_content_type("webm"), "video/webm");
+        assert_eq!(video_container_content_type("mp4"), "video/mp4");
+    }
+
+    #[test]
+    fn thumbnail_host_allowlist() {
+        assert!(is_allowed_thumbnail_host("p16-sign.tiktokcdn.com"));
+        assert!(is_allowed_thumbnail_host("tiktokcdn.com"));
+        assert!(!is_allowed_thumbnail_host("eviltiktokcdn.com"));
+        assert!(!is_allowed_thumbnail_host("example.com"));
+    }
+
+    /// One-shot image server handing back `bytes` as image/png.
+    async fn image_server(bytes: &'static [u8]) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let mut buf = [0u8; 4096];
+            let _ = socket.read(&mut buf).await;
+            let header = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: image/png\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                bytes.len()
+            );
+            let _ = socket.write_all(header.as_bytes()).await;
+            let _ = socket.write_all(bytes).await;
+        });
+        format!("http://{addr}/thumb.png")
+    }
+
+    #[tokio::test]
+    async fn inlined_thumbnails_encode_the_exact_bytes_served() {
+        let url = image_server(b"png-bytes").await;
+        let uri = fetch_thumbnail_data_uri(&url).await.unwrap();
+        // base64("png-bytes") == "cG5nLWJ5dGVz"
+        assert_eq!(uri, "data:image/png;base64,cG5nLWJ5dGVz");
+    }
+
+    #[test]
+    fn data_uri_carries_content_type_and_padding() {
+        assert_eq!(thumbnail_data_uri("image/jpeg", b"a"), "data:image/jpeg;base64,YQ==");
+    }
+
+    /// Server that 302s through `hops` intermediate paths before a 200.
+    async fn redirect_server(hops: usize) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                let step: usize = path.trim_start_matches("/hop/").parse().unwrap_or(0);
+                let reply = if step < hops {
+                    // Relative Location on purpose: the walker must resolve it.
+                    format!(
+                        "HTTP/1.1 302 Found
+location: /hop/{}
+content-length: 0
+connection: close
+
+",
+                        step + 1
+                    )
+                } else {
+                    "HTTP/1.1 200 OK
+content-length: 0
+connection: close
+
+".to_string()
+                };
+                let _ = socket.write_all(reply.as_bytes()).await;
+            }
+        });
+        format!("http://{addr}/hop/0")
+    }
+
+    #[tokio::test]
+    async fn the_resolver_reports_every_redirect_hop() {
+        let start = redirect_server(2).await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let chain = follow_redirect_chain(&client, &start, 10).await.unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0], start);
+        assert!(chain[1].ends_with("/hop/1"));
+        assert!(chain[2].ends_with("/hop/2"));
+    }
+
+    #[tokio::test]
+    async fn a_redirect_loop_trips_the_hop_limit() {
+        let start = redirect_server(usize::MAX).await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let err = follow_redirect_chain(&client, &start, 3).await.unwrap_err();
+        assert!(matches!(err, AppError::BadRequest(msg) if msg.contains("loop")));
+    }
+
+    #[test]
+    fn since_dates_must_look_like_real_days() {
+        assert!(validate_since_date("20240101").is_ok());
+        assert!(validate_since_date("19991231").is_ok());
+        for bad in ["2024-01-01", "2024011", "202401011", "20241301", "20240132", "latest"] {
+            assert!(validate_since_date(bad).is_err(), "{bad} should be rejected");
+        }
+    }
+
+    #[test]
+    fn the_audio_format_policy_gates_only_what_it_names() {
+        let allowed = vec!["mp3".to_string(), "m4a".to_string()];
+        assert!(ensure_audio_format_allowed(&allowed, "mp3").is_ok());
+        assert!(ensure_audio_format_allowed(&allowed, "m4a").is_ok());
+        let err = ensure_audio_format_allowed(&allowed, "wav").unwrap_err();
+        match err {
+            AppError::BadRequest(msg) => {
+                assert!(msg.contains("'wav'"), "{msg}");
+                assert!(msg.contains("mp3, m4a"), "{msg}");
+            }
+            other => panic!("expected BadRequest, got {other:?}"),
+        }
+        // No policy configured: everything supported stays open.
+        assert!(ensure_audio_format_allowed(&[], "wav").is_ok());
+    }
+}
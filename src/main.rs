@@ -0,0 +1,311 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use tokio::sync::Semaphore;
+use tower_http::{
+    cors::CorsLayer,
+    services::{ServeDir, ServeFile},
+    set_header::SetResponseHeaderLayer,
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
+};
+
+mod audit;
+mod config;
+mod error;
+mod handlers;
+mod models;
+mod rate_limit;
+mod recaptcha;
+mod service;
+mod stream;
+mod transcript;
+mod url_validator;
+mod zip_stream;
+
+use config::AppConfig;
+use rate_limit::RateLimiter;
+use recaptcha::RecaptchaService;
+use service::TikTokService;
+
+/// Shared application state cloned into every handler.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: AppConfig,
+    /// One service (and one temp dir) for the process; constructing it per
+    /// request scattered temp dirs and paid the setup cost every time.
+    pub service: Arc<TikTokService>,
+    pub recaptcha: RecaptchaService,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub download_semaphore: Arc<Semaphore>,
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "tiktok_downloader=info,tower_http=info".into()),
+        )
+        .init();
+
+    let config = AppConfig::from_env();
+    error::set_verbose_errors(config.verbose_errors);
+    service::init_http_client(&config);
+    // Reload any persisted profile jobs; interrupted ones are marked Failed
+    // so pollers get a definitive answer.
+    handlers::restore_job_registry(&config);
+    handlers::restore_download_counter(&config);
+    let state = AppState {
+        service: Arc::new(
+            TikTokService::new(&config).expect("failed to initialize download service"),
+        ),
+        recaptcha: RecaptchaService::new(
+            config.recaptcha_secret.clone(),
+            config.recaptcha_fail_open,
+        ),
+        rate_limiter: Arc::new(RateLimiter::new(config.rate_limit_per_minute)),
+        download_semaphore: Arc::new(Semaphore::new(config.max_concurrent_downloads)),
+        config: config.clone(),
+    };
+
+    // Routes that stream media bodies are long-lived by design and carry
+    // their own yt-dlp/ffmpeg timeouts, so the request timeout only wraps
+    // the quick JSON/metadata routes.
+    let streaming = Router::new()
+        .route("/api/video/download", post(handlers::download_video))
+        .route("/api/video/bundle", post(handlers::video_bundle))
+        .route("/api/video/stream", get(handlers::stream_video_download))
+        .route("/api/video/stream-muxed", get(handlers::stream_muxed_video))
+        .route("/api/audio/stream", get(handlers::stream_audio_download))
+        .route("/api/profile/stream-zip", get(handlers::stream_profile_zip))
+        .route("/api/video/watermark", get(handlers::watermark_video));
+
+    let timed = Router::new()
+        .route("/api/health", get(handlers::health))
+        .route("/api/capabilities", get(handlers::capabilities))
+        .route(
+            "/api/system/ytdlp-version",
+            get(handlers::ytdlp_version),
+        )
+        .route(
+            "/api/system/ffmpeg-version",
+            get(handlers::ffmpeg_version),
+        )
+        .route("/api/debug/formats", get(handlers::debug_formats))
+        .route("/api/debug/resolve", get(handlers::debug_resolve))
+        .route("/api/validate", post(handlers::validate_urls))
+        .route("/api/video/info", post(handlers::video_info))
+        .route("/api/batch/info", post(handlers::batch_info))
+        .route("/api/batch/estimate", post(handlers::batch_estimate))
+        .route("/api/profile/info", post(handlers::profile_info))
+        .route("/api/profile/stats", post(handlers::profile_stats))
+        .route("/api/profile/export", post(handlers::profile_export))
+        .route("/api/profile/download", post(handlers::profile_download))
+        .route(
+            "/api/profile/download-selected",
+            post(handlers::profile_download_selected),
+        )
+        .route(
+            "/api/profile/status/:download_id",
+            get(handlers::profile_download_status),
+        )
+        .route("/api/thumbnail/proxy", get(handlers::thumbnail_proxy))
+        .route("/api/photo/image", get(handlers::photo_image))
+        .route("/api/video/cover", get(handlers::video_cover))
+        .route("/api/video/transcript", get(handlers::video_transcript))
+        .route("/api/video/direct-url", get(handlers::direct_url))
+        .route("/api/video/manifest", get(handlers::video_manifest));
+
+    let api = apply_request_timeout(timed, config.request_timeout_secs)
+        .merge(streaming)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::rate_limit_middleware,
+        ));
+
+    // The static mount exposes every built archive to anyone who can guess
+    // its name, so it stays off unless the operator opts in; the streaming
+    // endpoint is the supported way to fetch ZIPs. When it is on, archives
+    // are cacheable for an hour (names can be reused across rebuilds, so
+    // not immutable) and misses return the API's JSON 404 instead of
+    // ServeDir's plain-text one. Precompressed variants are pointless here
+    // — the entries are already-compressed media — so they stay off.
+    let api = if config.serve_downloads_dir {
+        api.nest_service(
+            "/api/downloads",
+            tower::ServiceBuilder::new()
+                .layer(SetResponseHeaderLayer::if_not_present(
+                    axum::http::header::CACHE_CONTROL,
+                    axum::http::HeaderValue::from_static("private, max-age=3600"),
+                ))
+                .service(
+                    ServeDir::new(&config.downloads_dir)
+                        .not_found_service(get(handlers::download_not_found)),
+                ),
+        )
+    } else {
+        api
+    };
+
+    let app = api
+        .route_service("/", ServeFile::new("index.html"))
+        .layer(CorsLayer::permissive())
+        .layer(TraceLayer::new_for_http())
+        .with_state(state);
+
+    let addr: SocketAddr = format!("{}:{}", config.host, config.port)
+        .parse()
+        .expect("invalid HOST/PORT configuration");
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    let listener = bind_with_keepalive(addr, config.tcp_keepalive_secs);
+
+    // Serve TLS directly when a cert/key pair is configured, for deployments
+    // without a reverse proxy in front; otherwise plain HTTP as before.
+    if let Some((cert, key)) = config.tls_paths() {
+        let tls = axum_server::tls_rustls::RustlsConfig::from_pem_file(cert, key)
+            .await
+            .unwrap_or_else(|e| {
+                panic!("failed to load TLS cert/key ({cert}, {key}): {e}");
+            });
+        tracing::info!(%addr, "starting tiktok-downloader (https)");
+        axum_server::from_tcp_rustls(listener, tls)
+            .serve(make_service)
+            .await
+            .expect("server error");
+    } else {
+        tracing::info!(%addr, "starting tiktok-downloader");
+        listener
+            .set_nonblocking(true)
+            .expect("failed to configure listener");
+        let listener =
+            tokio::net::TcpListener::from_std(listener).expect("failed to adopt listener");
+        axum::serve(listener, make_service)
+            .await
+            .expect("server error");
+    }
+}
+
+/// Wrap `router` in an overall per-request timeout, or leave it untouched
+/// when the timeout is disabled (0). Timed-out requests get a 408.
+fn apply_request_timeout<S>(router: Router<S>, secs: u64) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    if secs > 0 {
+        router.layer(TimeoutLayer::new(Duration::from_secs(secs)))
+    } else {
+        router
+    }
+}
+
+/// Bind the listening socket with TCP keep-alive configured, so half-dead
+/// clients are eventually noticed instead of pinning connections open.
+/// Accepted sockets inherit the option from the listener.
+fn bind_with_keepalive(addr: SocketAddr, keepalive_secs: u64) -> std::net::TcpListener {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )
+    .expect("failed to create listening socket");
+    if keepalive_secs > 0 {
+        let keepalive = socket2::TcpKeepalive::new().with_time(Duration::from_secs(keepalive_secs));
+        socket
+            .set_tcp_keepalive(&keepalive)
+            .expect("failed to set TCP keep-alive");
+    }
+    socket
+        .set_reuse_address(true)
+        .expect("failed to set SO_REUSEADDR");
+    socket
+        .bind(&addr.into())
+        .expect("failed to bind listener");
+    socket.listen(1024).expect("failed to listen");
+    socket.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn slow_handlers_are_cut_off_at_the_request_timeout() {
+        async fn stuck() -> &'static str {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            "too late"
+        }
+        let app = apply_request_timeout(Router::new().route("/slow", get(stuck)), 1);
+        let response = app
+            .oneshot(Request::builder().uri("/slow").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn a_tls_handshake_succeeds_against_a_self_signed_cert() {
+        use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        // The same loading and serving entry points main() uses.
+        let tls = axum_server::tls_rustls::RustlsConfig::from_pem_file(&cert_path, &key_path)
+            .await
+            .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/ping", get(|| async { "pong" }));
+        tokio::spawn(async move {
+            axum_server::from_tcp_rustls(listener, tls)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        // A client that trusts exactly this certificate must complete the
+        // handshake; anything short of a negotiated session is a failure.
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config));
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let stream = connector.connect(server_name, tcp).await.unwrap();
+        assert!(stream.get_ref().1.protocol_version().is_some());
+    }
+
+    #[tokio::test]
+    async fn zero_disables_the_request_timeout() {
+        async fn slowish() -> &'static str {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            "fine"
+        }
+        let app = apply_request_timeout(Router::new().route("/slowish", get(slowish)), 0);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/slowish")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
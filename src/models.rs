@@ -0,0 +1,648 @@
+use serde::{Deserialize, Serialize};
+
+// ---------------------------------------------------------------------------
+// Request bodies / query strings
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize)]
+pub struct VideoInfoRequest {
+    pub url: String,
+    /// Skip the metadata cache and re-run extraction (the cache otherwise
+    /// serves entries for five minutes).
+    #[serde(default)]
+    pub refresh: bool,
+    /// Netscape-format cookie text for private videos; only honored when the
+    /// server enables per-request cookies.
+    pub cookies: Option<String>,
+    /// Fetch thumbnails server-side and return them as base64 data URIs in
+    /// place of remote URLs, for UIs that must render offline.
+    #[serde(default)]
+    pub inline_thumbnails: bool,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchInfoRequest {
+    pub urls: Vec<String>,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidateRequest {
+    pub urls: Vec<String>,
+}
+
+/// One classified link from /api/validate.
+#[derive(Debug, Serialize)]
+pub struct ValidateResult {
+    pub url: String,
+    pub kind: crate::url_validator::UrlKind,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileInfoRequest {
+    pub profile_url: String,
+    /// When set, keep only pinned (`true`) or only regular (`false`) videos.
+    /// Videos whose metadata doesn't say either way count as regular.
+    pub pinned: Option<bool>,
+    /// Same flag as on the video info endpoint: inline thumbnails as base64
+    /// data URIs, bounded to the first few videos.
+    #[serde(default)]
+    pub inline_thumbnails: bool,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileStatsRequest {
+    pub profile_url: String,
+    pub recaptcha_token: Option<String>,
+}
+
+/// Aggregates over an enumerated profile, as returned by
+/// /api/profile/stats. Every metric says how many videos actually carried
+/// the underlying field, since yt-dlp's listings are often partial.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ProfileStats {
+    pub username: String,
+    /// Videos enumerated (bounded by MAX_PROFILE_VIDEOS, so a huge
+    /// profile yields stats over its most recent slice).
+    pub video_count: usize,
+    pub total_views: u64,
+    pub videos_with_views: usize,
+    pub total_likes: u64,
+    pub videos_with_likes: usize,
+    /// Mean duration in seconds over the videos that report one.
+    pub average_duration: Option<f64>,
+    pub videos_with_duration: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileExportRequest {
+    pub profile_url: String,
+    /// Export format: "json" or "csv".
+    pub format: String,
+    pub recaptcha_token: Option<String>,
+}
+
+/// How entries inside a profile ZIP are named.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZipNaming {
+    /// Keep yt-dlp's uploader_title_id filenames.
+    #[default]
+    Original,
+    /// Prefix entries with a zero-padded sequence number so archives
+    /// browse in a sensible order.
+    Numbered,
+}
+
+/// Chronological direction for entries inside a profile ZIP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadOrder {
+    /// Most recent upload first, matching how the profile page reads.
+    #[default]
+    Newest,
+    /// Oldest upload first, so the archive replays the account's history.
+    Oldest,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileDownloadRequest {
+    pub profile_url: String,
+    /// When true, a per-video .info.json sidecar is included in the ZIP.
+    #[serde(default)]
+    pub include_metadata: bool,
+    #[serde(default)]
+    pub naming: ZipNaming,
+    /// Chronological ordering of the archive entries; meaningful together
+    /// with numbered naming, which freezes the order into the names.
+    #[serde(default)]
+    pub order: DownloadOrder,
+    /// When true, a manifest.json plus a human-readable index.txt are
+    /// written at the archive root describing every video in the ZIP.
+    #[serde(default)]
+    pub include_manifest: bool,
+    /// When set, the archive is split into numbered parts, each staying
+    /// under this many bytes (single oversized videos still get a part of
+    /// their own). Unset keeps the single-ZIP behavior.
+    pub split_size_bytes: Option<u64>,
+    /// YYYYMMDD floor for incremental archiving: only videos uploaded on
+    /// or after this date are fetched. Unset downloads the whole profile.
+    pub since_date: Option<String>,
+    /// Custom in-ZIP filename pattern built from {id}, {title}, {date},
+    /// {index} and {uploader} tokens, e.g. "{date}_{title}". Unset keeps
+    /// the stock uploader_title_id naming.
+    pub filename_template: Option<String>,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SelectedDownloadRequest {
+    pub profile_url: String,
+    /// Individual video URLs picked by the user from the profile listing.
+    pub urls: Vec<String>,
+    /// When true, a per-video .info.json sidecar is included in the ZIP.
+    #[serde(default)]
+    pub include_metadata: bool,
+    #[serde(default)]
+    pub naming: ZipNaming,
+    pub recaptcha_token: Option<String>,
+}
+
+/// Body of the deprecated POST /api/video/download endpoint.
+#[derive(Debug, Deserialize)]
+pub struct VideoDownloadRequest {
+    pub url: String,
+    pub format_id: String,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamDownloadQuery {
+    pub url: String,
+    /// Explicit format to download. When omitted the server picks the best
+    /// parsed format itself, so a URL alone is enough for a download.
+    pub format_id: Option<String>,
+    /// Raw yt-dlp format selector expression (e.g. "bv*[height<=1080]+ba/b")
+    /// passed verbatim to -f, for users fluent in that syntax. Takes
+    /// precedence over format_id; selection errors surface from yt-dlp
+    /// rather than up-front validation.
+    pub format_selector: Option<String>,
+    /// Stream the true best quality (bestvideo+bestaudio muxed by ffmpeg)
+    /// instead of the given format_id. Falls back to `best` without ffmpeg.
+    #[serde(default)]
+    pub best_quality: bool,
+    /// Embed soft subtitles into the file (requires ffmpeg). Uses the
+    /// file-then-stream path; ignored for trimmed downloads.
+    #[serde(default)]
+    pub embed_subs: bool,
+    /// Comma-separated subtitle language codes for embed_subs; all when unset.
+    pub sub_langs: Option<String>,
+    /// Strip the audio track and serve video only. Streams a video-only
+    /// format when TikTok offers one; otherwise the audio is removed with
+    /// ffmpeg.
+    #[serde(default)]
+    pub mute: bool,
+    /// Pick the format automatically: the largest one not exceeding this
+    /// many bytes (or the smallest when all do). Overrides format_id.
+    pub target_filesize: Option<u64>,
+    /// Scrub embedded metadata (location, device info) with ffmpeg before
+    /// serving. Forces the slower file-then-stream path.
+    #[serde(default)]
+    pub strip_metadata: bool,
+    /// format_id of an audio-only track to mux under the best video
+    /// (requires ffmpeg), for videos with multiple audio tracks (dubs).
+    /// The tracks are listed as `audio_tracks` on the info endpoint.
+    pub audio_track: Option<String>,
+    /// Remux the download into this container ("mp4", "mkv" or "webm")
+    /// with yt-dlp's --remux-video (requires ffmpeg). Orthogonal to format
+    /// selection; unset keeps whatever container the format comes in.
+    pub container: Option<String>,
+    /// Netscape-format cookie text for private videos; only honored when the
+    /// server enables per-request cookies.
+    pub cookies: Option<String>,
+    /// "attachment" (default) to force a download, "inline" for previewing.
+    pub disposition: Option<String>,
+    /// Clip start in seconds; requires ffmpeg. Defaults to the video start.
+    pub start_time: Option<f64>,
+    /// Clip end in seconds; requires ffmpeg. Defaults to the video end.
+    pub end_time: Option<f64>,
+    pub recaptcha_token: Option<String>,
+}
+
+/// Body of POST /api/video/bundle: one video, several renditions.
+#[derive(Debug, Deserialize)]
+pub struct BundleRequest {
+    pub url: String,
+    /// Requested renditions, e.g. ["video:720", "audio:mp3"].
+    pub outputs: Vec<String>,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AudioStreamQuery {
+    pub url: String,
+    /// Target audio format, e.g. "mp3" (default) or "m4a".
+    pub format: Option<String>,
+    /// "attachment" (default) to force a download, "inline" for previewing.
+    pub disposition: Option<String>,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MuxedStreamQuery {
+    pub url: String,
+    /// "attachment" (default) to force a download, "inline" for previewing.
+    pub disposition: Option<String>,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileStreamQuery {
+    /// Path of a previously built ZIP, as returned by the download endpoints.
+    pub zip_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DebugFormatsQuery {
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DebugResolveQuery {
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailProxyQuery {
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CoverQuery {
+    pub url: String,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WatermarkQuery {
+    pub url: String,
+    /// Overlay corner: "top-left", "top-right", "bottom-left" or
+    /// "bottom-right" (the default).
+    pub position: Option<String>,
+    /// Overlay opacity from 0.0 (invisible) to 1.0 (opaque, the default).
+    pub opacity: Option<f32>,
+    /// "attachment" (default) to force a download, "inline" for previewing.
+    pub disposition: Option<String>,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManifestQuery {
+    pub url: String,
+    pub recaptcha_token: Option<String>,
+}
+
+/// Adaptive-playback manifest for in-browser players, returned by
+/// /api/video/manifest.
+#[derive(Debug, Serialize)]
+pub struct ManifestResponse {
+    /// "hls" or "dash".
+    pub kind: String,
+    pub manifest_url: String,
+    /// Headers the player must send when fetching the manifest/segments.
+    pub headers: std::collections::HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TranscriptQuery {
+    pub url: String,
+    /// Caption language code; the first available track when unset.
+    pub lang: Option<String>,
+    pub recaptcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DirectUrlQuery {
+    pub url: String,
+    pub format_id: String,
+    pub recaptcha_token: Option<String>,
+}
+
+/// Resolved CDN location for one format, returned by /api/video/direct-url.
+#[derive(Debug, Serialize)]
+pub struct DirectUrlResponse {
+    pub format_id: String,
+    pub direct_url: String,
+    /// Headers the client must send when fetching from the CDN.
+    pub headers: std::collections::HashMap<String, String>,
+    /// Reminder that CDN URLs are signed and short-lived.
+    pub warning: String,
+}
+
+// ---------------------------------------------------------------------------
+// API responses
+// ---------------------------------------------------------------------------
+
+/// Metadata for a single video, as returned by `/api/video/info`.
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoInfo {
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    pub uploader: String,
+    pub duration: Option<f64>,
+    pub view_count: Option<u64>,
+    pub like_count: Option<u64>,
+    pub comment_count: Option<u64>,
+    /// Upload date in YYYYMMDD form, as yt-dlp reports it.
+    pub upload_date: Option<String>,
+    pub thumbnail_url: Option<String>,
+    /// Every thumbnail yt-dlp reports, smallest first, so the UI can pick a
+    /// size per view. `thumbnail_url` stays as the single "best" choice for
+    /// backward compatibility.
+    pub thumbnails: Vec<ThumbnailOption>,
+    pub formats: Vec<FormatOption>,
+    /// Language codes of available caption tracks, sorted; empty when the
+    /// video has none.
+    pub subtitle_languages: Vec<String>,
+    /// Hashtags from the description, without the leading '#', in order of
+    /// appearance and de-duplicated.
+    pub hashtags: Vec<String>,
+    /// Accounts @-mentioned in the description, without the leading '@'.
+    pub mentions: Vec<String>,
+    /// Effect/filter identifiers from the video's metadata; empty when
+    /// TikTok (or yt-dlp) reports none.
+    pub effects: Vec<String>,
+    /// Audio-only tracks for videos that carry more than one (dubs);
+    /// pass a track's format_id as `audio_track` on the stream endpoint
+    /// to mux it instead of the default audio.
+    pub audio_tracks: Vec<AudioTrackOption>,
+}
+
+/// One selectable audio track of a multi-audio video.
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioTrackOption {
+    pub format_id: String,
+    /// Language code when yt-dlp reports one ("en", "es-MX"...).
+    pub language: Option<String>,
+    /// Human label: the language, or the format note, or "audio".
+    pub label: String,
+    pub filesize: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThumbnailOption {
+    pub id: Option<String>,
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// One downloadable rendition the UI can offer.
+#[derive(Debug, Clone, Serialize)]
+pub struct FormatOption {
+    pub format_id: String,
+    /// Human label, e.g. "720p".
+    pub label: String,
+    pub ext: String,
+    pub height: Option<u32>,
+    pub filesize: Option<u64>,
+    /// True when the format carries no audio track; pairs with the `mute`
+    /// download option.
+    pub video_only: bool,
+    /// Direct media URL, only set for formats resolved by the fallback
+    /// extractor; yt-dlp formats expose theirs via /api/video/direct-url.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direct_url: Option<String>,
+}
+
+/// Outcome of one selected URL in a streamed selective download, packed
+/// into the archive as `download_report.json` so the user can see which of
+/// their picks failed and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct SelectedVideoResult {
+    pub url: String,
+    /// "ok" or "failed".
+    pub status: String,
+    /// Short reason for a failure, already mapped from yt-dlp stderr.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Compact per-video entry used in profile listings.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileVideoInfo {
+    pub id: String,
+    pub url: String,
+    pub title: String,
+    pub duration: Option<f64>,
+    pub view_count: Option<u64>,
+    /// Like count when the listing carries it; flat playlist output often
+    /// omits it.
+    pub like_count: Option<u64>,
+    pub upload_date: Option<String>,
+    pub thumbnail_url: Option<String>,
+    /// Every thumbnail yt-dlp reports, smallest first.
+    pub thumbnails: Vec<ThumbnailOption>,
+    /// Whether the creator pinned this video to the top of their profile.
+    /// None when yt-dlp's metadata doesn't distinguish pinned from regular,
+    /// which is the common case for flat playlist listings.
+    pub pinned: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileInfo {
+    pub username: String,
+    pub video_count: usize,
+    pub videos: Vec<ProfileVideoInfo>,
+    /// True when enumeration hit the soft time limit and this is only the
+    /// front of the profile.
+    pub truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchInfoResult {
+    pub url: String,
+    pub info: Option<VideoInfo>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EstimateResult {
+    pub url: String,
+    pub filesize: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Status of an asynchronous profile download job. Deserialize exists so
+/// persisted jobs can be reloaded after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum JobStatus {
+    Pending,
+    InProgress,
+    Completed { zip_path: String, size: u64 },
+    /// Split download: every part must be fetched for the full profile.
+    CompletedParts { parts: Vec<ZipPart> },
+    Failed { reason: String },
+}
+
+/// One line of the manifest.json written into profile ZIPs, tying an
+/// archive entry back to the video it came from.
+#[derive(Debug, Serialize)]
+pub struct ZipManifestEntry {
+    /// Downloaded file name, when the video made it into the archive.
+    pub filename: Option<String>,
+    pub url: String,
+    pub title: String,
+    pub upload_date: Option<String>,
+}
+
+/// One part of a split profile archive.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ZipPart {
+    pub zip_path: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileJob {
+    pub download_id: String,
+    pub profile_url: String,
+    pub status: JobStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProfileDownloadResponse {
+    pub download_id: String,
+    pub status: JobStatus,
+}
+
+// ---------------------------------------------------------------------------
+// yt-dlp JSON deserialization
+// ---------------------------------------------------------------------------
+
+/// Subset of yt-dlp's single-video JSON (`yt-dlp -j`) that we consume.
+#[derive(Debug, Clone, Deserialize)]
+pub struct YtDlpVideoInfo {
+    pub id: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub uploader: Option<String>,
+    pub duration: Option<f64>,
+    pub view_count: Option<u64>,
+    pub like_count: Option<u64>,
+    pub comment_count: Option<u64>,
+    pub upload_date: Option<String>,
+    #[serde(default)]
+    pub thumbnails: Vec<YtDlpThumbnail>,
+    #[serde(default)]
+    pub formats: Vec<YtDlpFormat>,
+    /// Caption tracks keyed by language code; we only care about the keys.
+    #[serde(default)]
+    pub subtitles: std::collections::HashMap<String, serde_json::Value>,
+    /// Effect/filter identifiers TikTok attaches to the video; the field
+    /// name has shifted across yt-dlp releases, hence the aliases.
+    #[serde(default, alias = "_effect", alias = "effects")]
+    pub effect_ids: Vec<String>,
+    /// yt-dlp sets this to "playlist" when the URL expanded into multiple
+    /// entries (sound pages, collections); absent for single videos.
+    #[serde(rename = "_type")]
+    pub object_type: Option<String>,
+    /// The expanded entries when `_type` is "playlist"; empty otherwise.
+    #[serde(default)]
+    pub entries: Vec<YtDlpPlaylistEntry>,
+}
+
+impl YtDlpVideoInfo {
+    pub fn is_playlist(&self) -> bool {
+        self.object_type.as_deref() == Some("playlist")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct YtDlpFormat {
+    pub format_id: String,
+    /// Direct media URL. Absent for merged selections that need muxing.
+    pub url: Option<String>,
+    /// Headers TikTok's CDN requires to serve the URL (Referer etc.).
+    #[serde(default)]
+    pub http_headers: std::collections::HashMap<String, String>,
+    pub ext: Option<String>,
+    pub height: Option<u32>,
+    pub filesize: Option<u64>,
+    pub filesize_approx: Option<u64>,
+    pub vcodec: Option<String>,
+    pub acodec: Option<String>,
+    pub format_note: Option<String>,
+    /// Audio language code for multi-audio videos (dubs).
+    pub language: Option<String>,
+    /// Delivery protocol ("https", "m3u8_native", "http_dash_segments"...).
+    pub protocol: Option<String>,
+    /// Adaptive manifest this format came from, for HLS/DASH entries.
+    pub manifest_url: Option<String>,
+}
+
+impl YtDlpFormat {
+    /// Exact size when yt-dlp knows it, approximate otherwise.
+    pub fn best_filesize(&self) -> Option<u64> {
+        self.filesize.or(self.filesize_approx)
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.vcodec.as_deref().map(|v| v != "none").unwrap_or(false)
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.acodec.as_deref().map(|a| a != "none").unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct YtDlpThumbnail {
+    pub id: Option<String>,
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PhotoImageQuery {
+    pub url: String,
+    /// Zero-based slide index within the carousel.
+    pub index: usize,
+    pub recaptcha_token: Option<String>,
+}
+
+// ---------------------------------------------------------------------------
+// Fallback extractor deserialization
+// ---------------------------------------------------------------------------
+
+/// Envelope of a tikwm-style fallback API (`GET <base>?url=...`): code 0
+/// means success, anything else carries a message in `msg`.
+#[derive(Debug, Deserialize)]
+pub struct FallbackApiResponse {
+    pub code: i64,
+    pub msg: Option<String>,
+    pub data: Option<FallbackVideoData>,
+}
+
+/// The subset of the fallback API's `data` object we can map onto
+/// [`VideoInfo`].
+#[derive(Debug, Deserialize)]
+pub struct FallbackVideoData {
+    pub id: String,
+    pub title: Option<String>,
+    /// Direct no-watermark MP4 URL.
+    pub play: Option<String>,
+    pub cover: Option<String>,
+    pub duration: Option<f64>,
+    pub play_count: Option<u64>,
+    pub digg_count: Option<u64>,
+    pub comment_count: Option<u64>,
+    pub author: Option<FallbackAuthor>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FallbackAuthor {
+    pub unique_id: Option<String>,
+    pub nickname: Option<String>,
+}
+
+/// Flat-playlist entry emitted by `yt-dlp --flat-playlist -j` for profiles.
+#[derive(Debug, Clone, Deserialize)]
+pub struct YtDlpPlaylistEntry {
+    pub id: String,
+    pub url: Option<String>,
+    pub title: Option<String>,
+    pub duration: Option<f64>,
+    pub view_count: Option<u64>,
+    pub like_count: Option<u64>,
+    pub upload_date: Option<String>,
+    #[serde(default)]
+    pub thumbnails: Vec<YtDlpThumbnail>,
+    /// Set by extractor versions that flag pinned videos; absent otherwise.
+    pub is_pinned: Option<bool>,
+}
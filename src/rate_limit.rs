@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{error::AppError, AppState};
+
+/// The resolved client address, inserted into request extensions by the
+/// rate-limit middleware so handlers (and reCAPTCHA) see the same IP the
+/// limiter counted.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Fixed-window per-IP rate limiter kept entirely in memory.
+pub struct RateLimiter {
+    max_per_minute: u32,
+    hits: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_per_minute: u32) -> Self {
+        Self {
+            max_per_minute,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a hit for `ip`, returning false when the caller is over budget.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        let entry = hits.entry(ip).or_default();
+        entry.retain(|t| now.duration_since(*t) < WINDOW);
+        if entry.len() >= self.max_per_minute as usize {
+            return false;
+        }
+        entry.push(now);
+        true
+    }
+}
+
+/// The client IP to bill requests against. Forwarded headers are only
+/// honored when the direct peer is a configured trusted proxy — anyone
+/// else could spoof X-Forwarded-For to dodge the rate limiter — otherwise
+/// the socket address is authoritative.
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr, trusted: &[ipnet::IpNet]) -> IpAddr {
+    if !trusted.iter().any(|net| net.contains(&peer.ip())) {
+        return peer.ip();
+    }
+    let header_ip = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .and_then(|v| v.trim().parse::<IpAddr>().ok())
+        .or_else(|| {
+            headers
+                .get("x-real-ip")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<IpAddr>().ok())
+        });
+    header_ip.unwrap_or_else(|| peer.ip())
+}
+
+/// Whether a request from `ip` may proceed: allowlisted infrastructure
+/// (RATE_LIMIT_BYPASS) skips the limiter entirely and consumes no budget;
+/// everyone else is counted against the per-minute window.
+pub fn allow_request(limiter: &RateLimiter, ip: IpAddr, bypass: &[ipnet::IpNet]) -> bool {
+    bypass.iter().any(|net| net.contains(&ip)) || limiter.check(ip)
+}
+
+pub async fn rate_limit_middleware(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request<axum::body::Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    let ip = client_ip(request.headers(), peer, &state.config.trusted_proxies);
+    if !allow_request(&state.rate_limiter, ip, &state.config.rate_limit_bypass) {
+        tracing::warn!(%ip, "rate limit exceeded");
+        return Err(AppError::ServiceUnavailable(
+            "Too many requests, slow down".to_string(),
+        ));
+    }
+    request.extensions_mut().insert(ClientIp(ip));
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xff(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn spoofed_forwarded_headers_from_untrusted_peers_are_ignored() {
+        let peer: SocketAddr = "203.0.113.9:40000".parse().unwrap();
+        let headers = xff("1.2.3.4");
+
+        // No trusted proxies: the header is a lie until proven otherwise.
+        assert_eq!(
+            client_ip(&headers, peer, &[]),
+            "203.0.113.9".parse::<IpAddr>().unwrap()
+        );
+        // Peer outside the trusted range: still ignored.
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        assert_eq!(
+            client_ip(&headers, peer, &trusted),
+            "203.0.113.9".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn bypassed_ips_are_never_throttled_and_spend_no_budget() {
+        let limiter = RateLimiter::new(1);
+        let bypass: Vec<ipnet::IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let internal: IpAddr = "10.3.2.1".parse().unwrap();
+
+        for _ in 0..50 {
+            assert!(allow_request(&limiter, internal, &bypass));
+        }
+        // The allowlist short-circuits before the limiter, so the budget
+        // for that IP is still untouched.
+        assert!(limiter.check(internal));
+    }
+
+    #[test]
+    fn unlisted_ips_still_hit_the_limit() {
+        let limiter = RateLimiter::new(1);
+        let bypass: Vec<ipnet::IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let public: IpAddr = "203.0.113.9".parse().unwrap();
+
+        assert!(allow_request(&limiter, public, &bypass));
+        assert!(!allow_request(&limiter, public, &bypass));
+    }
+
+    #[test]
+    fn forwarded_headers_from_trusted_proxies_are_honored() {
+        let peer: SocketAddr = "10.1.2.3:40000".parse().unwrap();
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+
+        assert_eq!(
+            client_ip(&xff("1.2.3.4"), peer, &trusted),
+            "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+        // First hop of a multi-entry XFF chain wins.
+        assert_eq!(
+            client_ip(&xff("1.2.3.4, 10.0.0.1"), peer, &trusted),
+            "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+        // Garbage in the header falls back to the socket address.
+        assert_eq!(
+            client_ip(&xff("not-an-ip"), peer, &trusted),
+            "10.1.2.3".parse::<IpAddr>().unwrap()
+        );
+    }
+}
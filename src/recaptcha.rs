@@ -0,0 +1,214 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::AppError;
+
+const SITEVERIFY_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
+
+/// Per-attempt ceiling so a hanging siteverify endpoint can't stall every
+/// download behind it.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Transient network errors get this many tries in total before the
+/// fail-open/fail-closed decision is made.
+const VERIFY_ATTEMPTS: u32 = 3;
+
+/// Base backoff between attempts; doubles after each failure.
+const VERIFY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Thin wrapper around Google's reCAPTCHA siteverify endpoint.
+///
+/// When no secret is configured the service is disabled and every token
+/// passes, which keeps local development friction-free.
+#[derive(Clone)]
+pub struct RecaptchaService {
+    client: reqwest::Client,
+    secret: Option<String>,
+    /// When true, an unreachable siteverify API lets requests through
+    /// (with a warning) instead of failing them; an *invalid token* is
+    /// still always rejected.
+    fail_open: bool,
+    verify_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+impl RecaptchaService {
+    pub fn new(secret: Option<String>, fail_open: bool) -> Self {
+        Self {
+            client: crate::service::shared_http_client().clone(),
+            secret,
+            fail_open,
+            verify_url: SITEVERIFY_URL.to_string(),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_verify_url(mut self, url: &str) -> Self {
+        self.verify_url = url.to_string();
+        self
+    }
+
+    /// Verify a client token. `remote_ip` is forwarded to Google when known.
+    pub async fn verify_token(
+        &self,
+        token: Option<&str>,
+        remote_ip: Option<&str>,
+    ) -> Result<(), AppError> {
+        let Some(secret) = &self.secret else {
+            return Ok(());
+        };
+        let token = token
+            .filter(|t| !t.is_empty())
+            .ok_or_else(|| AppError::BadRequest("reCAPTCHA token is required".to_string()))?;
+
+        let mut params = vec![("secret", secret.as_str()), ("response", token)];
+        if let Some(ip) = remote_ip {
+            params.push(("remoteip", ip));
+        }
+
+        // A rejected token comes back as a successful response with
+        // `success: false` and is never retried; only attempts that fail to
+        // produce a response at all (connect errors, timeouts) are worth a
+        // second try.
+        let mut api_result = self.send_verify(&params).await;
+        for attempt in 1..VERIFY_ATTEMPTS {
+            match &api_result {
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => {
+                    tracing::warn!(error = %e, attempt, "siteverify attempt failed; retrying");
+                    tokio::time::sleep(VERIFY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                    api_result = self.send_verify(&params).await;
+                }
+                _ => break,
+            }
+        }
+
+        let body = match api_result {
+            Ok(body) => body,
+            // Every attempt failed (network, timeout, bad gateway) — the
+            // user did nothing wrong.
+            Err(e) => {
+                if self.fail_open {
+                    tracing::warn!(error = %e, "siteverify unreachable; failing open");
+                    return Ok(());
+                }
+                return Err(AppError::BadRequest(format!(
+                    "reCAPTCHA verification unavailable: {e}"
+                )));
+            }
+        };
+
+        if body.success {
+            Ok(())
+        } else {
+            tracing::warn!(errors = ?body.error_codes, "reCAPTCHA token rejected");
+            Err(AppError::Unauthorized(
+                "reCAPTCHA verification failed".to_string(),
+            ))
+        }
+    }
+
+    /// One siteverify POST with its per-attempt timeout applied.
+    async fn send_verify(
+        &self,
+        params: &[(&str, &str)],
+    ) -> Result<SiteVerifyResponse, reqwest::Error> {
+        let response = self
+            .client
+            .post(&self.verify_url)
+            .timeout(VERIFY_TIMEOUT)
+            .form(params)
+            .send()
+            .await?;
+        response.json::<SiteVerifyResponse>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Nothing listens here, so the siteverify call always fails at the
+    // network layer.
+    const UNREACHABLE: &str = "http://127.0.0.1:9/siteverify";
+
+    #[tokio::test]
+    async fn network_error_fails_closed_by_default() {
+        let service = RecaptchaService::new(Some("secret".to_string()), false)
+            .with_verify_url(UNREACHABLE);
+        let result = service.verify_token(Some("token"), None).await;
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn network_error_passes_when_fail_open() {
+        let service = RecaptchaService::new(Some("secret".to_string()), true)
+            .with_verify_url(UNREACHABLE);
+        assert!(service.verify_token(Some("token"), None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn missing_token_rejected_even_when_fail_open() {
+        let service = RecaptchaService::new(Some("secret".to_string()), true)
+            .with_verify_url(UNREACHABLE);
+        assert!(service.verify_token(None, None).await.is_err());
+    }
+
+    /// Minimal siteverify stand-in: hangs up on the first `failures`
+    /// connections, then answers every later one with `success: true`.
+    async fn flaky_siteverify(failures: usize) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for served in 0.. {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    break;
+                };
+                if served < failures {
+                    drop(socket); // simulate a transient network failure
+                    continue;
+                }
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let body = r#"{"success": true}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK
+content-type: application/json
+content-length: {}
+connection: close
+
+{body}",
+                    body.len()
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{addr}/siteverify")
+    }
+
+    #[tokio::test]
+    async fn one_transient_failure_is_retried_to_success() {
+        let url = flaky_siteverify(1).await;
+        let service =
+            RecaptchaService::new(Some("secret".to_string()), false).with_verify_url(&url);
+        assert!(service.verify_token(Some("token"), None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn persistent_failures_exhaust_the_retries() {
+        // More failures than attempts, so even the last retry hits a hangup.
+        let url = flaky_siteverify(10).await;
+        let service =
+            RecaptchaService::new(Some("secret".to_string()), false).with_verify_url(&url);
+        let result = service.verify_token(Some("token"), None).await;
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+}
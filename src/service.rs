@@ -0,0 +1,4063 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use once_cell::sync::Lazy;
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::Command,
+    sync::Semaphore,
+    time::timeout,
+};
+
+use crate::{
+    config::AppConfig,
+    error::{AppError, YtDlpDiagnostics},
+    models::{
+        AudioTrackOption, DownloadOrder, FallbackApiResponse, FallbackVideoData, FormatOption,
+        ProfileInfo, ProfileVideoInfo,
+        SelectedVideoResult, ThumbnailOption, VideoInfo, YtDlpFormat, YtDlpPlaylistEntry,
+        YtDlpThumbnail, YtDlpVideoInfo, ZipManifestEntry, ZipNaming,
+    },
+    stream::VideoStream,
+    url_validator::{extract_username, normalize_tiktok_url},
+};
+
+/// How long cached video metadata stays fresh: five minutes. Clients that
+/// need newer counts or formats sooner can pass refresh=true (or send
+/// Cache-Control: no-cache) to force a re-extraction.
+const METADATA_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Process-wide metadata cache keyed by the URL passed to yt-dlp.
+static METADATA_CACHE: Lazy<Mutex<HashMap<String, (Instant, VideoInfo)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Circuit breaker tripped when TikTok rate-limits us: until this instant,
+/// new yt-dlp spawns are refused so the limit can reset instead of every
+/// request digging the hole deeper.
+static TIKTOK_BREAKER: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// What a media URL resolves to: one video's full metadata, or — for URLs
+/// yt-dlp expands into a playlist — the entries it contains.
+pub enum MediaInfo {
+    Single(Box<VideoInfo>),
+    Playlist(Vec<ProfileVideoInfo>),
+}
+
+/// Where in-flight downloads are staged: a random auto-cleaned directory
+/// by default, or an operator-chosen fixed path (SCRATCH_DIR) for
+/// deployments that stage downloads on a dedicated fast disk or tmpfs.
+/// Fixed scratch space survives restarts and is never deleted by us.
+enum ScratchSpace {
+    Ephemeral(TempDir),
+    Fixed(PathBuf),
+}
+
+impl ScratchSpace {
+    fn path(&self) -> &Path {
+        match self {
+            ScratchSpace::Ephemeral(dir) => dir.path(),
+            ScratchSpace::Fixed(path) => path,
+        }
+    }
+}
+
+/// The one reqwest client for the process, carrying the configured
+/// connect/total timeouts so a stalled remote can't pin a request
+/// forever. `init_http_client` must run before the first request; the
+/// fallback here only exists so tests and tools get sane defaults.
+static HTTP_CLIENT: once_cell::sync::OnceCell<reqwest::Client> = once_cell::sync::OnceCell::new();
+
+/// Install the process-wide HTTP client from config. Later calls (tests
+/// spinning up several configs) are ignored; the first one wins.
+pub fn init_http_client(config: &AppConfig) {
+    let _ = HTTP_CLIENT.set(build_http_client(
+        config.http_connect_timeout_secs,
+        config.http_timeout_secs,
+    ));
+}
+
+/// The shared outbound HTTP client; every call site should use this
+/// rather than `reqwest::Client::new()` so the configured timeouts apply.
+pub fn shared_http_client() -> &'static reqwest::Client {
+    HTTP_CLIENT.get_or_init(|| build_http_client(10, 30))
+}
+
+fn build_http_client(connect_timeout_secs: u64, timeout_secs: u64) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder();
+    if connect_timeout_secs > 0 {
+        builder = builder.connect_timeout(Duration::from_secs(connect_timeout_secs));
+    }
+    if timeout_secs > 0 {
+        builder = builder.timeout(Duration::from_secs(timeout_secs));
+    }
+    builder.build().expect("failed to build HTTP client")
+}
+
+/// Recently built profile archives, so an identical repeat request
+/// within the TTL reuses the file on disk instead of re-downloading the
+/// whole profile. Bounded: evicting an entry deletes its backing ZIPs.
+static ZIP_CACHE: Lazy<Mutex<HashMap<String, ZipCacheEntry>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+const ZIP_CACHE_CAPACITY: usize = 8;
+
+struct ZipCacheEntry {
+    parts: Vec<(PathBuf, u64)>,
+    built_at: Instant,
+    /// Refreshed on every hit; capacity eviction drops the least recently
+    /// used entry.
+    last_used: Instant,
+}
+
+/// Look up a cached archive. Entries past the TTL (or whose files were
+/// removed out from under us) are dropped, deleting any leftover parts.
+fn cached_profile_zip(
+    cache: &Mutex<HashMap<String, ZipCacheEntry>>,
+    key: &str,
+    ttl: Duration,
+) -> Option<Vec<(PathBuf, u64)>> {
+    let mut cache = cache.lock().unwrap();
+    let entry = cache.get_mut(key)?;
+    let complete = entry.parts.iter().all(|(path, _)| path.exists());
+    if entry.built_at.elapsed() > ttl || !complete {
+        let entry = cache.remove(key).expect("entry was just here");
+        for (path, _) in &entry.parts {
+            let _ = std::fs::remove_file(path);
+        }
+        return None;
+    }
+    entry.last_used = Instant::now();
+    Some(entry.parts.clone())
+}
+
+/// Remember a freshly built archive, evicting (and deleting) the least
+/// recently used entry once the cache is full.
+fn remember_profile_zip(
+    cache: &Mutex<HashMap<String, ZipCacheEntry>>,
+    key: String,
+    parts: &[(PathBuf, u64)],
+    capacity: usize,
+) {
+    let mut cache = cache.lock().unwrap();
+    while cache.len() >= capacity {
+        let Some(oldest) = cache
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        let entry = cache.remove(&oldest).expect("entry was just here");
+        for (path, _) in &entry.parts {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    let now = Instant::now();
+    cache.insert(
+        key,
+        ZipCacheEntry {
+            parts: parts.to_vec(),
+            built_at: now,
+            last_used: now,
+        },
+    );
+}
+
+/// A profile's enumerated videos, plus whether the enumeration was cut
+/// short by the soft time limit (see `run_ytdlp_lines`).
+pub struct ProfileListing {
+    pub videos: Vec<ProfileVideoInfo>,
+    pub truncated: bool,
+}
+
+/// Per-URL locks for in-flight metadata extractions; see `single_flight`.
+static INFLIGHT_INFO: Lazy<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Run `work` while holding a lock private to `key`, so concurrent calls
+/// for the same key execute one at a time. `work` is expected to start
+/// with a cache check: the first caller does the expensive part and
+/// populates the cache, everyone queued behind it hits the fresh entry.
+async fn single_flight<T, Fut>(key: &str, work: impl FnOnce() -> Fut) -> T
+where
+    Fut: std::future::Future<Output = T>,
+{
+    let slot = INFLIGHT_INFO
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_default()
+        .clone();
+    let result = {
+        let _guard = slot.lock().await;
+        work().await
+    };
+    // Drop the map entry once no other caller holds the slot, so the map
+    // doesn't accumulate one lock per URL ever seen.
+    let mut inflight = INFLIGHT_INFO.lock().unwrap();
+    if let Some(existing) = inflight.get(key) {
+        if Arc::strong_count(existing) == 2 {
+            inflight.remove(key);
+        }
+    }
+    drop(inflight);
+    result
+}
+
+/// Seconds left on the breaker, or None when it is closed.
+fn breaker_remaining_secs() -> Option<u64> {
+    let mut breaker = TIKTOK_BREAKER.lock().unwrap();
+    match *breaker {
+        Some(until) if until > Instant::now() => {
+            Some((until - Instant::now()).as_secs().max(1))
+        }
+        Some(_) => {
+            *breaker = None;
+            None
+        }
+        None => None,
+    }
+}
+
+fn trip_tiktok_breaker(cooldown: Duration) {
+    if cooldown.is_zero() {
+        return;
+    }
+    *TIKTOK_BREAKER.lock().unwrap() = Some(Instant::now() + cooldown);
+}
+
+/// Cache read for video metadata; `refresh` forces a miss so the caller
+/// re-extracts even inside the TTL window.
+fn lookup_cached_info(url: &str, refresh: bool) -> Option<VideoInfo> {
+    if refresh {
+        return None;
+    }
+    let cache = METADATA_CACHE.lock().unwrap();
+    let (stored_at, info) = cache.get(url)?;
+    (stored_at.elapsed() < METADATA_CACHE_TTL).then(|| info.clone())
+}
+
+/// Whether yt-dlp stderr indicates TikTok itself is rate-limiting us, as
+/// opposed to a problem with the requested video.
+fn is_tiktok_rate_limit(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    stderr.contains("429")
+        || stderr.contains("too many requests")
+        || stderr.contains("rate limit")
+        || stderr.contains("rate-limit")
+}
+
+/// One rendition of a bundle download: a video capped at a height (None
+/// means best) or an extracted audio track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BundleOutput {
+    Video(Option<u32>),
+    Audio(String),
+}
+
+/// Per-username download gates enforcing PER_PROFILE_CONCURRENCY. Entries
+/// are a few bytes each and usernames are few, so the map is never pruned.
+static PROFILE_SEMAPHORES: Lazy<Mutex<HashMap<String, Arc<Semaphore>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The semaphore limiting concurrent downloads from one profile. The limit
+/// is fixed when a username is first seen; config changes need a restart,
+/// which is already true of every other knob.
+fn profile_semaphore(username: &str, limit: usize) -> Arc<Semaphore> {
+    PROFILE_SEMAPHORES
+        .lock()
+        .unwrap()
+        .entry(username.to_lowercase())
+        .or_insert_with(|| Arc::new(Semaphore::new(limit.max(1))))
+        .clone()
+}
+
+/// A caller-supplied cookie jar written to a private temp file for the
+/// duration of a single yt-dlp invocation. The file is created with 0600
+/// permissions and removed on drop, and its contents are never logged.
+pub struct CookieFile {
+    path: PathBuf,
+}
+
+impl CookieFile {
+    pub fn write(contents: &str) -> Result<Self, AppError> {
+        let path = std::env::temp_dir().join(format!("tiktok-cookies-{}.txt", uuid::Uuid::new_v4()));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&path)
+            .map_err(|e| AppError::internal(format!("failed to create cookie file: {e}")))?;
+        std::io::Write::write_all(&mut file, contents.as_bytes())
+            .map_err(|e| AppError::internal(format!("failed to write cookie file: {e}")))?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CookieFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Pass a per-request cookie jar to yt-dlp when one was supplied.
+fn apply_cookies(cmd: &mut Command, cookies: Option<&CookieFile>) {
+    if let Some(cookies) = cookies {
+        cmd.arg("--cookies").arg(cookies.path());
+    }
+}
+
+/// Everything that shells out to yt-dlp lives here, keeping the handlers
+/// free of process-management details.
+pub struct TikTokService {
+    config: AppConfig,
+    scratch: ScratchSpace,
+    /// Caps how many ZIP archives are assembled at once; each build pins a
+    /// blocking-pool thread and hammers the disk, so an unbounded pile-up
+    /// of profile jobs would starve everything else doing file I/O.
+    zip_semaphore: Arc<Semaphore>,
+}
+
+impl TikTokService {
+    pub fn new(config: &AppConfig) -> Result<Self, AppError> {
+        std::fs::create_dir_all(&config.downloads_dir)?;
+        std::fs::create_dir_all(&config.temp_dir)?;
+        // Fail fast on unwritable directories; mid-download write errors are
+        // far harder to diagnose than a startup refusal.
+        verify_dir_writable(Path::new(&config.downloads_dir))?;
+        verify_dir_writable(Path::new(&config.temp_dir))?;
+        let scratch = match &config.scratch_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                verify_dir_writable(Path::new(dir))?;
+                ScratchSpace::Fixed(PathBuf::from(dir))
+            }
+            None => ScratchSpace::Ephemeral(
+                TempDir::new_in(&config.temp_dir)
+                    .map_err(|e| AppError::internal(format!("failed to create temp dir: {e}")))?,
+            ),
+        };
+        Ok(Self {
+            config: config.clone(),
+            scratch,
+            zip_semaphore: Arc::new(Semaphore::new(config.max_concurrent_zip_builds.max(1))),
+        })
+    }
+
+    /// Refuse a bulk download that would likely fill the disk. The estimate
+    /// is `count` videos at [`ESTIMATED_VIDEO_BYTES`] each; unknown free
+    /// space never blocks.
+    fn preflight_disk_space(&self, count: usize) -> Result<(), AppError> {
+        ensure_disk_space(
+            count as u64 * ESTIMATED_VIDEO_BYTES,
+            fs2::available_space(self.scratch.path()).ok(),
+        )
+    }
+
+    pub fn temp_dir_path(&self) -> &Path {
+        self.scratch.path()
+    }
+
+    pub fn config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    /// Base yt-dlp invocation shared by every command we build.
+    fn base_command(&self) -> Command {
+        let mut cmd = Command::new("yt-dlp");
+        cmd.arg("--no-warnings");
+        match self.config.ytdlp_cache_dir.as_deref() {
+            None => {}
+            Some("none") => {
+                cmd.arg("--no-cache-dir");
+            }
+            Some(dir) => {
+                cmd.arg("--cache-dir").arg(dir);
+            }
+        }
+        if self.config.bypass_age_gate {
+            // The app API variants skip the web player's consent
+            // interstitial, clearing "age restricted"/"login required"
+            // failures on publicly visible videos.
+            cmd.arg("--extractor-args")
+                .arg(AGE_GATE_EXTRACTOR_ARGS);
+        }
+        if let Some(path) = &self.config.cookies_file {
+            cmd.arg("--cookies").arg(path);
+        }
+        if let Some(browser) = &self.config.cookies_from_browser {
+            cmd.arg("--cookies-from-browser").arg(browser);
+        }
+        cmd.kill_on_drop(true);
+        cmd
+    }
+
+    /// The configured size cap for streamed downloads; 0 means unlimited.
+    fn stream_byte_limit(&self) -> Option<u64> {
+        (self.config.max_file_size > 0).then_some(self.config.max_file_size)
+    }
+
+    /// Apply the configured --limit-rate to a command that downloads media.
+    fn apply_rate_limit(&self, cmd: &mut Command) {
+        if let Some(rate) = &self.config.download_rate_limit {
+            cmd.arg("--limit-rate").arg(rate);
+        }
+    }
+
+    /// Run a prepared command to completion, enforcing the configured timeout
+    /// and classifying stderr on failure.
+    async fn run_ytdlp(&self, mut cmd: Command) -> Result<String, AppError> {
+        if let Some(secs) = breaker_remaining_secs() {
+            return Err(AppError::ServiceUnavailable(format!(
+                "Cooling down after a TikTok rate limit; retry in {secs}s"
+            )));
+        }
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let output = timeout(Duration::from_secs(self.config.ytdlp_timeout), cmd.output())
+            .await
+            .map_err(|_| {
+                AppError::internal("yt-dlp timed out; the video may be too large".to_string())
+            })?
+            .map_err(|e| AppError::internal(format!("failed to run yt-dlp: {e}")))?;
+
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if is_tiktok_rate_limit(&stderr) {
+                trip_tiktok_breaker(Duration::from_secs(self.config.tiktok_cooldown_secs));
+                return Err(AppError::TooManyRequests {
+                    message: "TikTok is rate limiting this server; please retry later"
+                        .to_string(),
+                    retry_after: self.config.tiktok_cooldown_secs.max(1),
+                });
+            }
+            Err(classify_ytdlp_error(&stderr, output.status.code()))
+        }
+    }
+
+    /// Incremental variant of `run_ytdlp` for commands that emit one JSON
+    /// object per line. Lines are collected as they arrive; when
+    /// `soft_limit` passes before yt-dlp finishes, the child is killed and
+    /// the partial list comes back flagged `true` instead of an error —
+    /// for huge profiles a partial answer beats none.
+    async fn run_ytdlp_lines(
+        &self,
+        mut cmd: Command,
+        soft_limit: Duration,
+    ) -> Result<(Vec<String>, bool), AppError> {
+        if let Some(secs) = breaker_remaining_secs() {
+            return Err(AppError::ServiceUnavailable(format!(
+                "Cooling down after a TikTok rate limit; retry in {secs}s"
+            )));
+        }
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| AppError::internal(format!("failed to run yt-dlp: {e}")))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| AppError::internal("yt-dlp has no stdout pipe".to_string()))?;
+        // Drained concurrently so a chatty stderr can't wedge the child
+        // against a full pipe buffer.
+        let stderr_pipe = child.stderr.take();
+        let stderr_task = tokio::spawn(async move {
+            let mut buf = String::new();
+            if let Some(mut pipe) = stderr_pipe {
+                let _ = pipe.read_to_string(&mut buf).await;
+            }
+            buf
+        });
+
+        let mut lines = BufReader::new(stdout).lines();
+        let deadline = Instant::now() + soft_limit;
+        let mut collected = Vec::new();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match timeout(remaining, lines.next_line()).await {
+                Err(_) => {
+                    let _ = child.start_kill();
+                    return Ok((collected, true));
+                }
+                Ok(Ok(Some(line))) => collected.push(line),
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => {
+                    let _ = child.start_kill();
+                    return Err(AppError::internal(format!(
+                        "failed to read yt-dlp output: {e}"
+                    )));
+                }
+            }
+        }
+
+        let status = child
+            .wait()
+            .await
+            .map_err(|e| AppError::internal(format!("failed to run yt-dlp: {e}")))?;
+        if !status.success() && collected.is_empty() {
+            let stderr = stderr_task.await.unwrap_or_default();
+            if is_tiktok_rate_limit(&stderr) {
+                trip_tiktok_breaker(Duration::from_secs(self.config.tiktok_cooldown_secs));
+                return Err(AppError::TooManyRequests {
+                    message: "TikTok is rate limiting this server; please retry later"
+                        .to_string(),
+                    retry_after: self.config.tiktok_cooldown_secs.max(1),
+                });
+            }
+            return Err(classify_ytdlp_error(&stderr, status.code()));
+        }
+        Ok((collected, false))
+    }
+
+    /// The enumeration soft limit, never exceeding the hard yt-dlp timeout.
+    fn profile_list_soft_limit(&self) -> Duration {
+        let hard = self.config.ytdlp_timeout;
+        let soft = match self.config.profile_list_soft_limit_secs {
+            0 => hard,
+            secs => secs.min(hard),
+        };
+        Duration::from_secs(soft)
+    }
+
+    /// Run `yt-dlp --list-formats` and return stdout, stderr and the exit
+    /// code verbatim, for the admin debugging endpoint. A non-zero exit is
+    /// part of the answer here, so nothing is classified into an error; note
+    /// the deliberate absence of --no-warnings, since warnings are often the
+    /// clue an operator is looking for.
+    pub async fn list_formats_raw(
+        &self,
+        url: &str,
+    ) -> Result<(String, String, Option<i32>), AppError> {
+        let mut cmd = Command::new("yt-dlp");
+        cmd.kill_on_drop(true);
+        cmd.args(["--list-formats", "--no-playlist"])
+            .arg(normalize_tiktok_url(url))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = timeout(Duration::from_secs(self.config.ytdlp_timeout), cmd.output())
+            .await
+            .map_err(|_| AppError::internal("yt-dlp timed out listing formats".to_string()))?
+            .map_err(|e| AppError::internal(format!("failed to run yt-dlp: {e}")))?;
+        Ok((
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+            output.status.code(),
+        ))
+    }
+
+    /// Report the installed yt-dlp version, or an error when it isn't on PATH.
+    pub async fn check_ytdlp_availability(&self) -> Result<String, AppError> {
+        let mut cmd = self.base_command();
+        cmd.arg("--version");
+        let out = self.run_ytdlp(cmd).await.map_err(|_| {
+            AppError::ServiceUnavailable("yt-dlp is not installed or not on PATH".to_string())
+        })?;
+        Ok(out.trim().to_string())
+    }
+
+    /// Fetch (or serve from cache) the full metadata for a single video.
+    pub async fn get_video_info(&self, url: &str) -> Result<VideoInfo, AppError> {
+        self.get_video_info_refreshed(url, false).await
+    }
+
+    /// Like [`get_video_info`](Self::get_video_info), but `refresh` skips the
+    /// cache read and re-runs yt-dlp; the fresh result still replaces the
+    /// cache entry for later callers.
+    pub async fn get_video_info_refreshed(
+        &self,
+        url: &str,
+        refresh: bool,
+    ) -> Result<VideoInfo, AppError> {
+        self.get_video_info_with_cookies(url, refresh, None).await
+    }
+
+    /// Full metadata fetch with an optional per-request cookie jar. Cookie
+    /// requests bypass the shared cache entirely — both ways — so private
+    /// video metadata never leaks to other callers. URLs that turn out to
+    /// be playlists are an error here; single-video callers (streaming,
+    /// trimming...) have no sensible way to pick an entry.
+    pub async fn get_video_info_with_cookies(
+        &self,
+        url: &str,
+        refresh: bool,
+        cookies: Option<&CookieFile>,
+    ) -> Result<VideoInfo, AppError> {
+        match self.get_media_info_with_cookies(url, refresh, cookies).await? {
+            MediaInfo::Single(info) => Ok(*info),
+            MediaInfo::Playlist(_) => Err(AppError::BadRequest(
+                "This URL expands to multiple videos; use /api/video/info to list them and pick one"
+                    .to_string(),
+            )),
+        }
+    }
+
+    /// Like [`get_video_info_with_cookies`](Self::get_video_info_with_cookies),
+    /// but URLs that yt-dlp expands into multiple entries (`_type:
+    /// "playlist"` — sound pages, collections) come back as a listing
+    /// instead of a parse error.
+    pub async fn get_media_info_with_cookies(
+        &self,
+        url: &str,
+        refresh: bool,
+        cookies: Option<&CookieFile>,
+    ) -> Result<MediaInfo, AppError> {
+        let url = normalize_tiktok_url(url);
+        // Cookie-backed requests stay un-coalesced and uncached: private
+        // metadata must not be shared between callers.
+        if cookies.is_some() {
+            return self.fetch_media_info(&url, cookies).await;
+        }
+        if let Some(info) = lookup_cached_info(&url, refresh) {
+            return Ok(MediaInfo::Single(Box::new(info)));
+        }
+        // Ten users pasting the same trending URL within a second should
+        // cost one yt-dlp run, not ten: concurrent callers for the same
+        // normalized URL queue behind a per-URL lock, and everyone after
+        // the first finds the leader's result in the cache.
+        single_flight(&url, || async {
+            if !refresh {
+                if let Some(info) = lookup_cached_info(&url, false) {
+                    return Ok(MediaInfo::Single(Box::new(info)));
+                }
+            }
+            let media = self.fetch_media_info(&url, None).await?;
+            if let MediaInfo::Single(info) = &media {
+                METADATA_CACHE
+                    .lock()
+                    .unwrap()
+                    .insert(url.clone(), (Instant::now(), (**info).clone()));
+            }
+            Ok(media)
+        })
+        .await
+    }
+
+    /// The raw extraction: one yt-dlp run plus playlist/fallback handling.
+    /// Expects an already-normalized URL; caching and coalescing live in
+    /// [`get_media_info_with_cookies`](Self::get_media_info_with_cookies).
+    async fn fetch_media_info(
+        &self,
+        url: &str,
+        cookies: Option<&CookieFile>,
+    ) -> Result<MediaInfo, AppError> {
+        let mut cmd = self.base_command();
+        cmd.args(["-j", "--no-playlist"]).arg(url);
+        apply_cookies(&mut cmd, cookies);
+        let stdout = match self.run_ytdlp(cmd).await {
+            Ok(stdout) => stdout,
+            // Cookie-backed requests stay on yt-dlp only: the fallback API
+            // can't use the jar, so it would just fail differently.
+            Err(primary) if cookies.is_none() => {
+                let info = self.fallback_video_info(url, primary).await?;
+                return Ok(MediaInfo::Single(Box::new(info)));
+            }
+            Err(primary) => return Err(primary),
+        };
+        let raw = extract_video_metadata(&stdout)?;
+        if raw.is_playlist() {
+            return Ok(MediaInfo::Playlist(
+                raw.entries.into_iter().map(playlist_entry_to_video).collect(),
+            ));
+        }
+        Ok(MediaInfo::Single(Box::new(
+            self.convert_ytdlp_to_video_info(raw),
+        )))
+    }
+
+    /// When yt-dlp failed with an extraction error (not a rate limit, not
+    /// a private or removed video — those would fail identically anywhere)
+    /// and a fallback API is configured, try it; any other outcome returns
+    /// the original yt-dlp error.
+    async fn fallback_video_info(&self, url: &str, primary: AppError) -> Result<VideoInfo, AppError> {
+        let Some(base) = &self.config.fallback_api_url else {
+            return Err(primary);
+        };
+        if !is_extraction_error(&primary) {
+            return Err(primary);
+        }
+        tracing::warn!(url, "yt-dlp extraction failed; trying the fallback API");
+        match fetch_fallback_info(base, url).await {
+            Ok(info) => Ok(info),
+            Err(fallback_err) => {
+                tracing::warn!(error = %fallback_err, "fallback API failed too");
+                Err(primary)
+            }
+        }
+    }
+
+    /// The full-resolution image URLs of a photo post's carousel, in slide
+    /// order. yt-dlp exposes each slide as a playlist entry whose largest
+    /// "thumbnail" is the original image; a single-image post comes back as
+    /// a carousel of one. `-J` rather than `-j` so multi-slide posts arrive
+    /// as one playlist object instead of per-entry lines.
+    pub async fn get_photo_images(&self, url: &str) -> Result<Vec<String>, AppError> {
+        let normalized = normalize_tiktok_url(url);
+        let mut cmd = self.base_command();
+        cmd.arg("-J").arg(&normalized);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let raw = extract_video_metadata(&stdout)?;
+        let images = photo_image_urls(&raw);
+        if images.is_empty() {
+            return Err(AppError::NotFound(
+                "This post has no images; is it really a photo post?".to_string(),
+            ));
+        }
+        Ok(images)
+    }
+
+    /// Resolve the direct CDN URL for one of a video's formats, along with
+    /// the headers the CDN requires. Errors when the format has no single
+    /// direct URL (e.g. merged video+audio selections).
+    pub async fn get_direct_url(
+        &self,
+        url: &str,
+        format_id: &str,
+    ) -> Result<crate::models::DirectUrlResponse, AppError> {
+        let normalized = normalize_tiktok_url(url);
+        let mut cmd = self.base_command();
+        cmd.args(["-j", "--no-playlist"]).arg(&normalized);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let raw = extract_video_metadata(&stdout)?;
+
+        let format = raw
+            .formats
+            .iter()
+            .find(|f| f.format_id == format_id)
+            .ok_or_else(|| {
+                AppError::BadRequest(format!("Unknown format_id '{format_id}' for this video"))
+            })?;
+        let direct_url = format.url.clone().ok_or_else(|| {
+            AppError::BadRequest(
+                "This format has no direct URL (it requires server-side muxing); use the streaming endpoint instead"
+                    .to_string(),
+            )
+        })?;
+
+        let mut headers = format.http_headers.clone();
+        headers
+            .entry("Referer".to_string())
+            .or_insert_with(|| "https://www.tiktok.com/".to_string());
+
+        Ok(crate::models::DirectUrlResponse {
+            format_id: format_id.to_string(),
+            direct_url,
+            headers,
+            warning: "Direct CDN URLs are signed and expire after a short time; fetch promptly"
+                .to_string(),
+        })
+    }
+
+    /// The best adaptive (HLS or DASH) manifest TikTok offers for a video,
+    /// for players that want in-browser adaptive playback instead of a
+    /// full download. 404s when only progressive formats exist.
+    pub async fn get_playback_manifest(
+        &self,
+        url: &str,
+    ) -> Result<crate::models::ManifestResponse, AppError> {
+        let normalized = normalize_tiktok_url(url);
+        let mut cmd = self.base_command();
+        cmd.args(["-j", "--no-playlist"]).arg(&normalized);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let raw = extract_video_metadata(&stdout)?;
+
+        let (format, kind) = select_manifest_format(&raw.formats).ok_or_else(|| {
+            AppError::NotFound(
+                "This video has no HLS or DASH manifest; only progressive downloads are available"
+                    .to_string(),
+            )
+        })?;
+        let manifest_url = format
+            .manifest_url
+            .clone()
+            .or_else(|| format.url.clone())
+            .ok_or_else(|| AppError::internal("manifest format carries no URL".to_string()))?;
+
+        let mut headers = format.http_headers.clone();
+        headers
+            .entry("Referer".to_string())
+            .or_insert_with(|| "https://www.tiktok.com/".to_string());
+
+        Ok(crate::models::ManifestResponse {
+            kind: kind.to_string(),
+            manifest_url,
+            headers,
+        })
+    }
+
+    /// Estimated size in bytes of the best listed format, when yt-dlp knows it.
+    pub async fn estimate_video_size(&self, url: &str) -> Result<Option<u64>, AppError> {
+        let info = self.get_video_info(url).await?;
+        Ok(info.formats.iter().find_map(|f| f.filesize))
+    }
+
+    fn convert_ytdlp_to_video_info(&self, raw: YtDlpVideoInfo) -> VideoInfo {
+        let description = raw.description.clone().unwrap_or_default();
+        let (hashtags, mentions) = extract_tags(&description);
+        VideoInfo {
+            id: raw.id.clone(),
+            title: raw.title.clone().unwrap_or_else(|| "Untitled".to_string()),
+            description,
+            uploader: raw.uploader.clone().unwrap_or_default(),
+            duration: raw.duration,
+            view_count: raw.view_count,
+            like_count: raw.like_count,
+            comment_count: raw.comment_count,
+            upload_date: raw.upload_date.clone(),
+            thumbnail_url: extract_best_thumbnail_url(&raw.thumbnails),
+            thumbnails: parse_thumbnails(&raw.thumbnails),
+            formats: parse_available_formats(
+                &raw.formats,
+                self.config.max_formats,
+                &self.config.preferred_codecs,
+            ),
+            subtitle_languages: {
+                let mut langs: Vec<String> = raw.subtitles.keys().cloned().collect();
+                langs.sort();
+                langs
+            },
+            hashtags,
+            mentions,
+            effects: raw.effect_ids.clone(),
+            audio_tracks: parse_audio_tracks(&raw.formats),
+        }
+    }
+
+    /// Enumerate a profile's videos. The fast flat-playlist listing is tried
+    /// first; both a hard failure and an empty result fall through to the
+    /// alternative listing, and only when both attempts come up empty-handed
+    /// do we error — with a message describing what each attempt did, so the
+    /// user isn't told "0 videos" when yt-dlp actually failed.
+    pub async fn get_profile_video_list(
+        &self,
+        profile_url: &str,
+    ) -> Result<ProfileListing, AppError> {
+        let url = normalize_tiktok_url(profile_url);
+
+        let mut cmd = self.base_command();
+        cmd.args(["--flat-playlist", "-j"])
+            .arg("--playlist-end")
+            .arg(self.config.max_profile_videos.to_string())
+            .arg(&url);
+        let primary = self
+            .run_ytdlp_lines(cmd, self.profile_list_soft_limit())
+            .await
+            .map(|(lines, truncated)| (parse_playlist_lines(&lines.join("\n")), truncated));
+        let primary_err = match primary {
+            Ok((videos, truncated)) if !videos.is_empty() => {
+                if truncated {
+                    tracing::warn!(
+                        collected = videos.len(),
+                        "profile listing hit the soft time limit; returning a partial list"
+                    );
+                }
+                return Ok(ProfileListing { videos, truncated });
+            }
+            Ok(_) => {
+                tracing::info!("flat-playlist listing empty, trying alternative");
+                None
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "flat-playlist listing failed, trying alternative");
+                Some(e)
+            }
+        };
+        self.jitter_between_requests().await;
+
+        let alternative = self.get_profile_video_list_alternative(&url).await;
+        match (primary_err, alternative) {
+            (_, Ok(videos)) if !videos.is_empty() => Ok(ProfileListing {
+                videos,
+                truncated: false,
+            }),
+            // Both listings genuinely found nothing: an empty profile.
+            (None, Ok(videos)) => Ok(ProfileListing {
+                videos,
+                truncated: false,
+            }),
+            (Some(primary_err), Ok(_)) => Err(AppError::internal(format!(
+                "Could not list profile videos: primary listing failed ({primary_err}); alternative listing returned no videos"
+            ))),
+            (None, Err(alt_err)) => Err(AppError::internal(format!(
+                "Could not list profile videos: primary listing returned no videos; alternative listing failed ({alt_err})"
+            ))),
+            (Some(primary_err), Err(alt_err)) => Err(AppError::internal(format!(
+                "Could not list profile videos: primary listing failed ({primary_err}); alternative listing failed ({alt_err})"
+            ))),
+        }
+    }
+
+    /// Slower fallback that does a full (non-flat) extraction per entry.
+    async fn get_profile_video_list_alternative(
+        &self,
+        profile_url: &str,
+    ) -> Result<Vec<ProfileVideoInfo>, AppError> {
+        let mut cmd = self.base_command();
+        cmd.arg("-j")
+            .arg("--playlist-end")
+            .arg(self.config.max_profile_videos.to_string())
+            .arg(profile_url);
+        let stdout = self.run_ytdlp(cmd).await?;
+        Ok(parse_playlist_lines(&stdout))
+    }
+
+    /// Enforce the operator's profile allow/deny lists.
+    fn check_profile_allowed(&self, username: &str) -> Result<(), AppError> {
+        if self.config.profile_access_allowed(username) {
+            Ok(())
+        } else {
+            Err(AppError::Unauthorized(format!(
+                "Downloads for profile @{username} are not permitted on this server"
+            )))
+        }
+    }
+
+    pub async fn get_profile_info(&self, profile_url: &str) -> Result<ProfileInfo, AppError> {
+        let username = extract_username(profile_url)
+            .ok_or_else(|| AppError::BadRequest("Invalid TikTok profile URL".to_string()))?;
+        self.check_profile_allowed(&username)?;
+        let listing = self.get_profile_video_list(profile_url).await?;
+        Ok(ProfileInfo {
+            username,
+            video_count: listing.videos.len(),
+            videos: listing.videos,
+            truncated: listing.truncated,
+        })
+    }
+
+    /// Download one video into `dir`, returning the path yt-dlp wrote.
+    pub async fn download_video_file(
+        &self,
+        url: &str,
+        format: &str,
+        dir: &Path,
+        write_info_json: bool,
+    ) -> Result<PathBuf, AppError> {
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(format)
+            .arg("-o")
+            .arg(dir.join("%(uploader)s_%(title)s_%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .args(["--print", "after_move:filepath", "--no-simulate"])
+            .arg(normalize_tiktok_url(url));
+        if write_info_json {
+            cmd.arg("--write-info-json");
+        }
+        self.apply_rate_limit(&mut cmd);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let path = PathBuf::from(stdout.trim());
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(AppError::internal(
+                "yt-dlp reported success but the output file is missing".to_string(),
+            ))
+        }
+    }
+
+    /// Download every video of a profile and pack them into a ZIP in
+    /// `downloads_dir`. Returns the archive path and its size in bytes.
+    /// Build the yt-dlp command for a remuxed download; split out so the
+    /// flag wiring is testable without spawning anything.
+    fn remux_command(&self, url: &str, format: &str, container: &str, session_dir: &Path) -> Command {
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(format)
+            .args(["--remux-video", container])
+            .arg("-o")
+            .arg(session_dir.join("%(uploader)s_%(title)s_%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .args(["--print", "after_move:filepath", "--no-simulate"])
+            .arg(normalize_tiktok_url(url));
+        self.apply_rate_limit(&mut cmd);
+        cmd
+    }
+
+    /// Download a video remuxed into the requested container (requires
+    /// ffmpeg). Remuxing copies the streams, so it is cheap compared to a
+    /// re-encode, but it needs a finished file — file-then-stream only.
+    pub async fn download_remuxed_video(
+        &self,
+        url: &str,
+        format: &str,
+        container: &str,
+    ) -> Result<PathBuf, AppError> {
+        let session_dir = self.new_session_dir()?;
+        let stdout = self
+            .run_ytdlp(self.remux_command(url, format, container, &session_dir))
+            .await?;
+        let path = PathBuf::from(stdout.trim());
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(AppError::internal(
+                "yt-dlp reported success but the output file is missing".to_string(),
+            ))
+        }
+    }
+
+    /// Download a video with soft subtitles embedded (requires ffmpeg).
+    /// Videos without captions still download; yt-dlp just has nothing to
+    /// embed. Callers should check the metadata first if they want to tell
+    /// the user no captions existed.
+    pub async fn download_video_with_subs(
+        &self,
+        url: &str,
+        format: &str,
+        sub_langs: Option<&str>,
+    ) -> Result<PathBuf, AppError> {
+        let dir = self.new_session_dir()?;
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(format)
+            .arg("-o")
+            .arg(dir.join("%(uploader)s_%(title)s_%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .args(subtitle_args(sub_langs))
+            .args(["--print", "after_move:filepath", "--no-simulate"])
+            .arg(normalize_tiktok_url(url));
+        self.apply_rate_limit(&mut cmd);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let path = PathBuf::from(stdout.trim());
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(AppError::internal(
+                "yt-dlp reported success but the output file is missing".to_string(),
+            ))
+        }
+    }
+
+    /// Fetch a video's captions (auto-generated included) without the
+    /// video itself and reduce them to plain paragraph text. `lang`
+    /// narrows the request to one language code; otherwise the first
+    /// caption track yt-dlp finds wins.
+    pub async fn fetch_transcript(
+        &self,
+        url: &str,
+        lang: Option<&str>,
+    ) -> Result<String, AppError> {
+        let dir = self.new_session_dir()?;
+        let mut cmd = self.base_command();
+        cmd.arg("--skip-download")
+            .args(["--write-subs", "--write-auto-subs"])
+            .arg("--sub-langs")
+            .arg(lang.unwrap_or("all"))
+            .args(["--sub-format", "vtt/srt/best"])
+            .arg("-o")
+            .arg(dir.join("%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .arg(normalize_tiktok_url(url));
+        self.apply_rate_limit(&mut cmd);
+        let result = self.run_ytdlp(cmd).await;
+        let subtitle_file = collect_subtitle_files(&dir)?.into_iter().next();
+        let Some(path) = subtitle_file else {
+            let _ = std::fs::remove_dir_all(&dir);
+            // A failed extraction should surface as its own error, not as
+            // a misleading "no captions".
+            result?;
+            return Err(AppError::NotFound(match lang {
+                Some(lang) => {
+                    format!("No captions are available in '{lang}' for this video")
+                }
+                None => "No captions are available for this video".to_string(),
+            }));
+        };
+        let raw = std::fs::read_to_string(&path)
+            .map_err(|e| AppError::internal(format!("failed to read subtitle file: {e}")))?;
+        let _ = std::fs::remove_dir_all(&dir);
+        let text = crate::transcript::clean_subtitle_text(&raw);
+        if text.is_empty() {
+            return Err(AppError::NotFound(
+                "This video's captions contain no text".to_string(),
+            ));
+        }
+        Ok(text)
+    }
+
+    // One parameter per request field, matching how the handler reads the
+    // JSON body; a dedicated options struct would only restate the model.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn download_all_profile_videos(
+        &self,
+        profile_url: &str,
+        include_metadata: bool,
+        include_manifest: bool,
+        naming: ZipNaming,
+        order: DownloadOrder,
+        split_size_bytes: Option<u64>,
+        since_date: Option<&str>,
+        filename_template: Option<&str>,
+    ) -> Result<Vec<(PathBuf, u64)>, AppError> {
+        let username = extract_username(profile_url)
+            .ok_or_else(|| AppError::BadRequest("Invalid TikTok profile URL".to_string()))?;
+        self.check_profile_allowed(&username)?;
+        let cache_key = format!(
+            "{username}|{include_metadata}|{include_manifest}|{naming:?}|{order:?}|{split_size_bytes:?}|{since_date:?}|{filename_template:?}"
+        );
+        let cache_ttl = self.config.profile_zip_cache_ttl_secs;
+        if cache_ttl > 0 {
+            if let Some(parts) =
+                cached_profile_zip(&ZIP_CACHE, &cache_key, Duration::from_secs(cache_ttl))
+            {
+                tracing::info!(%username, "serving profile archive from the ZIP cache");
+                return Ok(parts);
+            }
+        }
+        self.preflight_disk_space(self.config.max_profile_videos)?;
+        // Listed up front so a broken listing fails the job before the
+        // expensive download, not after. Numbered naming needs the listing
+        // too: upload dates are what turn the sequence numbers into
+        // chronological order.
+        let listing = match include_manifest || naming == ZipNaming::Numbered {
+            true => Some(self.get_profile_video_list(profile_url).await?.videos),
+            false => None,
+        };
+        let session_dir = self.new_session_dir()?;
+
+        let output = match filename_template {
+            Some(template) => ytdlp_output_template(template)?,
+            None => PROFILE_OUTPUT_TEMPLATE.to_string(),
+        };
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(PROFILE_FORMAT_SELECTOR)
+            .arg("-o")
+            .arg(session_dir.join(output))
+            .arg("--restrict-filenames")
+            .arg("--playlist-end")
+            .arg(self.config.max_profile_videos.to_string())
+            .arg("--ignore-errors")
+            .arg(normalize_tiktok_url(profile_url));
+        apply_date_filter(&mut cmd, since_date);
+        if include_metadata {
+            cmd.arg("--write-info-json");
+        }
+        self.apply_rate_limit(&mut cmd);
+        // --ignore-errors makes yt-dlp exit non-zero when any entry failed,
+        // so only treat it as fatal when nothing was downloaded at all.
+        let result = self.run_ytdlp(cmd).await;
+        let mut files = collect_video_files(&session_dir)?;
+        if files.is_empty() {
+            return Err(result
+                .err()
+                .unwrap_or_else(|| AppError::internal("no videos were downloaded".to_string())));
+        }
+        for file in files.iter().filter(|f| !is_mp4(f)) {
+            tracing::info!(file = %file.display(), "video had no mp4 format; kept the fallback");
+        }
+        if let Some(videos) = &listing {
+            order_files_chronologically(&mut files, videos, order);
+        }
+        if include_metadata {
+            files.extend(collect_metadata_files(&session_dir)?);
+        }
+        if let Some(videos) = &listing {
+            files.extend(write_session_manifest(&session_dir, videos, &files)?);
+        }
+
+        let parts = match split_size_bytes {
+            Some(limit) => {
+                self.zip_session_split(&username, &files, naming, limit)
+                    .await?
+            }
+            None => vec![self.zip_session(&username, &files, naming).await?],
+        };
+        if cache_ttl > 0 {
+            remember_profile_zip(&ZIP_CACHE, cache_key, &parts, ZIP_CACHE_CAPACITY);
+        }
+        Ok(parts)
+    }
+
+    /// Download the selected URLs, appending each video to a streaming ZIP
+    /// over `writer` the moment its download finishes, so the client
+    /// receives the first entries while later videos are still in flight.
+    /// Failures are skipped, and every URL's outcome is recorded in a
+    /// `download_report.json` entry at the end of the archive — a user who
+    /// picked 20 videos and got 17 can see which three failed and why.
+    /// Returns how many videos made it in.
+    pub async fn stream_selected_videos(
+        &self,
+        profile_url: &str,
+        urls: &[String],
+        include_metadata: bool,
+        naming: ZipNaming,
+        writer: tokio::io::DuplexStream,
+    ) -> Result<usize, AppError> {
+        let username = extract_username(profile_url)
+            .ok_or_else(|| AppError::BadRequest("Invalid TikTok profile URL".to_string()))?;
+        self.check_profile_allowed(&username)?;
+        self.preflight_disk_space(urls.len())?;
+        let session_dir = self.new_session_dir()?;
+
+        // Downloads complete out of order, which is the point: the first
+        // finished video heads the archive. Numbered names still follow the
+        // user's selection order so the listing stays meaningful.
+        let semaphore = Arc::new(Semaphore::new(self.config.batch_concurrency.max(1)));
+        // All these URLs belong to one profile, so the per-profile gate
+        // caps them as a group under the global batch limit.
+        let profile_gate = profile_semaphore(&username, self.config.per_profile_concurrency);
+        let mut in_flight: FuturesUnordered<_> = urls
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(index, url)| {
+                let semaphore = Arc::clone(&semaphore);
+                let profile_gate = Arc::clone(&profile_gate);
+                let session_dir = session_dir.clone();
+                async move {
+                    let _profile_permit =
+                        profile_gate.acquire().await.expect("semaphore closed");
+                    let _permit = semaphore.acquire().await.expect("semaphore closed");
+                    let result = self
+                        .download_video_file(
+                            &url,
+                            PROFILE_FORMAT_SELECTOR,
+                            &session_dir,
+                            include_metadata,
+                        )
+                        .await;
+                    (index, url, result)
+                }
+            })
+            .collect();
+
+        let mut zip = crate::zip_stream::StreamingZipWriter::new(writer);
+        let mut added = 0usize;
+        let mut report = Vec::with_capacity(urls.len());
+        while let Some((index, url, result)) = in_flight.next().await {
+            let path = match result {
+                Ok(path) => path,
+                Err(e) => {
+                    tracing::warn!(%url, error = %e, "skipping failed video");
+                    report.push(selected_video_result(&url, Some(&e)));
+                    continue;
+                }
+            };
+            report.push(selected_video_result(&url, None));
+            let mut files = vec![path.clone()];
+            if include_metadata {
+                let sidecar = path.with_extension("info.json");
+                if sidecar.exists() {
+                    files.push(sidecar);
+                }
+            }
+            for file in files {
+                let name = file
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .ok_or_else(|| AppError::internal("unrepresentable file name".to_string()))?;
+                let name = match naming {
+                    ZipNaming::Original => name.to_string(),
+                    ZipNaming::Numbered => numbered_entry_name(index, urls.len(), name),
+                };
+                let data = tokio::fs::read(&file).await?;
+                zip.add_entry(&name, &data).await?;
+            }
+            let _ = std::fs::remove_file(&path);
+            added += 1;
+        }
+        let _ = std::fs::remove_dir_all(&session_dir);
+        if added == 0 {
+            // Even an all-failed selection produces a valid archive: the
+            // report is the only way the reasons can reach the user once
+            // the body has started streaming.
+            tracing::warn!("none of the selected videos could be downloaded");
+        }
+        // Report order follows completion order, matching the entries.
+        let report_json = serde_json::to_vec_pretty(&report)
+            .map_err(|e| AppError::internal(format!("report serialization failed: {e}")))?;
+        zip.add_entry("download_report.json", &report_json).await?;
+        zip.finish().await?;
+        Ok(added)
+    }
+
+    /// Extract one audio rendition into `dir`, returning the written path.
+    pub async fn download_audio_file(
+        &self,
+        url: &str,
+        audio_format: &str,
+        dir: &Path,
+    ) -> Result<PathBuf, AppError> {
+        let mut cmd = self.base_command();
+        cmd.args(["-x", "--audio-format"])
+            .arg(audio_format)
+            .arg("-o")
+            .arg(dir.join("%(uploader)s_%(title)s_%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .args(["--print", "after_move:filepath", "--no-simulate"])
+            .arg(normalize_tiktok_url(url));
+        self.apply_rate_limit(&mut cmd);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let path = PathBuf::from(stdout.trim());
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(AppError::internal(
+                "yt-dlp reported success but the audio file is missing".to_string(),
+            ))
+        }
+    }
+
+    /// Download several renditions of one video — say the MP4 and the MP3 —
+    /// and pack them into one ZIP, saving the client a round trip per
+    /// format. Returns the archive path inside the session dir.
+    pub async fn download_bundle(
+        &self,
+        url: &str,
+        outputs: &[BundleOutput],
+    ) -> Result<PathBuf, AppError> {
+        let session_dir = self.new_session_dir()?;
+        let mut files = Vec::with_capacity(outputs.len());
+        for (i, output) in outputs.iter().enumerate() {
+            if i > 0 {
+                self.jitter_between_requests().await;
+            }
+            let path = match output {
+                BundleOutput::Video(height) => {
+                    let selector = match height {
+                        Some(h) => format!("best[height<={h}][ext=mp4]/best[height<={h}]/best"),
+                        None => BEST_SINGLE_SELECTOR.to_string(),
+                    };
+                    self.download_video_file(url, &selector, &session_dir, false)
+                        .await?
+                }
+                BundleOutput::Audio(format) => {
+                    self.download_audio_file(url, format, &session_dir).await?
+                }
+            };
+            files.push(path);
+        }
+        let zip_path = session_dir.join("bundle.zip");
+        self.build_zip(&files, &zip_path, ZipNaming::Original)
+            .await?;
+        Ok(zip_path)
+    }
+
+    /// Pause between consecutive yt-dlp calls when jitter is configured.
+    /// Deliberately not used inside the bounded-concurrency paths, where a
+    /// shared delay would just serialize the batch.
+    async fn jitter_between_requests(&self) {
+        jitter_wait(self.config.request_jitter_ms).await;
+    }
+
+    fn new_session_dir(&self) -> Result<PathBuf, AppError> {
+        let dir = self.scratch.path().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// Run `create_zip_archive` on the blocking pool, gated by the ZIP
+    /// semaphore. Archives run to gigabytes, and assembling one inline
+    /// would stall the reactor thread for the whole write.
+    async fn build_zip(
+        &self,
+        files: &[PathBuf],
+        zip_path: &Path,
+        naming: ZipNaming,
+    ) -> Result<u64, AppError> {
+        let _permit = self
+            .zip_semaphore
+            .acquire()
+            .await
+            .expect("semaphore closed");
+        let files = files.to_vec();
+        let zip_path = zip_path.to_path_buf();
+        tokio::task::spawn_blocking(move || create_zip_archive(&files, &zip_path, naming))
+            .await
+            .map_err(|e| AppError::internal(format!("zip task failed: {e}")))?
+    }
+
+    async fn zip_session(
+        &self,
+        username: &str,
+        files: &[PathBuf],
+        naming: ZipNaming,
+    ) -> Result<(PathBuf, u64), AppError> {
+        let zip_path = Path::new(&self.config.downloads_dir).join(format!(
+            "tiktok_{}_{}.zip",
+            username,
+            uuid::Uuid::new_v4().simple()
+        ));
+        let size = self.build_zip(files, &zip_path, naming).await?;
+        Ok((zip_path, size))
+    }
+
+    /// Like `zip_session`, but packs the files into numbered parts so no
+    /// single archive grows past `limit` bytes. Huge profiles otherwise
+    /// produce one multi-gigabyte ZIP that flaky connections never finish.
+    async fn zip_session_split(
+        &self,
+        username: &str,
+        files: &[PathBuf],
+        naming: ZipNaming,
+        limit: u64,
+    ) -> Result<Vec<(PathBuf, u64)>, AppError> {
+        let base = format!("tiktok_{}_{}", username, uuid::Uuid::new_v4().simple());
+        let groups = split_files_by_size(files, limit);
+        let mut parts = Vec::with_capacity(groups.len());
+        for (index, group) in groups.iter().enumerate() {
+            let zip_path = Path::new(&self.config.downloads_dir)
+                .join(format!("{}_part{:03}.zip", base, index + 1));
+            let size = self.build_zip(group, &zip_path, naming).await?;
+            parts.push((zip_path, size));
+        }
+        Ok(parts)
+    }
+
+    /// The "best quality" selector honoring the configured codec and
+    /// container preferences; falls back to the stock selector when no
+    /// preference is set.
+    pub fn best_quality_selector(&self) -> String {
+        preference_selector(
+            &self.config.preferred_codecs,
+            self.config.prefer_container.as_deref(),
+        )
+    }
+
+    /// The video half of [`Self::best_quality_selector`], fed to the mux
+    /// pipeline while ffmpeg fetches the audio track itself.
+    pub fn best_video_only_selector(&self) -> String {
+        video_preference_selector(
+            &self.config.preferred_codecs,
+            self.config.prefer_container.as_deref(),
+        )
+    }
+
+    /// True when ffmpeg is runnable; several features (trimming, audio
+    /// conversion) silently depend on it.
+    pub async fn ffmpeg_available(&self) -> bool {
+        self.check_ffmpeg_availability().await.is_ok()
+    }
+
+    /// Report the installed ffmpeg version, mirroring
+    /// [`check_ytdlp_availability`](Self::check_ytdlp_availability), or a
+    /// clear error when it isn't on PATH.
+    pub async fn check_ffmpeg_availability(&self) -> Result<String, AppError> {
+        probe_ffmpeg("ffmpeg").await
+    }
+
+    /// Download a trimmed section of a video to a temp file. Trimming goes
+    /// through ffmpeg post-processing, so unlike the plain streaming path
+    /// this must hit disk first.
+    pub async fn download_trimmed_video(
+        &self,
+        url: &str,
+        format_id: &str,
+        start: f64,
+        end: f64,
+    ) -> Result<PathBuf, AppError> {
+        let session_dir = self.new_session_dir()?;
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(format_id)
+            .arg("--download-sections")
+            .arg(format!("*{start}-{end}"))
+            .arg("-o")
+            .arg(session_dir.join("%(uploader)s_%(title)s_%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .args(["--print", "after_move:filepath", "--no-simulate"])
+            .arg(normalize_tiktok_url(url));
+        self.apply_rate_limit(&mut cmd);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let path = PathBuf::from(stdout.trim());
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(AppError::internal(
+                "yt-dlp reported success but the trimmed file is missing".to_string(),
+            ))
+        }
+    }
+
+    /// Download a video and strip its audio track with ffmpeg. This is the
+    /// mute path for videos that only offer muxed formats — the common case
+    /// on TikTok — and like trimming it must hit disk first.
+    pub async fn download_muted_video(&self, url: &str, format: &str) -> Result<PathBuf, AppError> {
+        let session_dir = self.new_session_dir()?;
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(format)
+            .arg("-o")
+            .arg(session_dir.join("%(uploader)s_%(title)s_%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .args(["--print", "after_move:filepath", "--no-simulate"])
+            .arg(normalize_tiktok_url(url));
+        self.apply_rate_limit(&mut cmd);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let path = PathBuf::from(stdout.trim());
+        if !path.exists() {
+            return Err(AppError::internal(
+                "yt-dlp reported success but the output file is missing".to_string(),
+            ));
+        }
+
+        let muted = session_dir.join("muted.mp4");
+        let status = Command::new("ffmpeg")
+            .args(mute_ffmpeg_args(&path, &muted))
+            .status()
+            .await
+            .map_err(|e| AppError::internal(format!("failed to run ffmpeg: {e}")))?;
+        if !status.success() || !muted.exists() {
+            return Err(AppError::internal(
+                "ffmpeg failed to strip the audio track".to_string(),
+            ));
+        }
+        Ok(muted)
+    }
+
+    /// Download a video and rewrite it through ffmpeg with all container
+    /// metadata dropped, for callers who don't want location or device
+    /// traces in the file they share.
+    pub async fn download_scrubbed_video(
+        &self,
+        url: &str,
+        format: &str,
+    ) -> Result<PathBuf, AppError> {
+        let session_dir = self.new_session_dir()?;
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(format)
+            .arg("-o")
+            .arg(session_dir.join("%(uploader)s_%(title)s_%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .args(["--print", "after_move:filepath", "--no-simulate"])
+            .arg(normalize_tiktok_url(url));
+        self.apply_rate_limit(&mut cmd);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let path = PathBuf::from(stdout.trim());
+        if !path.exists() {
+            return Err(AppError::internal(
+                "yt-dlp reported success but the output file is missing".to_string(),
+            ));
+        }
+
+        let scrubbed = session_dir.join("scrubbed.mp4");
+        let status = Command::new("ffmpeg")
+            .args(strip_metadata_ffmpeg_args(&path, &scrubbed))
+            .status()
+            .await
+            .map_err(|e| AppError::internal(format!("failed to run ffmpeg: {e}")))?;
+        if !status.success() || !scrubbed.exists() {
+            return Err(AppError::internal(
+                "ffmpeg failed to strip the file's metadata".to_string(),
+            ));
+        }
+        Ok(scrubbed)
+    }
+
+    /// Download a video and composite the operator-configured watermark
+    /// image onto it. Re-encodes the video track (the overlay makes that
+    /// unavoidable), so it is the slowest of the ffmpeg paths.
+    pub async fn download_watermarked_video(
+        &self,
+        url: &str,
+        format: &str,
+        image: &Path,
+        position: WatermarkPosition,
+        opacity: f32,
+    ) -> Result<PathBuf, AppError> {
+        let session_dir = self.new_session_dir()?;
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(format)
+            .arg("-o")
+            .arg(session_dir.join("%(uploader)s_%(title)s_%(id)s.%(ext)s"))
+            .args(["--restrict-filenames", "--no-playlist"])
+            .args(["--print", "after_move:filepath", "--no-simulate"])
+            .arg(normalize_tiktok_url(url));
+        self.apply_rate_limit(&mut cmd);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let path = PathBuf::from(stdout.trim());
+        if !path.exists() {
+            return Err(AppError::internal(
+                "yt-dlp reported success but the output file is missing".to_string(),
+            ));
+        }
+
+        let branded = session_dir.join("branded.mp4");
+        let filter = watermark_filter(position, opacity);
+        let status = Command::new("ffmpeg")
+            .args(watermark_ffmpeg_args(&path, image, &filter, &branded))
+            .status()
+            .await
+            .map_err(|e| AppError::internal(format!("failed to run ffmpeg: {e}")))?;
+        if !status.success() || !branded.exists() {
+            return Err(AppError::internal(
+                "ffmpeg failed to composite the watermark".to_string(),
+            ));
+        }
+        Ok(branded)
+    }
+
+    /// Spawn a yt-dlp process writing the selected format to stdout and wrap
+    /// it in a `VideoStream` body.
+    pub fn spawn_video_stream(
+        &self,
+        url: &str,
+        format_id: &str,
+        cookies: Option<&CookieFile>,
+    ) -> Result<VideoStream, AppError> {
+        let mut cmd = self.base_command();
+        cmd.arg("-f")
+            .arg(format_id)
+            .args(["-o", "-", "--no-playlist"])
+            .arg(normalize_tiktok_url(url))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        apply_cookies(&mut cmd, cookies);
+        self.apply_rate_limit(&mut cmd);
+        let child = cmd
+            .spawn()
+            .map_err(|e| AppError::internal(format!("failed to spawn yt-dlp: {e}")))?;
+        VideoStream::new(child, self.config.stream_buffer_size, self.stream_byte_limit())
+            .map_err(|e| AppError::internal(e.to_string()))
+    }
+
+    /// Stream a muxed download for posts whose best quality only exists as
+    /// separate video and audio tracks, which the plain stdout pipe cannot
+    /// serve (muxing needs ffmpeg). yt-dlp feeds the video track through a
+    /// pipe, ffmpeg pulls the audio track straight off the CDN, and the
+    /// result is a fragmented MP4 — playable as it arrives, unlike a
+    /// regular moov-at-the-end file — written to ffmpeg's stdout. Nothing
+    /// touches disk.
+    pub async fn spawn_muxed_stream(
+        &self,
+        url: &str,
+        video_selector: &str,
+        audio_track: Option<&str>,
+        cookies: Option<&CookieFile>,
+    ) -> Result<VideoStream, AppError> {
+        let normalized = normalize_tiktok_url(url);
+        let mut cmd = self.base_command();
+        cmd.args(["-j", "--no-playlist"]).arg(&normalized);
+        apply_cookies(&mut cmd, cookies);
+        let stdout = self.run_ytdlp(cmd).await?;
+        let raw = extract_video_metadata(&stdout)?;
+        let audio = pick_mux_audio(&raw.formats, audio_track)?;
+        let audio_url = audio.url.as_deref().expect("picked on url presence");
+
+        let mut ytdlp = self.base_command();
+        ytdlp
+            .arg("-f")
+            .arg(video_selector)
+            .args(["-o", "-", "--no-playlist"])
+            .arg(&normalized)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        apply_cookies(&mut ytdlp, cookies);
+        self.apply_rate_limit(&mut ytdlp);
+        let mut ytdlp = ytdlp
+            .spawn()
+            .map_err(|e| AppError::internal(format!("failed to spawn yt-dlp: {e}")))?;
+        let video_pipe: Stdio = ytdlp
+            .stdout
+            .take()
+            .ok_or_else(|| AppError::internal("yt-dlp has no stdout pipe".to_string()))?
+            .try_into()
+            .map_err(|_| AppError::internal("could not inherit the yt-dlp pipe".to_string()))?;
+
+        let mut ffmpeg = Command::new("ffmpeg");
+        ffmpeg
+            .args(mux_ffmpeg_args(audio_url, &audio.http_headers))
+            .stdin(video_pipe)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let ffmpeg = ffmpeg
+            .spawn()
+            .map_err(|e| AppError::internal(format!("failed to spawn ffmpeg: {e}")))?;
+        VideoStream::pipeline(
+            vec![ytdlp],
+            ffmpeg,
+            self.config.stream_buffer_size,
+            self.stream_byte_limit(),
+        )
+        .map_err(|e| AppError::internal(e.to_string()))
+    }
+
+    /// Spawn a yt-dlp process extracting audio to stdout in the given format.
+    pub fn spawn_audio_stream(&self, url: &str, audio_format: &str) -> Result<VideoStream, AppError> {
+        let mut cmd = self.base_command();
+        cmd.args(["-x", "--audio-format"])
+            .arg(audio_format)
+            .args(["-o", "-", "--no-playlist"])
+            .arg(normalize_tiktok_url(url))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        self.apply_rate_limit(&mut cmd);
+        let child = cmd
+            .spawn()
+            .map_err(|e| AppError::internal(format!("failed to spawn yt-dlp: {e}")))?;
+        VideoStream::new(child, self.config.stream_buffer_size, self.stream_byte_limit())
+            .map_err(|e| AppError::internal(e.to_string()))
+    }
+}
+
+/// Run `f` over `items` with at most `limit` futures in flight, returning the
+/// results in input order. This is the one place that knows how to run many
+/// yt-dlp jobs politely; batch info, batch estimate and selective download
+/// all funnel through it.
+pub async fn run_bounded<T, F, Fut>(
+    items: impl IntoIterator<Item = String>,
+    limit: usize,
+    f: F,
+) -> Vec<Result<T, AppError>>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = Result<T, AppError>>,
+{
+    let semaphore = Arc::new(Semaphore::new(limit.max(1)));
+    let mut in_flight = FuturesUnordered::new();
+    let mut total = 0usize;
+    for (index, item) in items.into_iter().enumerate() {
+        total += 1;
+        let semaphore = Arc::clone(&semaphore);
+        let fut = f(item);
+        in_flight.push(async move {
+            let _permit = semaphore.acquire().await.expect("semaphore closed");
+            (index, fut.await)
+        });
+    }
+
+    let mut results: Vec<Option<Result<T, AppError>>> = Vec::new();
+    results.resize_with(total, || None);
+    while let Some((index, result)) = in_flight.next().await {
+        results[index] = Some(result);
+    }
+    results
+        .into_iter()
+        .map(|r| r.expect("every slot filled"))
+        .collect()
+}
+
+/// Set a file's mtime to the video's upload date (YYYYMMDD form), so
+/// archived files sort by when they were posted rather than processed.
+/// Best effort: a missing or unparsable date leaves the mtime alone.
+pub fn apply_upload_mtime(path: &Path, upload_date: Option<&str>) {
+    let Some(date) = upload_date else { return };
+    let Ok(date) = chrono::NaiveDate::parse_from_str(date, "%Y%m%d") else {
+        return;
+    };
+    let Some(midnight) = date.and_hms_opt(0, 0, 0) else {
+        return;
+    };
+    let mtime = filetime::FileTime::from_unix_time(midnight.and_utc().timestamp(), 0);
+    if let Err(e) = filetime::set_file_mtime(path, mtime) {
+        tracing::debug!(path = %path.display(), error = %e, "failed to set upload mtime");
+    }
+}
+
+/// One report line for a selected URL; failures keep the short reason
+/// [`classify_ytdlp_error`] already derived from yt-dlp stderr.
+fn selected_video_result(url: &str, error: Option<&AppError>) -> SelectedVideoResult {
+    SelectedVideoResult {
+        url: url.to_string(),
+        status: if error.is_none() { "ok" } else { "failed" }.to_string(),
+        error: error.map(|e| e.to_string()),
+    }
+}
+
+/// Rough per-video size used for the disk-space preflight. TikTok videos
+/// average well under this, so overshooting only makes the guard more
+/// conservative.
+const ESTIMATED_VIDEO_BYTES: u64 = 30 * 1024 * 1024;
+
+/// The preflight itself, split out so tests can feed it a mocked
+/// availability figure.
+fn ensure_disk_space(required: u64, available: Option<u64>) -> Result<(), AppError> {
+    match available {
+        Some(available) if available < required => Err(AppError::BadRequest(format!(
+            "insufficient disk space: about {required} bytes needed, {available} free"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Probe that a directory accepts writes, deleting the probe file again.
+fn verify_dir_writable(dir: &Path) -> Result<(), AppError> {
+    let probe = dir.join(format!(".write-probe-{}", uuid::Uuid::new_v4().simple()));
+    std::fs::write(&probe, b"probe")
+        .map_err(|e| AppError::internal(format!("{} is not writable: {e}", dir.display())))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Format selector for bulk downloads. The `/best` fallback matters: some
+/// videos have no mp4 rendition at all, and a bare `best[ext=mp4]` would
+/// silently drop them from the archive.
+const PROFILE_FORMAT_SELECTOR: &str = "best[ext=mp4]/best";
+
+/// Stock output pattern for profile downloads; the trailing `_%(id)s`
+/// is what the manifest and chronological ordering match filenames on.
+const PROFILE_OUTPUT_TEMPLATE: &str = "%(uploader)s_%(title)s_%(id)s.%(ext)s";
+
+/// Translate a user-facing `{token}` template into a yt-dlp output
+/// pattern. Only a whitelisted token set is honored and everything
+/// outside a token is escaped — yt-dlp expands `%(field)s` sequences, so
+/// forwarding raw user text as the template would let callers pull
+/// arbitrary metadata fields (or crash the run) via a stray `%`.
+pub fn ytdlp_output_template(template: &str) -> Result<String, AppError> {
+    if template.contains(['/', '\\']) {
+        return Err(AppError::BadRequest(
+            "filename_template must not contain path separators".to_string(),
+        ));
+    }
+    let mut out = String::with_capacity(template.len() + 16);
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open].replace('%', "%%"));
+        let Some(close) = rest[open..].find('}') else {
+            return Err(AppError::BadRequest(
+                "Unclosed '{' in filename_template".to_string(),
+            ));
+        };
+        let field = match &rest[open + 1..open + close] {
+            "id" => "%(id)s",
+            "title" => "%(title)s",
+            "date" => "%(upload_date)s",
+            "index" => "%(playlist_index)s",
+            "uploader" => "%(uploader)s",
+            other => {
+                return Err(AppError::BadRequest(format!(
+                    "Unknown filename_template token '{{{other}}}'; supported: \
+                     {{id}}, {{title}}, {{date}}, {{index}}, {{uploader}}"
+                )))
+            }
+        };
+        out.push_str(field);
+        rest = &rest[open + close + 1..];
+    }
+    out.push_str(&rest.replace('%', "%%"));
+    if out.is_empty() {
+        return Err(AppError::BadRequest(
+            "filename_template must not be empty".to_string(),
+        ));
+    }
+    out.push_str(".%(ext)s");
+    Ok(out)
+}
+
+/// Archival-quality selector: the best video and audio streams muxed
+/// together, which may exceed what any single pre-muxed format offers.
+/// Requires ffmpeg; callers without it should use [`BEST_SINGLE_SELECTOR`].
+pub const BEST_QUALITY_SELECTOR: &str = "bestvideo+bestaudio/best";
+
+/// Best single pre-muxed stream; no ffmpeg needed.
+pub const BEST_SINGLE_SELECTOR: &str = "best";
+
+/// Best video-only stream, for mute downloads. Only usable when the video
+/// actually offers a format without audio; TikTok usually serves muxed
+/// formats only, in which case the audio has to be stripped with ffmpeg
+/// instead (see [`TikTokService::download_muted_video`]).
+pub const VIDEO_ONLY_SELECTOR: &str = "bestvideo[ext=mp4]/bestvideo";
+
+/// Extractor args appended when BYPASS_AGE_GATE is on: prefer TikTok's
+/// mobile app API hosts, which serve age-gated public videos without the
+/// web consent wall.
+const AGE_GATE_EXTRACTOR_ARGS: &str = "tiktok:api_hostname=api22-normal-c-useast2a.tiktokv.com;app_name=trill";
+
+/// ffmpeg arguments that drop the audio track from `input`. `-c copy` keeps
+/// the video stream as-is — removing audio never needs a re-encode.
+/// Where a watermark overlay lands on the frame, with a 10px margin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl std::str::FromStr for WatermarkPosition {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "top-left" => Ok(WatermarkPosition::TopLeft),
+            "top-right" => Ok(WatermarkPosition::TopRight),
+            "bottom-left" => Ok(WatermarkPosition::BottomLeft),
+            "bottom-right" => Ok(WatermarkPosition::BottomRight),
+            _ => Err(()),
+        }
+    }
+}
+
+/// ffmpeg filter graph compositing input 1 (the watermark image) onto
+/// input 0 at the chosen corner, faded to `opacity` via the alpha channel.
+fn watermark_filter(position: WatermarkPosition, opacity: f32) -> String {
+    let coords = match position {
+        WatermarkPosition::TopLeft => "10:10",
+        WatermarkPosition::TopRight => "W-w-10:10",
+        WatermarkPosition::BottomLeft => "10:H-h-10",
+        WatermarkPosition::BottomRight => "W-w-10:H-h-10",
+    };
+    format!("[1]format=rgba,colorchannelmixer=aa={opacity}[wm];[0][wm]overlay={coords}")
+}
+
+fn watermark_ffmpeg_args(
+    input: &Path,
+    image: &Path,
+    filter: &str,
+    output: &Path,
+) -> Vec<std::ffi::OsString> {
+    vec![
+        "-y".into(),
+        "-i".into(),
+        input.into(),
+        "-i".into(),
+        image.into(),
+        "-filter_complex".into(),
+        filter.into(),
+        "-c:a".into(),
+        "copy".into(),
+        output.into(),
+    ]
+}
+
+/// ffmpeg args that rewrite the container without its metadata. Streams
+/// are copied, not re-encoded, so the cost is one extra disk pass.
+fn strip_metadata_ffmpeg_args(input: &Path, output: &Path) -> Vec<std::ffi::OsString> {
+    vec![
+        "-y".into(),
+        "-i".into(),
+        input.into(),
+        "-map_metadata".into(),
+        "-1".into(),
+        "-c".into(),
+        "copy".into(),
+        output.into(),
+    ]
+}
+
+fn mute_ffmpeg_args(input: &Path, output: &Path) -> Vec<std::ffi::OsString> {
+    vec![
+        "-y".into(),
+        "-i".into(),
+        input.into(),
+        "-c".into(),
+        "copy".into(),
+        "-an".into(),
+        output.into(),
+    ]
+}
+
+/// Append yt-dlp's upload-date floor for incremental archive runs.
+/// --dateafter rejects entries uploaded before `date` at the listing
+/// stage, and --no-overwrites skips any file already on disk, so a
+/// weekly job only pays for what is actually new.
+fn apply_date_filter(cmd: &mut Command, since_date: Option<&str>) {
+    if let Some(date) = since_date {
+        cmd.arg("--dateafter").arg(date).arg("--no-overwrites");
+    }
+}
+
+/// yt-dlp flags that fetch caption tracks and mux them into the output as
+/// soft subtitles. ffmpeg does the embedding.
+fn subtitle_args(sub_langs: Option<&str>) -> Vec<String> {
+    vec![
+        "--write-subs".to_string(),
+        "--embed-subs".to_string(),
+        "--sub-langs".to_string(),
+        sub_langs.unwrap_or("all").to_string(),
+    ]
+}
+
+fn is_mp4(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some("mp4")
+}
+
+/// Parse yt-dlp's single-video JSON output.
+pub fn extract_video_metadata(stdout: &str) -> Result<YtDlpVideoInfo, AppError> {
+    match serde_json::from_str(stdout) {
+        Ok(info) => Ok(info),
+        Err(whole_err) => {
+            // Some invocations (a URL resolving to a playlist-of-one, for
+            // instance) print one JSON object per line; take the first.
+            let first_line = stdout.lines().find(|l| !l.trim().is_empty());
+            if let Some(info) = first_line.and_then(|l| serde_json::from_str(l).ok()) {
+                tracing::debug!("yt-dlp printed multiple JSON lines; used the first");
+                return Ok(info);
+            }
+            Err(AppError::internal(format!(
+                "failed to parse yt-dlp output: {whole_err}"
+            )))
+        }
+    }
+}
+
+/// Sleep a random 50-100% of `max_ms`. Randomized rather than fixed so
+/// the request pattern doesn't look mechanical to TikTok.
+async fn jitter_wait(max_ms: u64) {
+    if max_ms == 0 {
+        return;
+    }
+    let wait = rand::Rng::gen_range(&mut rand::thread_rng(), max_ms / 2..=max_ms);
+    tokio::time::sleep(Duration::from_millis(wait)).await;
+}
+
+/// The highest-resolution adaptive format, HLS preferred over DASH since
+/// browser players handle it more widely. Progressive-only listings
+/// return None.
+pub fn select_manifest_format(formats: &[YtDlpFormat]) -> Option<(&YtDlpFormat, &'static str)> {
+    let kind_of = |f: &YtDlpFormat| -> Option<&'static str> {
+        match f.protocol.as_deref() {
+            Some(p) if p.contains("m3u8") => Some("hls"),
+            Some(p) if p.contains("dash") => Some("dash"),
+            _ => None,
+        }
+    };
+    formats
+        .iter()
+        .filter_map(|f| kind_of(f).map(|kind| (f, kind)))
+        .max_by_key(|(f, kind)| (*kind == "hls", f.height.unwrap_or(0)))
+}
+
+/// Hashtags take letters and digits from any script plus underscores;
+/// mentions follow TikTok's username alphabet (ASCII word characters and
+/// periods).
+static HASHTAG_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r"#([\p{L}\p{N}_]+)").unwrap());
+static MENTION_RE: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r"@([A-Za-z0-9_.]+)").unwrap());
+
+/// Pull (hashtags, mentions) out of a description, markers stripped, in
+/// order of first appearance.
+pub fn extract_tags(description: &str) -> (Vec<String>, Vec<String>) {
+    let collect = |re: &regex::Regex| {
+        let mut seen = Vec::new();
+        for capture in re.captures_iter(description) {
+            let tag = capture[1].to_string();
+            if !seen.contains(&tag) {
+                seen.push(tag);
+            }
+        }
+        seen
+    };
+    (collect(&HASHTAG_RE), collect(&MENTION_RE))
+}
+
+fn parse_playlist_lines(stdout: &str) -> Vec<ProfileVideoInfo> {
+    stdout
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|line| serde_json::from_str::<YtDlpPlaylistEntry>(line).ok())
+        .map(playlist_entry_to_video)
+        .collect()
+}
+
+/// Only genuine extraction breakage warrants the fallback. Rate limits,
+/// timeouts and private/removed videos all map to dedicated variants;
+/// what's left as Internal is yt-dlp failing to make sense of the page —
+/// exactly the "TikTok broke the extractor" case.
+fn is_extraction_error(err: &AppError) -> bool {
+    matches!(err, AppError::Internal { .. })
+}
+
+/// Query a tikwm-style API and map its response onto our [`VideoInfo`].
+pub async fn fetch_fallback_info(base_url: &str, url: &str) -> Result<VideoInfo, AppError> {
+    let response = shared_http_client()
+        .get(base_url)
+        .query(&[("url", url)])
+        .timeout(Duration::from_secs(15))
+        .send()
+        .await
+        .map_err(|e| AppError::internal(format!("fallback API unreachable: {e}")))?;
+    let body: FallbackApiResponse = response
+        .json()
+        .await
+        .map_err(|e| AppError::internal(format!("fallback API returned garbage: {e}")))?;
+    if body.code != 0 {
+        return Err(AppError::VideoUnavailable(format!(
+            "Fallback API could not extract this video: {}",
+            body.msg.unwrap_or_else(|| format!("code {}", body.code))
+        )));
+    }
+    let data = body
+        .data
+        .ok_or_else(|| AppError::internal("fallback API response carries no data".to_string()))?;
+    Ok(fallback_to_video_info(data))
+}
+
+/// The fallback API knows much less than yt-dlp — one no-watermark
+/// rendition, one cover image, no captions — so most lists come back
+/// short or empty.
+fn fallback_to_video_info(data: FallbackVideoData) -> VideoInfo {
+    let description = data.title.clone().unwrap_or_default();
+    let (hashtags, mentions) = extract_tags(&description);
+    VideoInfo {
+        id: data.id,
+        title: data.title.unwrap_or_else(|| "Untitled".to_string()),
+        description,
+        uploader: data
+            .author
+            .and_then(|a| a.unique_id.or(a.nickname))
+            .unwrap_or_default(),
+        duration: data.duration,
+        view_count: data.play_count,
+        like_count: data.digg_count,
+        comment_count: data.comment_count,
+        upload_date: None,
+        thumbnail_url: data.cover.clone(),
+        thumbnails: data
+            .cover
+            .map(|url| {
+                vec![ThumbnailOption {
+                    id: None,
+                    url,
+                    width: None,
+                    height: None,
+                }]
+            })
+            .unwrap_or_default(),
+        formats: data
+            .play
+            .map(|play| {
+                vec![FormatOption {
+                    format_id: "fallback".to_string(),
+                    label: "No watermark (fallback)".to_string(),
+                    ext: "mp4".to_string(),
+                    height: None,
+                    filesize: None,
+                    video_only: false,
+                    direct_url: Some(play),
+                }]
+            })
+            .unwrap_or_default(),
+        subtitle_languages: Vec::new(),
+        hashtags,
+        mentions,
+        effects: Vec::new(),
+        audio_tracks: Vec::new(),
+    }
+}
+
+/// Best image URL per slide, in carousel order.
+pub fn photo_image_urls(raw: &YtDlpVideoInfo) -> Vec<String> {
+    if raw.is_playlist() {
+        raw.entries
+            .iter()
+            .filter_map(|e| extract_best_thumbnail_url(&e.thumbnails))
+            .collect()
+    } else {
+        extract_best_thumbnail_url(&raw.thumbnails)
+            .into_iter()
+            .collect()
+    }
+}
+
+/// The compact listing shape for one playlist/profile entry.
+fn playlist_entry_to_video(entry: YtDlpPlaylistEntry) -> ProfileVideoInfo {
+    ProfileVideoInfo {
+        url: entry
+            .url
+            .clone()
+            .unwrap_or_else(|| format!("https://www.tiktok.com/video/{}", entry.id)),
+        title: entry.title.clone().unwrap_or_else(|| "Untitled".to_string()),
+        thumbnail_url: extract_best_thumbnail_url(&entry.thumbnails),
+        thumbnails: parse_thumbnails(&entry.thumbnails),
+        id: entry.id,
+        duration: entry.duration,
+        view_count: entry.view_count,
+        like_count: entry.like_count,
+        upload_date: entry.upload_date,
+        pinned: entry.is_pinned,
+    }
+}
+
+/// Rough size guess for formats yt-dlp reports without a filesize, scaled
+/// from the height (720p comes out around 18 MB, typical for a TikTok
+/// clip). Only used to keep target_filesize selection working when TikTok
+/// omits sizes.
+const ESTIMATED_BYTES_PER_PIXEL_ROW: u64 = 25_000;
+
+/// Pick the format whose size is the largest not exceeding `target`,
+/// falling back to the smallest when every format is over it. Formats
+/// without a known filesize are estimated from their height; video-only
+/// formats are skipped since auto-selection should yield a normal video.
+pub fn select_format_by_size(formats: &[FormatOption], target: u64) -> Option<&FormatOption> {
+    fn size_of(f: &FormatOption) -> u64 {
+        f.filesize
+            .unwrap_or_else(|| u64::from(f.height.unwrap_or(0)) * ESTIMATED_BYTES_PER_PIXEL_ROW)
+    }
+
+    let candidates: Vec<&FormatOption> = formats.iter().filter(|f| !f.video_only).collect();
+    candidates
+        .iter()
+        .filter(|f| size_of(f) <= target)
+        .max_by_key(|f| size_of(f))
+        .or_else(|| candidates.iter().min_by_key(|f| size_of(f)))
+        .copied()
+}
+
+/// The audio-only tracks of a format list, for multi-audio videos
+/// (dubs). Unlike the video format list these are never truncated — a
+/// video rarely has more than a handful of dubs.
+pub fn parse_audio_tracks(formats: &[YtDlpFormat]) -> Vec<AudioTrackOption> {
+    formats
+        .iter()
+        .filter(|f| f.has_audio() && !f.has_video())
+        .map(|f| AudioTrackOption {
+            format_id: f.format_id.clone(),
+            language: f.language.clone(),
+            label: f
+                .language
+                .clone()
+                .or_else(|| f.format_note.clone())
+                .unwrap_or_else(|| "audio".to_string()),
+            filesize: f.best_filesize(),
+        })
+        .collect()
+}
+
+/// Reduce yt-dlp's raw format table to the short list the UI offers: video
+/// formats only, best-first, one per resolution.
+pub fn parse_available_formats(
+    formats: &[YtDlpFormat],
+    max_formats: usize,
+    preferred_codecs: &[String],
+) -> Vec<FormatOption> {
+    let mut candidates: Vec<&YtDlpFormat> = formats
+        .iter()
+        .filter(|f| f.has_video() && f.height.is_some())
+        .collect();
+    // Preferred codecs sort first within a (height, audio) group, so the
+    // dedup below keeps the preferred variant.
+    candidates.sort_by(|a, b| {
+        b.height
+            .cmp(&a.height)
+            .then(a.has_audio().cmp(&b.has_audio()).reverse())
+            .then(codec_rank(a, preferred_codecs).cmp(&codec_rank(b, preferred_codecs)))
+    });
+    let mut options: Vec<FormatOption> = candidates
+        .into_iter()
+        .map(|f| FormatOption {
+            format_id: f.format_id.clone(),
+            label: if f.has_audio() {
+                format!("{}p", f.height.unwrap_or(0))
+            } else {
+                format!("{}p (video only)", f.height.unwrap_or(0))
+            },
+            ext: f.ext.clone().unwrap_or_else(|| "mp4".to_string()),
+            height: f.height,
+            filesize: f.best_filesize(),
+            video_only: !f.has_audio(),
+            direct_url: None,
+        })
+        .collect();
+    options.dedup_by_key(|f| (f.height, f.video_only));
+    if max_formats > 0 {
+        options.truncate(max_formats);
+    }
+    options
+}
+
+/// Position of the first preferred codec this format's vcodec starts
+/// with; unmatched formats rank after every preference.
+fn codec_rank(format: &YtDlpFormat, preferred: &[String]) -> usize {
+    let vcodec = format.vcodec.as_deref().unwrap_or("");
+    preferred
+        .iter()
+        .position(|codec| vcodec.starts_with(codec.as_str()))
+        .unwrap_or(preferred.len())
+}
+
+/// Build a "best" selector that tries the preferred codecs (optionally in
+/// the preferred container) in order before yt-dlp's stock best pick.
+fn preference_selector(codecs: &[String], container: Option<&str>) -> String {
+    let mut alternatives = Vec::new();
+    for codec in codecs {
+        if let Some(container) = container {
+            alternatives.push(format!(
+                "bestvideo[vcodec^={codec}][ext={container}]+bestaudio"
+            ));
+        }
+        alternatives.push(format!("bestvideo[vcodec^={codec}]+bestaudio"));
+    }
+    if codecs.is_empty() {
+        if let Some(container) = container {
+            alternatives.push(format!("bestvideo[ext={container}]+bestaudio"));
+        }
+    }
+    alternatives.push(BEST_QUALITY_SELECTOR.to_string());
+    alternatives.join("/")
+}
+
+/// Video-only companion to [`preference_selector`], for the mux pipeline
+/// where ffmpeg supplies the audio separately and a `+bestaudio` merge
+/// would defeat the point of piping video alone.
+fn video_preference_selector(codecs: &[String], container: Option<&str>) -> String {
+    let mut alternatives = Vec::new();
+    for codec in codecs {
+        if let Some(container) = container {
+            alternatives.push(format!("bestvideo[vcodec^={codec}][ext={container}]"));
+        }
+        alternatives.push(format!("bestvideo[vcodec^={codec}]"));
+    }
+    if codecs.is_empty() {
+        if let Some(container) = container {
+            alternatives.push(format!("bestvideo[ext={container}]"));
+        }
+    }
+    alternatives.push(VIDEO_ONLY_SELECTOR.to_string());
+    alternatives.join("/")
+}
+
+/// All thumbnails a video offers, sorted smallest-first by pixel area so
+/// list views can grab the front and detail views the back.
+pub fn parse_thumbnails(thumbnails: &[YtDlpThumbnail]) -> Vec<ThumbnailOption> {
+    let mut options: Vec<ThumbnailOption> = thumbnails
+        .iter()
+        .map(|t| ThumbnailOption {
+            id: t.id.clone(),
+            url: t.url.clone(),
+            width: t.width,
+            height: t.height,
+        })
+        .collect();
+    options.sort_by_key(|t| t.width.unwrap_or(0) as u64 * t.height.unwrap_or(0) as u64);
+    options
+}
+
+/// Pick the highest-resolution thumbnail, falling back to the last listed
+/// (yt-dlp orders worst-to-best when sizes are missing).
+pub fn extract_best_thumbnail_url(thumbnails: &[YtDlpThumbnail]) -> Option<String> {
+    thumbnails
+        .iter()
+        .max_by_key(|t| t.width.unwrap_or(0) * t.height.unwrap_or(0))
+        .or_else(|| thumbnails.last())
+        .map(|t| t.url.clone())
+}
+
+/// Map yt-dlp stderr to the most helpful error we can. Unclassified
+/// failures keep the exit code and stderr tail as diagnostics, which only
+/// reach clients when VERBOSE_ERRORS is on.
+pub fn classify_ytdlp_error(stderr: &str, exit_code: Option<i32>) -> AppError {
+    let lower = stderr.to_lowercase();
+    if lower.contains("private video") || lower.contains("log in") || lower.contains("login") {
+        AppError::Unauthorized("This video is private or requires login".to_string())
+    } else if lower.contains("removed")
+        || lower.contains("not available")
+        || lower.contains("404")
+    {
+        AppError::VideoUnavailable("This video is no longer available".to_string())
+    } else if lower.contains("unsupported url") {
+        AppError::BadRequest("This URL is not a downloadable TikTok video".to_string())
+    } else {
+        let tail: String = stderr.lines().rev().take(3).collect::<Vec<_>>().join("; ");
+        AppError::Internal {
+            message: format!("yt-dlp failed: {tail}"),
+            details: Some(YtDlpDiagnostics {
+                exit_code,
+                stderr_tail: tail,
+            }),
+        }
+    }
+}
+
+/// The .info.json sidecars yt-dlp writes next to each video when
+/// --write-info-json is on; their names already match the media files.
+fn collect_metadata_files(dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.ends_with(".info.json"))
+                .unwrap_or(false)
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Subtitle files written by --write-subs/--write-auto-subs.
+fn collect_subtitle_files(dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            matches!(
+                p.extension().and_then(|e| e.to_str()),
+                Some("vtt") | Some("srt")
+            )
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn collect_video_files(dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            matches!(
+                p.extension().and_then(|e| e.to_str()),
+                Some("mp4") | Some("webm") | Some("mkv")
+            )
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Zero-padded sequence prefix whose width grows with the file count, so
+/// archives with 100+ entries still sort correctly by name.
+fn numbered_entry_name(index: usize, total: usize, name: &str) -> String {
+    let width = total.to_string().len().max(3);
+    format!("{:0width$}_{name}", index + 1)
+}
+
+/// Disambiguate a ZIP entry name against the names already used: the
+/// first occurrence keeps its name, later ones get `_1`, `_2`... before
+/// the extension. Two videos can easily sanitize to the same title
+/// (especially with restrict-filenames), and many unzip tools silently
+/// overwrite duplicate entries.
+fn dedupe_entry_name(name: String, seen: &mut std::collections::HashSet<String>) -> String {
+    if seen.insert(name.clone()) {
+        return name;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) => (stem.to_string(), format!(".{ext}")),
+        None => (name.clone(), String::new()),
+    };
+    (1..)
+        .map(|n| format!("{stem}_{n}{ext}"))
+        .find(|candidate| seen.insert(candidate.clone()))
+        .expect("suffix search is unbounded")
+}
+
+/// Sort downloaded files by their video's upload date from the listing,
+/// so numbered ZIP entries extract (and sort by name) in the requested
+/// chronological direction. Files the listing doesn't know about sink to
+/// the end, keeping their relative order.
+fn order_files_chronologically(
+    files: &mut [PathBuf],
+    videos: &[ProfileVideoInfo],
+    order: DownloadOrder,
+) {
+    let date_of = |file: &PathBuf| -> Option<&str> {
+        let name = file.file_name()?.to_str()?;
+        videos
+            .iter()
+            .find(|video| name.contains(&format!("_{}.", video.id)))
+            .and_then(|video| video.upload_date.as_deref())
+    };
+    files.sort_by(|a, b| match (date_of(a), date_of(b)) {
+        (Some(a), Some(b)) => match order {
+            DownloadOrder::Newest => b.cmp(a),
+            DownloadOrder::Oldest => a.cmp(b),
+        },
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+}
+
+/// Describe each listed video for the manifest, pairing it with the
+/// archive entry that carries it. yt-dlp's output template ends in
+/// `_{id}.{ext}`, so the id is enough to find the file; videos that
+/// failed to download keep a line with no filename.
+fn manifest_entries(videos: &[ProfileVideoInfo], files: &[PathBuf]) -> Vec<ZipManifestEntry> {
+    videos
+        .iter()
+        .map(|video| ZipManifestEntry {
+            filename: files.iter().find_map(|file| {
+                let name = file.file_name()?.to_str()?;
+                name.contains(&format!("_{}.", video.id))
+                    .then(|| name.to_string())
+            }),
+            url: video.url.clone(),
+            title: video.title.clone(),
+            upload_date: video.upload_date.clone(),
+        })
+        .collect()
+}
+
+/// Write manifest.json and index.txt into the session dir and return
+/// their paths so they land at the root of the archive.
+fn write_session_manifest(
+    session_dir: &Path,
+    videos: &[ProfileVideoInfo],
+    files: &[PathBuf],
+) -> Result<Vec<PathBuf>, AppError> {
+    use std::fmt::Write;
+
+    let entries = manifest_entries(videos, files);
+    let json_path = session_dir.join("manifest.json");
+    let json = serde_json::to_vec_pretty(&entries)
+        .map_err(|e| AppError::internal(format!("manifest serialization failed: {e}")))?;
+    std::fs::write(&json_path, json)?;
+
+    let mut index = String::new();
+    for entry in &entries {
+        let filename = entry.filename.as_deref().unwrap_or("(not downloaded)");
+        let date = entry.upload_date.as_deref().unwrap_or("unknown date");
+        let _ = writeln!(index, "{filename}
+  {} ({date})
+  {}", entry.title, entry.url);
+    }
+    let index_path = session_dir.join("index.txt");
+    std::fs::write(&index_path, index)?;
+
+    Ok(vec![json_path, index_path])
+}
+
+/// Run `program -version` and pull the version out of ffmpeg's banner.
+/// Takes the program name so tests can point it at a binary that does not
+/// exist.
+async fn probe_ffmpeg(program: &str) -> Result<String, AppError> {
+    let output = Command::new(program)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|_| {
+            AppError::ServiceUnavailable("ffmpeg is not installed or not on PATH".to_string())
+        })?;
+    if !output.status.success() {
+        return Err(AppError::ServiceUnavailable(
+            "ffmpeg is installed but failed to report its version".to_string(),
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(parse_ffmpeg_version(&stdout).unwrap_or_else(|| "unknown".to_string()))
+}
+
+/// The version token out of ffmpeg's first banner line, e.g.
+/// "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) ..." -> "6.1.1-3ubuntu5".
+fn parse_ffmpeg_version(banner: &str) -> Option<String> {
+    banner
+        .lines()
+        .next()?
+        .strip_prefix("ffmpeg version ")?
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+}
+
+/// The audio side of the mux pipeline: an explicitly requested track by
+/// format id, or — yt-dlp lists formats worst-first — the last audio-only
+/// entry carrying a direct URL, i.e. the best one.
+fn pick_mux_audio<'a>(
+    formats: &'a [YtDlpFormat],
+    audio_track: Option<&str>,
+) -> Result<&'a YtDlpFormat, AppError> {
+    match audio_track {
+        Some(id) => formats
+            .iter()
+            .find(|f| f.format_id == id && f.url.is_some())
+            .ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "Audio track '{id}' has no direct URL for this video"
+                ))
+            }),
+        None => formats
+            .iter()
+            .rev()
+            .find(|f| f.has_audio() && !f.has_video() && f.url.is_some())
+            .ok_or_else(|| {
+                AppError::BadRequest(
+                    "This post has no separate audio track; the regular streaming endpoint already serves it muxed"
+                        .to_string(),
+                )
+            }),
+    }
+}
+
+/// ffmpeg arguments for the streaming muxer: video from stdin, audio
+/// fetched by ffmpeg itself (with whatever headers the CDN demands), both
+/// copied untouched into a fragmented MP4 on stdout.
+fn mux_ffmpeg_args(
+    audio_url: &str,
+    audio_headers: &std::collections::HashMap<String, String>,
+) -> Vec<String> {
+    let mut args: Vec<String> = ["-hide_banner", "-loglevel", "error", "-i", "pipe:0"]
+        .map(str::to_string)
+        .into();
+    if !audio_headers.is_empty() {
+        let headers: String = audio_headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect();
+        args.push("-headers".to_string());
+        args.push(headers);
+    }
+    args.push("-i".to_string());
+    args.push(audio_url.to_string());
+    args.extend(
+        [
+            "-map", "0:v:0", "-map", "1:a:0", "-c", "copy", "-movflags",
+            "frag_keyframe+empty_moov", "-f", "mp4", "pipe:1",
+        ]
+        .map(str::to_string),
+    );
+    args
+}
+
+/// Group `files` into consecutive runs whose on-disk sizes stay under
+/// `limit`. A file bigger than the limit on its own still gets a group;
+/// splitting a video across archives would help nobody.
+fn split_files_by_size(files: &[PathBuf], limit: u64) -> Vec<Vec<PathBuf>> {
+    let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+    let mut current: Vec<PathBuf> = Vec::new();
+    let mut current_size = 0u64;
+    for file in files {
+        let size = std::fs::metadata(file).map(|m| m.len()).unwrap_or(0);
+        if !current.is_empty() && current_size + size > limit {
+            groups.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+        current.push(file.clone());
+        current_size += size;
+    }
+    if !current.is_empty() {
+        groups.push(current);
+    }
+    groups
+}
+
+/// Pack `files` into a ZIP at `zip_path`, returning the archive size.
+/// `files` order is preserved, which matters for numbered naming.
+pub fn create_zip_archive(
+    files: &[PathBuf],
+    zip_path: &Path,
+    naming: ZipNaming,
+) -> Result<u64, AppError> {
+    let file = std::fs::File::create(zip_path)?;
+    let mut zip = zip::ZipWriter::new(file);
+    let base_options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+
+    let mut seen = std::collections::HashSet::new();
+    for (index, path) in files.iter().enumerate() {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| AppError::internal("unrepresentable file name".to_string()))?;
+        let name = match naming {
+            ZipNaming::Original => name.to_string(),
+            ZipNaming::Numbered => numbered_entry_name(index, files.len(), name),
+        };
+        let name = dedupe_entry_name(name, &mut seen);
+        // Entries keep the file's mtime, which yt-dlp sets to the upload
+        // date; archives then unpack with the original timeline intact.
+        let options = match entry_datetime(path) {
+            Some(datetime) => base_options.last_modified_time(datetime),
+            None => base_options,
+        };
+        zip.start_file(name, options)
+            .map_err(|e| AppError::internal(format!("zip error: {e}")))?;
+        // Copy in chunks; reading a whole video into memory first would
+        // spike RSS by the size of the largest file in the session.
+        std::io::copy(&mut std::fs::File::open(path)?, &mut zip)?;
+    }
+    zip.finish()
+        .map_err(|e| AppError::internal(format!("zip error: {e}")))?;
+
+    Ok(std::fs::metadata(zip_path)?.len())
+}
+
+/// A file's mtime as a ZIP datetime, when it falls inside the format's
+/// representable range (1980-2107).
+fn entry_datetime(path: &Path) -> Option<zip::DateTime> {
+    use chrono::{Datelike, Timelike};
+
+    let mtime = std::fs::metadata(path).ok()?.modified().ok()?;
+    let stamp = chrono::DateTime::<chrono::Utc>::from(mtime);
+    zip::DateTime::from_date_and_time(
+        u16::try_from(stamp.year()).ok()?,
+        stamp.month() as u8,
+        stamp.day() as u8,
+        stamp.hour() as u8,
+        stamp.minute() as u8,
+        stamp.second() as u8,
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn format(id: &str, height: Option<u32>, vcodec: &str) -> YtDlpFormat {
+        YtDlpFormat {
+            format_id: id.to_string(),
+            url: Some(format!("https://cdn.example/{id}")),
+            http_headers: Default::default(),
+            ext: Some("mp4".to_string()),
+            height,
+            filesize: None,
+            filesize_approx: None,
+            vcodec: Some(vcodec.to_string()),
+            acodec: Some("aac".to_string()),
+            format_note: None,
+            language: None,
+            protocol: Some("https".to_string()),
+            manifest_url: None,
+        }
+    }
+
+    #[test]
+    fn audio_only_formats_surface_as_selectable_tracks() {
+        let mut en = format("audio-en", None, "none");
+        en.language = Some("en".to_string());
+        let mut es = format("audio-es", None, "none");
+        es.language = Some("es-MX".to_string());
+        let formats = vec![format("hd", Some(1080), "h264"), en, es];
+
+        let tracks = parse_audio_tracks(&formats);
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].format_id, "audio-en");
+        assert_eq!(tracks[0].label, "en");
+        assert_eq!(tracks[1].language.as_deref(), Some("es-MX"));
+
+        // Video formats never appear as audio tracks, and vice versa the
+        // video format list still filters audio out.
+        assert!(parse_available_formats(&formats, 0, &[])
+            .iter()
+            .all(|f| !f.format_id.starts_with("audio")));
+    }
+
+    #[test]
+    fn formats_are_video_only_sorted_and_capped() {
+        let mut input = vec![format("audio", None, "none")];
+        for h in [144, 240, 360, 480, 540, 720, 1080] {
+            input.push(format(&format!("f{h}"), Some(h), "h264"));
+        }
+        let parsed = parse_available_formats(&input, 5, &[]);
+        assert_eq!(parsed.len(), 5);
+        assert_eq!(parsed[0].height, Some(1080));
+        assert!(parsed.iter().all(|f| f.height.is_some()));
+
+        // The cap is configurable, and 0 means every distinct format.
+        assert_eq!(parse_available_formats(&input, 2, &[]).len(), 2);
+        assert_eq!(parse_available_formats(&input, 0, &[]).len(), 7);
+    }
+
+    #[test]
+    fn video_only_formats_are_surfaced_alongside_muxed_ones() {
+        let mut muxed = format("muxed720", Some(720), "h264");
+        muxed.format_id = "muxed720".to_string();
+        let mut video_only = format("vonly720", Some(720), "h264");
+        video_only.acodec = Some("none".to_string());
+
+        let parsed = parse_available_formats(&[muxed, video_only], 5, &[]);
+        // Same height, but one carries audio and one doesn't — both survive
+        // dedup so the UI can offer the mute variant.
+        assert_eq!(parsed.len(), 2);
+        assert!(!parsed[0].video_only);
+        assert!(parsed[1].video_only);
+        assert_eq!(parsed[1].label, "720p (video only)");
+    }
+
+    #[test]
+    fn mute_prefers_a_video_only_stream_and_strips_audio_without_reencoding() {
+        // When a video-only format exists, mute streams it directly.
+        assert!(VIDEO_ONLY_SELECTOR.starts_with("bestvideo[ext=mp4]"));
+        assert!(VIDEO_ONLY_SELECTOR.ends_with("/bestvideo"));
+
+        // Muxed-only videos go through ffmpeg: drop audio, copy video.
+        let args = mute_ffmpeg_args(Path::new("in.mp4"), Path::new("out.mp4"));
+        assert!(args.contains(&"-an".into()));
+        let copy_at = args.iter().position(|a| a == "-c").unwrap();
+        assert_eq!(args[copy_at + 1], "copy");
+    }
+
+    fn sized_option(id: &str, height: u32, filesize: Option<u64>) -> FormatOption {
+        FormatOption {
+            format_id: id.to_string(),
+            label: format!("{height}p"),
+            ext: "mp4".to_string(),
+            height: Some(height),
+            filesize,
+            video_only: false,
+            direct_url: None,
+        }
+    }
+
+    #[test]
+    fn target_filesize_picks_the_largest_format_that_fits() {
+        let formats = [
+            sized_option("hd", 1080, Some(40_000_000)),
+            sized_option("sd", 720, Some(12_000_000)),
+            sized_option("low", 360, Some(4_000_000)),
+        ];
+
+        // Largest under the target wins.
+        let pick = select_format_by_size(&formats, 15_000_000).unwrap();
+        assert_eq!(pick.format_id, "sd");
+        // When everything is over the target, the smallest is the least bad.
+        let pick = select_format_by_size(&formats, 1_000_000).unwrap();
+        assert_eq!(pick.format_id, "low");
+        // No formats at all yields None rather than a panic.
+        assert!(select_format_by_size(&[], 1_000_000).is_none());
+    }
+
+    #[test]
+    fn target_filesize_estimates_from_height_when_sizes_are_unknown() {
+        let formats = [
+            sized_option("hd", 1080, None),
+            sized_option("sd", 720, None),
+            sized_option("low", 360, None),
+        ];
+
+        // ~10 MB comfortably covers the 360p estimate but not 720p's.
+        let pick = select_format_by_size(&formats, 10_000_000).unwrap();
+        assert_eq!(pick.format_id, "low");
+        let pick = select_format_by_size(&formats, 20_000_000).unwrap();
+        assert_eq!(pick.format_id, "sd");
+
+        // Video-only formats are never auto-selected.
+        let mut muted = sized_option("vonly", 360, Some(1_000));
+        muted.video_only = true;
+        assert!(select_format_by_size(std::slice::from_ref(&muted), 10_000_000).is_none());
+    }
+
+    #[test]
+    fn selection_report_distinguishes_failed_urls_with_reasons() {
+        let urls = [
+            "https://www.tiktok.com/@u/video/1",
+            "https://www.tiktok.com/@u/video/2",
+            "https://www.tiktok.com/@u/video/3",
+        ];
+        let outcomes = [
+            None,
+            Some(classify_ytdlp_error("ERROR: Unsupported URL: junk", Some(1))),
+            None,
+        ];
+        let report: Vec<SelectedVideoResult> = urls
+            .iter()
+            .zip(&outcomes)
+            .map(|(url, outcome)| selected_video_result(url, outcome.as_ref()))
+            .collect();
+
+        assert_eq!(report[0].status, "ok");
+        assert!(report[0].error.is_none());
+        assert_eq!(report[1].status, "failed");
+        let reason = report[1].error.as_deref().unwrap();
+        assert!(reason.contains("not a downloadable TikTok video"), "{reason}");
+        assert_eq!(report[2].url, urls[2]);
+    }
+
+    #[test]
+    fn disk_space_guard_rejects_only_when_space_is_known_short() {
+        // Mocked availability: too little space trips the guard.
+        let err = ensure_disk_space(1_000_000, Some(10_000)).unwrap_err();
+        assert!(matches!(&err, AppError::BadRequest(m) if m.contains("insufficient disk space")));
+
+        // Plenty of space, or no way to tell, lets the download proceed.
+        assert!(ensure_disk_space(1_000_000, Some(10_000_000)).is_ok());
+        assert!(ensure_disk_space(1_000_000, None).is_ok());
+    }
+
+    #[test]
+    fn shared_service_clones_use_one_temp_dir() {
+        let config = AppConfig::from_env();
+        let service = std::sync::Arc::new(TikTokService::new(&config).unwrap());
+        let other = service.clone();
+        // Handlers share one service, so every request's session dirs land
+        // under the same process-lifetime temp dir.
+        assert_eq!(service.temp_dir_path(), other.temp_dir_path());
+        assert!(service.temp_dir_path().exists());
+    }
+
+    #[test]
+    fn browser_cookies_flag_reaches_every_ytdlp_command() {
+        let mut config = AppConfig::from_env();
+        config.cookies_from_browser = Some("firefox".to_string());
+        let service = TikTokService::new(&config).unwrap();
+        let cmd = service.base_command();
+        let args: Vec<String> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        let at = args.iter().position(|a| a == "--cookies-from-browser").unwrap();
+        assert_eq!(args[at + 1], "firefox");
+    }
+
+    #[test]
+    fn configured_scratch_dir_hosts_the_session_dirs() {
+        let scratch = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::from_env();
+        config.scratch_dir = Some(scratch.path().to_string_lossy().into_owned());
+        let service = TikTokService::new(&config).unwrap();
+
+        assert_eq!(service.temp_dir_path(), scratch.path());
+        let session = service.new_session_dir().unwrap();
+        assert!(session.starts_with(scratch.path()));
+        assert!(session.is_dir());
+    }
+
+    #[test]
+    fn cache_dir_setting_reaches_every_ytdlp_command() {
+        fn args_with_cache(value: Option<&str>) -> Vec<String> {
+            let mut config = AppConfig::from_env();
+            config.ytdlp_cache_dir = value.map(str::to_string);
+            let service = TikTokService::new(&config).unwrap();
+            service
+                .base_command()
+                .as_std()
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect()
+        }
+
+        // Unset: yt-dlp's default cache behavior, no flag at all.
+        let args = args_with_cache(None);
+        assert!(!args.iter().any(|a| a.contains("cache")));
+
+        // A path goes through --cache-dir.
+        let args = args_with_cache(Some("/var/cache/yt-dlp"));
+        let at = args.iter().position(|a| a == "--cache-dir").unwrap();
+        assert_eq!(args[at + 1], "/var/cache/yt-dlp");
+
+        // "none" disables the cache entirely.
+        let args = args_with_cache(Some("none"));
+        assert!(args.contains(&"--no-cache-dir".to_string()));
+    }
+
+    #[test]
+    fn remux_downloads_pass_the_container_to_ytdlp() {
+        let config = AppConfig::from_env();
+        let service = TikTokService::new(&config).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cmd = service.remux_command(
+            "https://www.tiktok.com/@u/video/1",
+            "best",
+            "mkv",
+            dir.path(),
+        );
+        let args: Vec<String> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        let at = args.iter().position(|a| a == "--remux-video").unwrap();
+        assert_eq!(args[at + 1], "mkv");
+    }
+
+    #[test]
+    fn cookie_file_is_private_and_deleted_after_use() {
+        let cookie_file = CookieFile::write("# Netscape HTTP Cookie File\n").unwrap();
+        let path = cookie_file.path().to_path_buf();
+        assert!(path.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        drop(cookie_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refresh_bypasses_a_fresh_cache_entry() {
+        let url = "https://www.tiktok.com/@cache/video/1";
+        let info = VideoInfo {
+            id: "1".to_string(),
+            title: "cached".to_string(),
+            description: String::new(),
+            uploader: String::new(),
+            duration: None,
+            view_count: None,
+            like_count: None,
+            comment_count: None,
+            upload_date: None,
+            thumbnail_url: None,
+            thumbnails: vec![],
+            formats: vec![],
+            subtitle_languages: vec![],
+            hashtags: vec![],
+            mentions: vec![],
+            effects: vec![],
+            audio_tracks: vec![],
+        };
+        METADATA_CACHE
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), (Instant::now(), info));
+
+        // Within the TTL a normal lookup is served from cache...
+        assert!(lookup_cached_info(url, false).is_some());
+        // ...but refresh forces a miss so yt-dlp runs again.
+        assert!(lookup_cached_info(url, true).is_none());
+    }
+
+    #[test]
+    fn tiktok_rate_limit_stderr_opens_the_breaker() {
+        assert!(is_tiktok_rate_limit(
+            "ERROR: [TikTok] 123: HTTP Error 429: Too Many Requests"
+        ));
+        assert!(is_tiktok_rate_limit("ERROR: unable to download: rate limit reached"));
+        assert!(!is_tiktok_rate_limit("ERROR: Video unavailable"));
+
+        trip_tiktok_breaker(Duration::from_secs(30));
+        assert!(breaker_remaining_secs().is_some());
+        // Close the breaker again so other tests can spawn yt-dlp.
+        *TIKTOK_BREAKER.lock().unwrap() = None;
+        assert!(breaker_remaining_secs().is_none());
+
+        // A zero cooldown disables the breaker entirely.
+        trip_tiktok_breaker(Duration::ZERO);
+        assert!(breaker_remaining_secs().is_none());
+    }
+
+    #[test]
+    fn subtitle_args_embed_requested_languages() {
+        let args = subtitle_args(Some("en,ar"));
+        assert!(args.contains(&"--write-subs".to_string()));
+        assert!(args.contains(&"--embed-subs".to_string()));
+        assert_eq!(args.last(), Some(&"en,ar".to_string()));
+        // Every caption track is embedded when no languages are given.
+        assert_eq!(subtitle_args(None).last(), Some(&"all".to_string()));
+    }
+
+    #[test]
+    fn best_quality_selector_muxes_with_a_fallback() {
+        // bestvideo+bestaudio needs ffmpeg to mux; /best covers videos with
+        // only pre-muxed formats, and BEST_SINGLE_SELECTOR is what ffmpeg-less
+        // servers degrade to.
+        assert_eq!(BEST_QUALITY_SELECTOR, "bestvideo+bestaudio/best");
+        assert_eq!(BEST_SINGLE_SELECTOR, "best");
+    }
+
+    #[test]
+    fn bulk_format_selector_falls_back_past_mp4() {
+        // Without the /best fallback, webm-only videos are silently dropped
+        // from profile archives.
+        let mut selectors = PROFILE_FORMAT_SELECTOR.split('/');
+        assert_eq!(selectors.next(), Some("best[ext=mp4]"));
+        assert_eq!(selectors.next(), Some("best"));
+    }
+
+    #[test]
+    fn playlist_lines_carry_pinned_marker_when_present() {
+        let stdout = concat!(
+            r#"{"id": "111", "title": "pinned clip", "is_pinned": true}"#,
+            "\n",
+            r#"{"id": "222", "title": "regular clip"}"#,
+            "\n",
+        );
+        let videos = parse_playlist_lines(stdout);
+        assert_eq!(videos.len(), 2);
+        assert_eq!(videos[0].pinned, Some(true));
+        assert_eq!(videos[1].pinned, None);
+    }
+
+    #[test]
+    fn thumbnails_are_sorted_smallest_first() {
+        let thumbs = vec![
+            YtDlpThumbnail {
+                id: Some("2".to_string()),
+                url: "mid".to_string(),
+                width: Some(360),
+                height: Some(640),
+            },
+            YtDlpThumbnail {
+                id: Some("0".to_string()),
+                url: "tiny".to_string(),
+                width: Some(72),
+                height: Some(128),
+            },
+            YtDlpThumbnail {
+                id: Some("1".to_string()),
+                url: "big".to_string(),
+                width: Some(720),
+                height: Some(1280),
+            },
+        ];
+        let parsed = parse_thumbnails(&thumbs);
+        assert_eq!(parsed.len(), 3);
+        let urls: Vec<&str> = parsed.iter().map(|t| t.url.as_str()).collect();
+        assert_eq!(urls, vec!["tiny", "mid", "big"]);
+    }
+
+    #[test]
+    fn best_thumbnail_prefers_largest() {
+        let thumbs = vec![
+            YtDlpThumbnail {
+                id: None,
+                url: "small".to_string(),
+                width: Some(100),
+                height: Some(100),
+            },
+            YtDlpThumbnail {
+                id: None,
+                url: "large".to_string(),
+                width: Some(720),
+                height: Some(1280),
+            },
+        ];
+        assert_eq!(extract_best_thumbnail_url(&thumbs), Some("large".to_string()));
+        assert_eq!(extract_best_thumbnail_url(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn run_bounded_preserves_order() {
+        let results = run_bounded(
+            (0..8).map(|i| i.to_string()),
+            3,
+            |item| async move {
+                // Finish out of order to prove results are re-ordered.
+                let n: u64 = item.parse().unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(20 - 2 * n)).await;
+                Ok::<_, AppError>(n)
+            },
+        )
+        .await;
+        let values: Vec<u64> = results.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(values, (0..8).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn run_bounded_respects_concurrency_cap() {
+        static CURRENT: AtomicUsize = AtomicUsize::new(0);
+        static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+        let _ = run_bounded((0..10).map(|i| i.to_string()), 2, |_| async {
+            let now = CURRENT.fetch_add(1, Ordering::SeqCst) + 1;
+            PEAK.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            CURRENT.fetch_sub(1, Ordering::SeqCst);
+            Ok::<_, AppError>(())
+        })
+        .await;
+
+        assert!(PEAK.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn zip_entries_keep_the_source_file_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let video = dir.path().join("user_title_123.mp4");
+        std::fs::write(&video, b"video bytes").unwrap();
+        // Pretend yt-dlp stamped the upload date.
+        apply_upload_mtime(&video, Some("20240115"));
+
+        let zip_path = dir.path().join("out.zip");
+        create_zip_archive(&[video], &zip_path, ZipNaming::Original).unwrap();
+
+        let file = std::fs::File::open(&zip_path).unwrap();
+        let mut archive = zip::ZipArchive::new(file).unwrap();
+        let modified = archive.by_index(0).unwrap().last_modified();
+        assert_eq!(modified.year(), 2024);
+        assert_eq!(modified.month(), 1);
+        assert_eq!(modified.day(), 15);
+    }
+
+    #[test]
+    fn bundle_zip_holds_one_entry_per_rendition() {
+        let dir = tempfile::tempdir().unwrap();
+        let video = dir.path().join("user_title_123.mp4");
+        let audio = dir.path().join("user_title_123.mp3");
+        std::fs::write(&video, b"video bytes").unwrap();
+        std::fs::write(&audio, b"audio bytes").unwrap();
+
+        let zip_path = dir.path().join("bundle.zip");
+        create_zip_archive(&[video, audio], &zip_path, ZipNaming::Original).unwrap();
+
+        let file = std::fs::File::open(&zip_path).unwrap();
+        let archive = zip::ZipArchive::new(file).unwrap();
+        assert_eq!(archive.len(), 2);
+        let names: Vec<&str> = archive.file_names().collect();
+        assert!(names.contains(&"user_title_123.mp4"));
+        assert!(names.contains(&"user_title_123.mp3"));
+    }
+
+    #[tokio::test]
+    async fn one_profile_never_exceeds_its_concurrency_cap() {
+        static CURRENT: AtomicUsize = AtomicUsize::new(0);
+        static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+        let gate = profile_semaphore("gate_test_user", 2);
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let gate = Arc::clone(&gate);
+                tokio::spawn(async move {
+                    let _permit = gate.acquire().await.unwrap();
+                    let now = CURRENT.fetch_add(1, Ordering::SeqCst) + 1;
+                    PEAK.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                    CURRENT.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(PEAK.load(Ordering::SeqCst) <= 2);
+
+        // A different profile gets its own gate and doesn't contend.
+        let other = profile_semaphore("another_user", 2);
+        assert!(!Arc::ptr_eq(&gate, &other));
+        // Lookups are case-insensitive, matching the allow/deny lists.
+        let same = profile_semaphore("Gate_Test_User", 2);
+        assert!(Arc::ptr_eq(&gate, &same));
+    }
+
+    #[test]
+    fn numbered_names_are_zero_padded_and_width_adapts() {
+        assert_eq!(numbered_entry_name(0, 12, "a.mp4"), "001_a.mp4");
+        assert_eq!(numbered_entry_name(11, 12, "b.mp4"), "012_b.mp4");
+        assert_eq!(numbered_entry_name(0, 1234, "c.mp4"), "0001_c.mp4");
+    }
+
+    #[test]
+    fn numbered_zip_entries_follow_input_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("zeta.mp4");
+        let second = dir.path().join("alpha.mp4");
+        std::fs::write(&first, b"1").unwrap();
+        std::fs::write(&second, b"2").unwrap();
+
+        let zip_path = dir.path().join("out.zip");
+        create_zip_archive(&[first, second], &zip_path, ZipNaming::Numbered).unwrap();
+
+        let file = std::fs::File::open(&zip_path).unwrap();
+        let archive = zip::ZipArchive::new(file).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert!(names.contains(&"001_zeta.mp4"));
+        assert!(names.contains(&"002_alpha.mp4"));
+    }
+
+    #[test]
+    fn identically_named_files_get_distinct_zip_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let first_dir = dir.path().join("a");
+        let second_dir = dir.path().join("b");
+        std::fs::create_dir_all(&first_dir).unwrap();
+        std::fs::create_dir_all(&second_dir).unwrap();
+        // Two different videos whose titles sanitized to the same name.
+        let first = first_dir.join("dance_video.mp4");
+        let second = second_dir.join("dance_video.mp4");
+        std::fs::write(&first, b"1").unwrap();
+        std::fs::write(&second, b"2").unwrap();
+
+        let zip_path = dir.path().join("out.zip");
+        create_zip_archive(&[first, second], &zip_path, ZipNaming::Original).unwrap();
+
+        let file = std::fs::File::open(&zip_path).unwrap();
+        let archive = zip::ZipArchive::new(file).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"dance_video.mp4"));
+        assert!(names.contains(&"dance_video_1.mp4"));
+    }
+
+    #[tokio::test]
+    async fn a_slow_listing_returns_what_it_collected_flagged_truncated() {
+        let config = AppConfig::from_env();
+        let service = TikTokService::new(&config).unwrap();
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo first; echo second; sleep 5; echo third"]);
+        let (lines, truncated) = service
+            .run_ytdlp_lines(cmd, Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert!(truncated);
+        assert_eq!(lines, vec!["first".to_string(), "second".to_string()]);
+
+        // A listing that finishes inside the limit is complete and says so.
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo only"]);
+        let (lines, truncated) = service
+            .run_ytdlp_lines(cmd, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(!truncated);
+        assert_eq!(lines, vec!["only".to_string()]);
+    }
+
+    #[test]
+    fn a_repeat_profile_request_inside_the_ttl_skips_the_download() {
+        let cache = Mutex::new(HashMap::new());
+        let dir = tempfile::tempdir().unwrap();
+        let zip = dir.path().join("cached.zip");
+        std::fs::write(&zip, b"zip bytes").unwrap();
+        let parts = vec![(zip.clone(), 9u64)];
+
+        remember_profile_zip(&cache, "user|false|false|Original|None".to_string(), &parts, 8);
+        // The identical request hits the cache; only the first one pays
+        // for the download.
+        assert_eq!(
+            cached_profile_zip(&cache, "user|false|false|Original|None", Duration::from_secs(60)),
+            Some(parts)
+        );
+        // Different options miss it.
+        assert_eq!(
+            cached_profile_zip(&cache, "user|true|false|Original|None", Duration::from_secs(60)),
+            None
+        );
+        // An expired entry is dropped and its file deleted.
+        assert_eq!(
+            cached_profile_zip(&cache, "user|false|false|Original|None", Duration::ZERO),
+            None
+        );
+        assert!(!zip.exists());
+    }
+
+    #[test]
+    fn capacity_eviction_deletes_the_least_recently_used_archive() {
+        let cache = Mutex::new(HashMap::new());
+        let dir = tempfile::tempdir().unwrap();
+        let mut zips = Vec::new();
+        for i in 0..3 {
+            let zip = dir.path().join(format!("{i}.zip"));
+            std::fs::write(&zip, b"zip").unwrap();
+            remember_profile_zip(&cache, format!("key-{i}"), &[(zip.clone(), 3)], 2);
+            zips.push(zip);
+        }
+        // Capacity 2: inserting the third entry evicted the oldest and
+        // removed its file.
+        assert!(!zips[0].exists());
+        assert!(zips[1].exists() && zips[2].exists());
+        assert!(cached_profile_zip(&cache, "key-0", Duration::from_secs(60)).is_none());
+        assert!(cached_profile_zip(&cache, "key-2", Duration::from_secs(60)).is_some());
+    }
+
+    #[test]
+    fn token_templates_translate_to_ytdlp_fields() {
+        assert_eq!(
+            ytdlp_output_template("{date}_{title}").unwrap(),
+            "%(upload_date)s_%(title)s.%(ext)s"
+        );
+        assert_eq!(
+            ytdlp_output_template("{index} - {uploader} - {id}").unwrap(),
+            "%(playlist_index)s - %(uploader)s - %(id)s.%(ext)s"
+        );
+        // A literal percent cannot smuggle in a field expansion.
+        assert_eq!(
+            ytdlp_output_template("100%(id)s {id}").unwrap(),
+            "100%%(id)s %(id)s.%(ext)s"
+        );
+    }
+
+    #[test]
+    fn bad_templates_are_rejected_up_front() {
+        for bad in ["{vid}", "{title", "", "clips/{id}", "..\\{id}"] {
+            assert!(ytdlp_output_template(bad).is_err(), "{bad:?} should be rejected");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_zip_build_leaves_the_reactor_free_for_other_work() {
+        let session = tempfile::tempdir().unwrap();
+        let downloads = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::from_env();
+        config.downloads_dir = downloads.path().to_string_lossy().into_owned();
+        let service = Arc::new(TikTokService::new(&config).unwrap());
+
+        // Poorly compressible payload so deflating it takes real time; an
+        // inline build would pin this test's single reactor thread for the
+        // whole compression.
+        let mut payload = vec![0u8; 24 * 1024 * 1024];
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for byte in payload.iter_mut() {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            *byte = (state >> 56) as u8;
+        }
+        let big = session.path().join("user_big_1.mp4");
+        std::fs::write(&big, payload).unwrap();
+
+        let zip_path = downloads.path().join("big.zip");
+        let build = {
+            let service = Arc::clone(&service);
+            let zip_path = zip_path.clone();
+            tokio::spawn(async move {
+                service
+                    .build_zip(&[big], &zip_path, ZipNaming::Original)
+                    .await
+            })
+        };
+        tokio::task::yield_now().await;
+
+        // Stand-in for a concurrent health check: a short timer on the same
+        // (current-thread) runtime must fire roughly on time while the
+        // archive compresses on the blocking pool.
+        let started = std::time::Instant::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(
+            started.elapsed() < Duration::from_millis(250),
+            "reactor was stalled for {:?}",
+            started.elapsed()
+        );
+
+        build.await.unwrap().unwrap();
+        assert!(zip_path.exists());
+    }
+
+    #[tokio::test]
+    async fn small_split_thresholds_produce_multiple_named_parts() {
+        let session = tempfile::tempdir().unwrap();
+        let downloads = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::from_env();
+        config.downloads_dir = downloads.path().to_string_lossy().into_owned();
+        let service = TikTokService::new(&config).unwrap();
+
+        let mut files = Vec::new();
+        for i in 0..5 {
+            let path = session.path().join(format!("clip_{i}.mp4"));
+            std::fs::write(&path, vec![b'x'; 400]).unwrap();
+            files.push(path);
+        }
+
+        let parts = service
+            .zip_session_split("user", &files, ZipNaming::Original, 1000)
+            .await
+            .unwrap();
+
+        // Five 400-byte files under a 1000-byte cap: two pairs plus a
+        // remainder.
+        assert_eq!(parts.len(), 3);
+        for (index, (path, size)) in parts.iter().enumerate() {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            assert!(name.starts_with("tiktok_user_"), "unexpected name {name}");
+            assert!(
+                name.ends_with(&format!("_part{:03}.zip", index + 1)),
+                "unexpected name {name}"
+            );
+            assert_eq!(*size, std::fs::metadata(path).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn a_file_over_the_split_limit_still_gets_its_own_part() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = dir.path().join("big.mp4");
+        let small = dir.path().join("small.mp4");
+        std::fs::write(&big, vec![b'x'; 2000]).unwrap();
+        std::fs::write(&small, vec![b'x'; 100]).unwrap();
+
+        let groups = split_files_by_size(&[big.clone(), small.clone()], 1000);
+        assert_eq!(groups, vec![vec![big], vec![small]]);
+    }
+
+    #[test]
+    fn numbered_entries_follow_the_requested_chronological_order() {
+        fn dated(id: &str, date: Option<&str>) -> ProfileVideoInfo {
+            ProfileVideoInfo {
+                id: id.to_string(),
+                url: format!("https://www.tiktok.com/@user/video/{id}"),
+                title: format!("video {id}"),
+                duration: None,
+                view_count: None,
+                like_count: None,
+                upload_date: date.map(str::to_string),
+                thumbnail_url: None,
+                thumbnails: vec![],
+                pinned: None,
+            }
+        }
+
+        let videos = vec![
+            dated("111", Some("20240105")),
+            dated("222", Some("20240101")),
+            dated("333", None),
+        ];
+        let mut files = vec![
+            PathBuf::from("user_b_222.mp4"),
+            PathBuf::from("user_c_333.mp4"),
+            PathBuf::from("user_a_111.mp4"),
+        ];
+
+        order_files_chronologically(&mut files, &videos, DownloadOrder::Newest);
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("user_a_111.mp4"),
+                PathBuf::from("user_b_222.mp4"),
+                // Undated videos sink to the end.
+                PathBuf::from("user_c_333.mp4"),
+            ]
+        );
+        // The numeric prefixes then freeze that order into the names.
+        assert_eq!(numbered_entry_name(0, files.len(), "user_a_111.mp4"), "001_user_a_111.mp4");
+
+        order_files_chronologically(&mut files, &videos, DownloadOrder::Oldest);
+        assert_eq!(files[0], PathBuf::from("user_b_222.mp4"));
+        assert_eq!(files[1], PathBuf::from("user_a_111.mp4"));
+        assert_eq!(files[2], PathBuf::from("user_c_333.mp4"));
+    }
+
+    #[test]
+    fn the_manifest_lists_each_video_and_its_archive_entry() {
+        fn listed(id: &str, title: &str) -> ProfileVideoInfo {
+            ProfileVideoInfo {
+                id: id.to_string(),
+                url: format!("https://www.tiktok.com/@user/video/{id}"),
+                title: title.to_string(),
+                duration: None,
+                view_count: None,
+                like_count: None,
+                upload_date: Some("20240101".to_string()),
+                thumbnail_url: None,
+                thumbnails: vec![],
+                pinned: None,
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let video = dir.path().join("user_dance_111.mp4");
+        std::fs::write(&video, b"fake video bytes").unwrap();
+        let videos = vec![listed("111", "dance"), listed("222", "gone")];
+
+        let manifest_files =
+            write_session_manifest(dir.path(), &videos, std::slice::from_ref(&video)).unwrap();
+        let mut files = vec![video];
+        files.extend(manifest_files);
+        let zip_path = dir.path().join("out.zip");
+        create_zip_archive(&files, &zip_path, ZipNaming::Original).unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&zip_path).unwrap()).unwrap();
+        let names: Vec<String> = archive.file_names().map(str::to_string).collect();
+        assert!(names.contains(&"manifest.json".to_string()));
+        assert!(names.contains(&"index.txt".to_string()));
+
+        let manifest: serde_json::Value =
+            serde_json::from_reader(archive.by_name("manifest.json").unwrap()).unwrap();
+        assert_eq!(manifest[0]["filename"], "user_dance_111.mp4");
+        assert_eq!(manifest[0]["title"], "dance");
+        assert_eq!(manifest[0]["upload_date"], "20240101");
+        // The listing knew about a second video that never downloaded; its
+        // line survives with no filename.
+        assert_eq!(manifest[1]["filename"], serde_json::Value::Null);
+        assert_eq!(
+            manifest[1]["url"],
+            "https://www.tiktok.com/@user/video/222"
+        );
+    }
+
+    #[test]
+    fn zip_archive_contains_video_and_metadata_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let video = dir.path().join("user_title_123.mp4");
+        let sidecar = dir.path().join("user_title_123.info.json");
+        std::fs::write(&video, b"fake video bytes").unwrap();
+        std::fs::write(&sidecar, b"{\"id\":\"123\"}").unwrap();
+
+        let zip_path = dir.path().join("out.zip");
+        create_zip_archive(&[video, sidecar], &zip_path, ZipNaming::Original).unwrap();
+
+        let file = std::fs::File::open(&zip_path).unwrap();
+        let archive = zip::ZipArchive::new(file).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert!(names.contains(&"user_title_123.mp4"));
+        assert!(names.contains(&"user_title_123.info.json"));
+    }
+
+    #[test]
+    fn watermark_filter_graph_places_and_fades_the_overlay() {
+        let graph = watermark_filter(WatermarkPosition::BottomRight, 0.5);
+        assert!(graph.contains("colorchannelmixer=aa=0.5"));
+        assert!(graph.ends_with("overlay=W-w-10:H-h-10"));
+
+        let graph = watermark_filter(WatermarkPosition::TopLeft, 1.0);
+        assert!(graph.ends_with("overlay=10:10"));
+
+        // The graph rides into ffmpeg as one -filter_complex argument.
+        let args = watermark_ffmpeg_args(
+            Path::new("in.mp4"),
+            Path::new("logo.png"),
+            &graph,
+            Path::new("out.mp4"),
+        );
+        let at = args.iter().position(|a| a == "-filter_complex").unwrap();
+        assert_eq!(args[at + 1], std::ffi::OsString::from(graph));
+    }
+
+    #[test]
+    fn strip_metadata_args_drop_metadata_without_reencoding() {
+        let args = strip_metadata_ffmpeg_args(Path::new("in.mp4"), Path::new("out.mp4"));
+        let strs: Vec<&str> = args.iter().filter_map(|a| a.to_str()).collect();
+        let map_at = strs.iter().position(|a| *a == "-map_metadata").unwrap();
+        assert_eq!(strs[map_at + 1], "-1");
+        // Copy codecs: scrubbing must not trigger a re-encode.
+        let c_at = strs.iter().position(|a| *a == "-c").unwrap();
+        assert_eq!(strs[c_at + 1], "copy");
+    }
+
+    #[tokio::test]
+    async fn jitter_spaces_sequential_calls_apart() {
+        let started = Instant::now();
+        jitter_wait(30).await;
+        jitter_wait(30).await;
+        // Two calls each sleep at least half the configured maximum.
+        assert!(started.elapsed() >= Duration::from_millis(30));
+
+        // Zero jitter must not sleep at all.
+        let started = Instant::now();
+        jitter_wait(0).await;
+        assert!(started.elapsed() < Duration::from_millis(5));
+    }
+
+    #[test]
+    fn effect_ids_deserialize_under_either_name_and_default_empty() {
+        let with_effect: YtDlpVideoInfo =
+            serde_json::from_str(r#"{"id": "1", "_effect": ["green_screen"]}"#).unwrap();
+        assert_eq!(with_effect.effect_ids, vec!["green_screen"]);
+
+        let without: YtDlpVideoInfo = serde_json::from_str(r#"{"id": "2"}"#).unwrap();
+        assert!(without.effect_ids.is_empty());
+    }
+
+    #[test]
+    fn manifest_selection_prefers_hls_and_skips_progressive() {
+        let mut hls = format("hls-720", Some(720), "h264");
+        hls.protocol = Some("m3u8_native".to_string());
+        hls.manifest_url = Some("https://cdn.example/master.m3u8".to_string());
+        let mut dash = format("dash-1080", Some(1080), "h264");
+        dash.protocol = Some("http_dash_segments".to_string());
+        let progressive = format("prog-1080", Some(1080), "h264");
+
+        let formats = vec![progressive.clone(), dash, hls];
+        let (chosen, kind) = select_manifest_format(&formats).unwrap();
+        assert_eq!(kind, "hls");
+        assert_eq!(chosen.format_id, "hls-720");
+
+        // Only progressive formats: nothing to offer a player.
+        assert!(select_manifest_format(&[progressive]).is_none());
+    }
+
+    #[test]
+    fn hashtags_and_mentions_are_extracted_from_descriptions() {
+        let (tags, mentions) =
+            extract_tags("dance with @friend.01 #fyp #dance #fyp more at @friend.01");
+        assert_eq!(tags, vec!["fyp", "dance"]);
+        assert_eq!(mentions, vec!["friend.01"]);
+
+        // Unicode hashtags survive with the marker stripped.
+        let (tags, _) = extract_tags("#رقص #ダンス2024");
+        assert_eq!(tags, vec!["رقص", "ダンス2024"]);
+
+        // A bare '#' or '@' tags nothing.
+        let (tags, mentions) = extract_tags("# nothing @ here");
+        assert!(tags.is_empty());
+        assert!(mentions.is_empty());
+    }
+
+    #[test]
+    fn multi_line_ytdlp_output_parses_the_first_entry() {
+        let stdout = "{\"id\": \"111\", \"title\": \"first\"}\n{\"id\": \"222\", \"title\": \"second\"}\n";
+        let info = extract_video_metadata(stdout).unwrap();
+        assert_eq!(info.id, "111");
+        assert_eq!(info.title.as_deref(), Some("first"));
+
+        // Output that isn't JSON on any line still errors.
+        assert!(extract_video_metadata("WARNING: nope\n").is_err());
+    }
+
+    #[test]
+    fn playlist_shaped_output_yields_a_listing() {
+        let stdout = r#"{"_type": "playlist", "id": "sound-123", "title": "original sound", "entries": [
+            {"id": "111", "title": "first clip", "duration": 12.0},
+            {"id": "222", "url": "https://www.tiktok.com/@u/video/222"}
+        ]}"#;
+        let raw = extract_video_metadata(stdout).unwrap();
+        assert!(raw.is_playlist());
+
+        let videos: Vec<_> = raw.entries.into_iter().map(playlist_entry_to_video).collect();
+        assert_eq!(videos.len(), 2);
+        assert_eq!(videos[0].id, "111");
+        assert_eq!(videos[0].title, "first clip");
+        assert_eq!(videos[0].url, "https://www.tiktok.com/video/111");
+        assert_eq!(videos[1].url, "https://www.tiktok.com/@u/video/222");
+        assert_eq!(videos[1].title, "Untitled");
+    }
+
+    /// tikwm-style mock serving one canned JSON response.
+    async fn fallback_api(body: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let mut buf = [0u8; 4096];
+            let _ = socket.read(&mut buf).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+        format!("http://{addr}/")
+    }
+
+    #[tokio::test]
+    async fn fallback_api_response_maps_onto_video_info() {
+        let url = fallback_api(
+            r#"{"code": 0, "data": {"id": "789", "title": "Broken day #backup",
+                "play": "https://fallback.example/nowm.mp4",
+                "cover": "https://fallback.example/cover.jpg",
+                "duration": 14.0, "play_count": 1000, "digg_count": 50,
+                "author": {"unique_id": "someuser"}}}"#,
+        )
+        .await;
+        let info = fetch_fallback_info(&url, "https://www.tiktok.com/@u/video/789")
+            .await
+            .unwrap();
+        assert_eq!(info.id, "789");
+        assert_eq!(info.uploader, "someuser");
+        assert_eq!(info.hashtags, vec!["backup"]);
+        assert_eq!(info.thumbnail_url.as_deref(), Some("https://fallback.example/cover.jpg"));
+        assert_eq!(info.formats.len(), 1);
+        assert_eq!(info.formats[0].format_id, "fallback");
+        assert_eq!(
+            info.formats[0].direct_url.as_deref(),
+            Some("https://fallback.example/nowm.mp4")
+        );
+    }
+
+    #[test]
+    fn the_best_selector_reflects_codec_and_container_preferences() {
+        let codecs = vec!["h264".to_string(), "vp9".to_string()];
+        assert_eq!(
+            preference_selector(&codecs, Some("mp4")),
+            "bestvideo[vcodec^=h264][ext=mp4]+bestaudio\
+             /bestvideo[vcodec^=h264]+bestaudio\
+             /bestvideo[vcodec^=vp9][ext=mp4]+bestaudio\
+             /bestvideo[vcodec^=vp9]+bestaudio\
+             /bestvideo+bestaudio/best"
+        );
+        // A container preference alone still gets its alternative, and no
+        // preferences at all leaves the stock selector untouched.
+        assert!(preference_selector(&[], Some("mp4"))
+            .starts_with("bestvideo[ext=mp4]+bestaudio/"));
+        assert_eq!(preference_selector(&[], None), BEST_QUALITY_SELECTOR);
+    }
+
+    #[test]
+    fn preferred_codecs_win_the_per_resolution_dedup() {
+        let input = vec![
+            format("hevc-720", Some(720), "hevc"),
+            format("h264-720", Some(720), "h264.main"),
+        ];
+        let parsed = parse_available_formats(&input, 0, &["h264".to_string()]);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].format_id, "h264-720");
+
+        // Without a preference the listing order decides, as before.
+        let parsed = parse_available_formats(&input, 0, &[]);
+        assert_eq!(parsed[0].format_id, "hevc-720");
+    }
+
+    #[tokio::test]
+    async fn a_missing_ffmpeg_reads_as_service_unavailable() {
+        let err = probe_ffmpeg("definitely-not-ffmpeg").await.unwrap_err();
+        assert!(matches!(err, AppError::ServiceUnavailable(_)));
+        assert!(err.to_string().contains("ffmpeg"));
+    }
+
+    #[test]
+    fn the_ffmpeg_banner_parses_down_to_a_version() {
+        assert_eq!(
+            parse_ffmpeg_version(
+                "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023 the FFmpeg developers\n"
+            ),
+            Some("6.1.1-3ubuntu5".to_string())
+        );
+        assert_eq!(parse_ffmpeg_version("not a banner"), None);
+    }
+
+    #[test]
+    fn the_date_floor_becomes_a_dateafter_flag() {
+        let config = AppConfig::from_env();
+        let service = TikTokService::new(&config).unwrap();
+
+        let mut cmd = service.base_command();
+        apply_date_filter(&mut cmd, Some("20240101"));
+        let args: Vec<String> = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        let position = args
+            .iter()
+            .position(|a| a == "--dateafter")
+            .expect("date filter present");
+        assert_eq!(args[position + 1], "20240101");
+        assert!(args.iter().any(|a| a == "--no-overwrites"));
+
+        let mut cmd = service.base_command();
+        apply_date_filter(&mut cmd, None);
+        assert!(!cmd
+            .as_std()
+            .get_args()
+            .any(|a| a == "--dateafter" || a == "--no-overwrites"));
+    }
+
+    #[test]
+    fn the_age_gate_bypass_rides_on_every_ytdlp_command() {
+        fn args_for(bypass: bool) -> Vec<String> {
+            let mut config = AppConfig::from_env();
+            config.bypass_age_gate = bypass;
+            let service = TikTokService::new(&config).unwrap();
+            service
+                .base_command()
+                .as_std()
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect()
+        }
+
+        let args = args_for(true);
+        let position = args
+            .iter()
+            .position(|a| a == "--extractor-args")
+            .expect("extractor args present");
+        assert_eq!(args[position + 1], AGE_GATE_EXTRACTOR_ARGS);
+
+        assert!(!args_for(false).iter().any(|a| a == "--extractor-args"));
+    }
+
+    #[test]
+    fn the_streaming_muxer_writes_a_fragmented_mp4_to_stdout() {
+        let mut headers = std::collections::HashMap::new();
+        headers.insert("Referer".to_string(), "https://www.tiktok.com/".to_string());
+        let args = mux_ffmpeg_args("https://cdn.example/audio.m4a", &headers);
+
+        // Video rides in on stdin; audio is ffmpeg's own fetch with the
+        // CDN's headers attached.
+        assert!(args.windows(2).any(|w| w == ["-i", "pipe:0"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-headers", "Referer: https://www.tiktok.com/\r\n"]));
+        assert!(args.windows(2).any(|w| w == ["-i", "https://cdn.example/audio.m4a"]));
+        // Fragmented output is what makes the result streamable at all.
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-movflags", "frag_keyframe+empty_moov"]));
+        assert_eq!(args.last().map(String::as_str), Some("pipe:1"));
+    }
+
+    #[test]
+    fn the_mux_audio_pick_honors_an_explicit_track() {
+        let mut worse = format("audio-low", None, "none");
+        worse.acodec = Some("mp4a.40.5".to_string());
+        let mut best = format("audio-high", None, "none");
+        best.acodec = Some("mp4a.40.2".to_string());
+        let formats = vec![format("hd", Some(1080), "h264"), worse, best];
+
+        // No request: the last (best) audio-only entry wins.
+        assert_eq!(pick_mux_audio(&formats, None).unwrap().format_id, "audio-high");
+        // An explicit dub is taken verbatim, even when a "better" one exists.
+        assert_eq!(
+            pick_mux_audio(&formats, Some("audio-low")).unwrap().format_id,
+            "audio-low"
+        );
+        assert!(matches!(
+            pick_mux_audio(&formats, Some("audio-de")),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn the_mux_video_selector_never_asks_for_a_merge() {
+        let mut config = AppConfig::from_env();
+        config.preferred_codecs = vec!["h264".to_string()];
+        config.prefer_container = Some("mp4".to_string());
+        let service = TikTokService::new(&config).unwrap();
+
+        let selector = service.best_video_only_selector();
+        assert_eq!(
+            selector,
+            "bestvideo[vcodec^=h264][ext=mp4]/bestvideo[vcodec^=h264]/bestvideo[ext=mp4]/bestvideo"
+        );
+        // A '+' would make yt-dlp merge on its own side of the pipe, which
+        // is exactly what the mux pipeline exists to avoid.
+        assert!(!selector.contains('+'));
+
+        config.preferred_codecs.clear();
+        config.prefer_container = None;
+        let service = TikTokService::new(&config).unwrap();
+        assert_eq!(service.best_video_only_selector(), VIDEO_ONLY_SELECTOR);
+    }
+
+    #[tokio::test]
+    async fn the_mux_pipeline_streams_the_second_stage_output() {
+        // Stand-ins for yt-dlp and ffmpeg: stage one emits the "video"
+        // bytes, stage two transforms what arrives on its stdin. This is
+        // the exact pipe handoff merged selectors now ride.
+        let mut first = Command::new("sh");
+        first
+            .args(["-c", "printf 'video-bytes'"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let mut first = first.spawn().unwrap();
+        let pipe: Stdio = first.stdout.take().unwrap().try_into().unwrap();
+
+        let mut second = Command::new("tr");
+        second
+            .args(["a-z", "A-Z"])
+            .stdin(pipe)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        let second = second.spawn().unwrap();
+
+        let stream = VideoStream::pipeline(vec![first], second, 8 * 1024, None).unwrap();
+        let chunks: Vec<_> = stream.collect().await;
+        let bytes: Vec<u8> = chunks.into_iter().flat_map(|c| c.unwrap()).collect();
+        assert_eq!(bytes, b"VIDEO-BYTES");
+    }
+
+    #[tokio::test]
+    async fn a_stalled_remote_is_cut_off_by_the_client_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Accept the connection but never answer.
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop(socket);
+        });
+
+        let client = build_http_client(1, 1);
+        let err = client
+            .get(format!("http://{addr}/slow"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout(), "expected a timeout, got {err}");
+    }
+
+    #[tokio::test]
+    async fn fallback_api_error_codes_read_as_unavailable() {
+        let url = fallback_api(r#"{"code": -1, "msg": "parsing failed"}"#).await;
+        let err = fetch_fallback_info(&url, "https://www.tiktok.com/@u/video/789")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::VideoUnavailable(_)));
+    }
+
+    #[test]
+    fn only_unclassified_ytdlp_failures_reach_the_fallback() {
+        // The simulated "TikTok broke yt-dlp" failure: an unclassified
+        // extraction error.
+        assert!(is_extraction_error(&AppError::internal(
+            "Unable to extract webpage video data".to_string()
+        )));
+        assert!(!is_extraction_error(&AppError::NotFound("gone".to_string())));
+        assert!(!is_extraction_error(&AppError::TooManyRequests {
+            message: "rate limited".to_string(),
+            retry_after: 60,
+        }));
+    }
+
+    #[tokio::test]
+    async fn concurrent_identical_lookups_coalesce_to_one_fetch() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let cached = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let fetches = fetches.clone();
+            let cached = cached.clone();
+            tasks.push(tokio::spawn(async move {
+                single_flight("https://www.tiktok.com/@u/video/1", || async {
+                    // The cache check every caller performs under the lock.
+                    if cached.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    // The expensive part only the leader should reach.
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    cached.store(true, Ordering::SeqCst);
+                })
+                .await;
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        // The per-URL lock is cleaned up once the stampede drains.
+        assert!(!INFLIGHT_INFO
+            .lock()
+            .unwrap()
+            .contains_key("https://www.tiktok.com/@u/video/1"));
+    }
+
+    #[test]
+    fn photo_carousel_slides_come_back_in_order_at_full_resolution() {
+        let stdout = r#"{"_type": "playlist", "id": "photo-1", "entries": [
+            {"id": "1", "thumbnails": [
+                {"url": "https://cdn/1-small.jpg", "width": 100, "height": 100},
+                {"url": "https://cdn/1-full.jpg", "width": 1080, "height": 1920}
+            ]},
+            {"id": "2", "thumbnails": [{"url": "https://cdn/2-full.jpg", "width": 1080, "height": 1920}]},
+            {"id": "3", "thumbnails": [{"url": "https://cdn/3-full.jpg", "width": 1080, "height": 1920}]}
+        ]}"#;
+        let raw = extract_video_metadata(stdout).unwrap();
+        let images = photo_image_urls(&raw);
+        assert_eq!(
+            images,
+            vec![
+                "https://cdn/1-full.jpg",
+                "https://cdn/2-full.jpg",
+                "https://cdn/3-full.jpg"
+            ]
+        );
+        // An out-of-range index has nothing to resolve to.
+        assert!(images.get(3).is_none());
+    }
+
+    #[test]
+    fn classify_private_video() {
+        let err = classify_ytdlp_error("ERROR: Private video. Log in to view", Some(1));
+        assert!(matches!(err, AppError::Unauthorized(_)));
+        assert_eq!(err.status_code().as_u16(), 401);
+    }
+
+    #[test]
+    fn classify_unavailable_videos_as_404() {
+        for stderr in [
+            "ERROR: [TikTok] 712345: The video has been removed",
+            "ERROR: HTTP Error 404: Not Found",
+        ] {
+            let err = classify_ytdlp_error(stderr, Some(1));
+            assert!(matches!(err, AppError::VideoUnavailable(_)), "{stderr}");
+            assert_eq!(err.status_code().as_u16(), 404, "{stderr}");
+        }
+
+        // Everything unmatched stays a 500 so genuine breakage is visible.
+        let err = classify_ytdlp_error("ERROR: something exploded", Some(1));
+        assert_eq!(err.status_code().as_u16(), 500);
+    }
+
+    #[test]
+    fn extractor_breakage_stays_internal_so_the_fallback_can_fire() {
+        // The canonical "TikTok broke yt-dlp" stderr. It must not be
+        // mistaken for a removed video: the post is fine, the extractor
+        // isn't, and that distinction is what arms the fallback API.
+        let err = classify_ytdlp_error(
+            "ERROR: [TikTok] 712345: Unable to extract webpage video data",
+            Some(1),
+        );
+        assert!(matches!(err, AppError::Internal { .. }));
+        assert!(is_extraction_error(&err));
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    process::{Child, ChildStdout},
+};
+
+/// Adapts a yt-dlp child process writing to stdout into a byte `Stream`
+/// suitable for an axum response body. Holding the `Child` keeps the process
+/// alive for as long as the client is reading; dropping the stream (client
+/// disconnect) drops and kills the child.
+pub struct VideoStream {
+    child: Child,
+    /// Earlier stages of a process pipeline (e.g. the yt-dlp feeding an
+    /// ffmpeg muxer). Held only so they live and die with the stream.
+    upstream: Vec<Child>,
+    stdout: ChildStdout,
+    /// Reused across polls; only the filled portion is copied out into the
+    /// yielded `Bytes`, so we pay one allocation per chunk, not one per poll.
+    buf: Vec<u8>,
+    /// Bytes yielded so far, checked against `limit`.
+    emitted: u64,
+    /// Hard cap on yielded bytes; the child is killed and the stream errors
+    /// once it is crossed. None means unlimited. This backstops formats whose
+    /// size yt-dlp can't report up front.
+    limit: Option<u64>,
+}
+
+impl VideoStream {
+    pub fn new(child: Child, buffer_size: usize, limit: Option<u64>) -> io::Result<Self> {
+        Self::pipeline(Vec::new(), child, buffer_size, limit)
+    }
+
+    /// Wrap the final process of a pipeline, keeping the upstream stages
+    /// alive for as long as the client reads and killing the whole chain
+    /// when the stream is dropped.
+    pub fn pipeline(
+        upstream: Vec<Child>,
+        mut child: Child,
+        buffer_size: usize,
+        limit: Option<u64>,
+    ) -> io::Result<Self> {
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::other("child process has no stdout pipe"))?;
+        Ok(Self {
+            child,
+            upstream,
+            stdout,
+            buf: vec![0u8; buffer_size.max(1)],
+            emitted: 0,
+            limit,
+        })
+    }
+}
+
+impl Stream for VideoStream {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut read_buf = ReadBuf::new(&mut this.buf);
+        match Pin::new(&mut this.stdout).poll_read(cx, &mut read_buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(Ok(())) => {
+                let filled = read_buf.filled();
+                if filled.is_empty() {
+                    // EOF: reap the children so they don't linger as zombies.
+                    let _ = this.child.try_wait();
+                    for stage in &mut this.upstream {
+                        let _ = stage.try_wait();
+                    }
+                    Poll::Ready(None)
+                } else {
+                    this.emitted += filled.len() as u64;
+                    if let Some(limit) = this.limit {
+                        if this.emitted > limit {
+                            let _ = this.child.start_kill();
+                            for stage in &mut this.upstream {
+                                let _ = stage.start_kill();
+                            }
+                            return Poll::Ready(Some(Err(io::Error::other(format!(
+                                "download exceeded the {limit}-byte size limit"
+                            )))));
+                        }
+                    }
+                    // A partial read just yields a shorter chunk; the buffer
+                    // is fully reusable on the next poll.
+                    Poll::Ready(Some(Ok(Bytes::copy_from_slice(filled))))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for VideoStream {
+    fn drop(&mut self) {
+        let _ = self.child.start_kill();
+        for stage in &mut self.upstream {
+            let _ = stage.start_kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::process::Stdio;
+    use tokio::process::Command;
+
+    #[tokio::test]
+    async fn streams_child_stdout_to_completion() {
+        let child = Command::new("echo")
+            .arg("hello stream")
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("spawn echo");
+        let mut stream = VideoStream::new(child, 4, None).expect("wrap child");
+
+        let mut collected = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            collected.extend_from_slice(&chunk.expect("read chunk"));
+        }
+        assert_eq!(collected, b"hello stream\n");
+    }
+
+    #[tokio::test]
+    async fn a_two_stage_pipeline_streams_the_last_stdout() {
+        let mut first = Command::new("echo")
+            .arg("muxed bytes")
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("spawn echo");
+        let pipe: std::process::Stdio = first
+            .stdout
+            .take()
+            .expect("echo stdout")
+            .try_into()
+            .expect("inherit pipe");
+        let second = Command::new("cat")
+            .stdin(pipe)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("spawn cat");
+        let mut stream =
+            VideoStream::pipeline(vec![first], second, 8, None).expect("wrap pipeline");
+
+        let mut collected = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            collected.extend_from_slice(&chunk.expect("read chunk"));
+        }
+        assert_eq!(collected, b"muxed bytes
+");
+    }
+
+    #[tokio::test]
+    async fn byte_limit_kills_the_child_and_errors() {
+        let child = Command::new("sh")
+            .args(["-c", "head -c 1024 /dev/zero"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("spawn sh");
+        let mut stream = VideoStream::new(child, 64, Some(100)).expect("wrap child");
+
+        let mut total = 0u64;
+        let mut saw_error = false;
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(bytes) => total += bytes.len() as u64,
+                Err(e) => {
+                    assert!(e.to_string().contains("size limit"));
+                    saw_error = true;
+                    break;
+                }
+            }
+        }
+        assert!(saw_error, "stream ended without hitting the limit");
+        // At most one buffer past the cap may have been counted, never yielded.
+        assert!(total <= 128);
+    }
+}
@@ -0,0 +1,98 @@
+//! Turning a subtitle file into readable prose. TikTok's auto-captions
+//! come back from yt-dlp as WebVTT (occasionally SRT), both of which are
+//! cue-oriented: timing lines, positioning settings, and — for rolling
+//! captions — the same sentence repeated across consecutive cues as it
+//! scrolls. A transcript wants none of that.
+
+/// Convert raw VTT or SRT subtitle text into clean paragraph text:
+/// headers, cue numbers, timing lines and inline tags are dropped, and
+/// lines repeated by overlapping cues are collapsed to one occurrence.
+pub fn clean_subtitle_text(raw: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for line in raw.lines() {
+        let line = line.trim();
+        if line.is_empty()
+            || line.contains("-->")
+            || is_cue_counter(line)
+            || line.starts_with("WEBVTT")
+            || line.starts_with("Kind:")
+            || line.starts_with("Language:")
+            || line.starts_with("NOTE")
+            || line.starts_with("STYLE")
+        {
+            continue;
+        }
+        let text = strip_inline_tags(line);
+        if text.is_empty() {
+            continue;
+        }
+        // Rolling captions repeat earlier lines in later cues; keep each
+        // line only the first time it appears in a row.
+        if lines.last().map(String::as_str) != Some(text.as_str()) {
+            lines.push(text);
+        }
+    }
+    lines.join(" ")
+}
+
+/// SRT numbers its cues with a bare integer on its own line.
+fn is_cue_counter(line: &str) -> bool {
+    !line.is_empty() && line.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Drop inline markup like `<c>`, `<i>` and per-word timestamps
+/// (`<00:00:01.000>`), which VTT embeds inside cue text.
+fn strip_inline_tags(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut in_tag = false;
+    for ch in line.chars() {
+        match ch {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => out.push(ch),
+            _ => {}
+        }
+    }
+    out.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_VTT: &str = "\
+WEBVTT
+Kind: captions
+Language: en
+
+00:00:00.000 --> 00:00:02.500
+Hey everyone, welcome back.
+
+00:00:02.500 --> 00:00:05.000
+Hey everyone, welcome back.
+Today we're making pasta.
+
+00:00:05.000 --> 00:00:08.000
+Today we're making pasta.
+<c>It only takes</c> <00:00:06.000>ten minutes.
+";
+
+    #[test]
+    fn sample_vtt_becomes_clean_paragraph_text() {
+        assert_eq!(
+            clean_subtitle_text(SAMPLE_VTT),
+            "Hey everyone, welcome back. Today we're making pasta. It only takes ten minutes."
+        );
+    }
+
+    #[test]
+    fn srt_cue_numbers_and_timing_are_stripped() {
+        let srt = "1\n00:00:00,000 --> 00:00:02,000\nFirst line.\n\n2\n00:00:02,000 --> 00:00:04,000\nSecond line.\n";
+        assert_eq!(clean_subtitle_text(srt), "First line. Second line.");
+    }
+
+    #[test]
+    fn caption_free_input_yields_empty_text() {
+        assert_eq!(clean_subtitle_text("WEBVTT\n\n"), "");
+    }
+}
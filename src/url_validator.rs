@@ -0,0 +1,368 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static VIDEO_URL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^https?://(www\.|m\.)?tiktok\.com/@[\w.\-]+/video/\d+|^https?://(vm|vt)\.tiktok\.com/[\w]+",
+    )
+    .expect("video URL regex")
+});
+
+static LIVE_URL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^https?://(www\.|m\.)?tiktok\.com/@[\w.\-]+/live(/|\?|$)").expect("live URL regex")
+});
+
+static PROFILE_URL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^https?://(www\.|m\.)?tiktok\.com/@([\w.\-]+)/?(\?.*)?$").expect("profile URL regex")
+});
+
+static PLAYLIST_URL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^https?://(www\.|m\.)?tiktok\.com/@[\w.\-]+/playlist/").expect("playlist URL regex")
+});
+
+static PHOTO_URL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^https?://(www\.|m\.)?tiktok\.com/@[\w.\-]+/photo/\d+").expect("photo URL regex")
+});
+
+/// True when the URL looks like a single TikTok video (or a short link).
+pub fn is_valid_tiktok_url(url: &str) -> bool {
+    VIDEO_URL_RE.is_match(url.trim())
+}
+
+/// True when the URL points at a LIVE stream or LIVE replay
+/// (`tiktok.com/@user/live`). These behave very differently from normal
+/// posts — yt-dlp can stall on them or produce enormous files — so they
+/// get a dedicated rejection instead of a generic "invalid URL".
+pub fn is_live_url(url: &str) -> bool {
+    LIVE_URL_RE.is_match(url.trim())
+}
+
+/// True when the URL is a TikTok profile page (`tiktok.com/@user`).
+pub fn is_valid_profile_url(url: &str) -> bool {
+    PROFILE_URL_RE.is_match(url.trim())
+}
+
+/// Query parameters TikTok's share sheet appends that carry no meaning for
+/// extraction; stripping them keeps the cache key stable across pastes of
+/// the "same" link.
+const TRACKING_PARAMS: &[&str] = &[
+    "is_from_webapp",
+    "sender_device",
+    "sender_web_id",
+    "web_id",
+    "u_code",
+    "_r",
+    "_t",
+    "checksum",
+    "tt_from",
+    "share_app_id",
+    "share_link_id",
+    "refer",
+];
+
+/// Canonicalize a pasted URL: trim whitespace, add a missing scheme,
+/// normalize tiktok.com hosts to www.tiktok.com, drop known tracking query
+/// parameters and trailing slashes. The result is used both as the metadata
+/// cache key and as the URL handed to yt-dlp, so equivalent pastes resolve
+/// to one canonical string.
+pub fn normalize_tiktok_url(raw: &str) -> String {
+    let trimmed = raw.trim();
+    let with_scheme = if trimmed.starts_with("http://") || trimmed.starts_with("https://") {
+        trimmed.to_string()
+    } else {
+        format!("https://{trimmed}")
+    };
+
+    let Ok(mut url) = url::Url::parse(&with_scheme) else {
+        return with_scheme;
+    };
+
+    // Short-link hosts (vm/vt) must stay untouched; they resolve by redirect.
+    if matches!(url.host_str(), Some("tiktok.com") | Some("m.tiktok.com")) {
+        let _ = url.set_host(Some("www.tiktok.com"));
+    }
+
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| !TRACKING_PARAMS.contains(&k.as_ref()) && !k.starts_with("utm_"))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        let query: String = kept
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query(Some(&query));
+    }
+
+    let path = url.path().to_string();
+    if path.len() > 1 && path.ends_with('/') {
+        url.set_path(path.trim_end_matches('/'));
+    }
+
+    url.to_string()
+}
+
+/// Pull the username out of a profile URL, without the leading '@'.
+pub fn extract_username(profile_url: &str) -> Option<String> {
+    PROFILE_URL_RE
+        .captures(profile_url.trim())
+        .map(|c| c[2].to_string())
+}
+
+/// What a pasted TikTok link points at, as far as the URL alone can say.
+/// Serialized lowercase into /api/validate responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UrlKind {
+    Video,
+    Profile,
+    Playlist,
+    Photo,
+    Live,
+    Invalid,
+}
+
+/// Classify a pasted link without touching the network. LIVE and photo
+/// pages are matched before videos and profiles since their paths are
+/// more specific.
+pub fn classify_url(url: &str) -> UrlKind {
+    let url = url.trim();
+    if is_live_url(url) {
+        UrlKind::Live
+    } else if PHOTO_URL_RE.is_match(url) {
+        UrlKind::Photo
+    } else if PLAYLIST_URL_RE.is_match(url) {
+        UrlKind::Playlist
+    } else if is_valid_tiktok_url(url) {
+        UrlKind::Video
+    } else if is_valid_profile_url(url) {
+        UrlKind::Profile
+    } else {
+        UrlKind::Invalid
+    }
+}
+
+/// Reduce a title to something safe for filenames and Content-Disposition.
+/// How filenames derived from video titles treat non-ASCII characters
+/// (FILENAME_POLICY).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilenamePolicy {
+    /// Replace everything outside ASCII alphanumerics with underscores.
+    /// Safe everywhere, but reduces Arabic or CJK titles to underscores.
+    #[default]
+    Ascii,
+    /// Keep letters from any script and strip only filesystem-unsafe
+    /// characters (path separators, control characters, `: * ? " < > |`).
+    Unicode,
+}
+
+impl std::str::FromStr for FilenamePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ascii" => Ok(FilenamePolicy::Ascii),
+            "unicode" => Ok(FilenamePolicy::Unicode),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The sanitized title, or the video id when sanitizing leaves nothing.
+/// Emoji-only and whitespace titles reduce to the empty string under
+/// either policy and would otherwise produce filenames like ".mp4".
+pub fn title_or_id(title: &str, id: &str, policy: FilenamePolicy) -> String {
+    let sanitized = sanitize_filename_with(title, policy);
+    if !sanitized.is_empty() {
+        return sanitized;
+    }
+    let from_id = sanitize_filename_with(id, policy);
+    if from_id.is_empty() {
+        "video".to_string()
+    } else {
+        from_id
+    }
+}
+
+pub fn sanitize_filename_with(name: &str, policy: FilenamePolicy) -> String {
+    let replaced: String = match policy {
+        FilenamePolicy::Ascii => name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+        FilenamePolicy::Unicode => name
+            .chars()
+            .map(|c| {
+                if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect(),
+    };
+    replaced
+        .trim_matches(['_', ' ', '.'])
+        .chars()
+        .take(100)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_standard_video_urls() {
+        assert!(is_valid_tiktok_url(
+            "https://www.tiktok.com/@someuser/video/7234567890123456789"
+        ));
+        assert!(is_valid_tiktok_url("https://vm.tiktok.com/ZMabcdef/"));
+        assert!(is_valid_tiktok_url("https://m.tiktok.com/@a.b-c/video/123"));
+    }
+
+    #[test]
+    fn rejects_non_tiktok_urls() {
+        assert!(!is_valid_tiktok_url("https://example.com/@user/video/123"));
+        assert!(!is_valid_tiktok_url("not a url"));
+        assert!(!is_valid_tiktok_url("https://www.tiktok.com/@user"));
+    }
+
+    #[test]
+    fn live_urls_detected() {
+        assert!(is_live_url("https://www.tiktok.com/@someuser/live"));
+        assert!(is_live_url("https://www.tiktok.com/@someuser/live/"));
+        assert!(is_live_url("https://m.tiktok.com/@someuser/live?lang=en"));
+        assert!(!is_live_url("https://www.tiktok.com/@someuser/video/123"));
+        assert!(!is_live_url("https://www.tiktok.com/@livemusic/video/123"));
+    }
+
+    #[test]
+    fn profile_urls_detected_and_username_extracted() {
+        assert!(is_valid_profile_url("https://www.tiktok.com/@some.user"));
+        assert_eq!(
+            extract_username("https://www.tiktok.com/@some.user"),
+            Some("some.user".to_string())
+        );
+        assert!(!is_valid_profile_url(
+            "https://www.tiktok.com/@user/video/123"
+        ));
+    }
+
+    #[test]
+    fn classification_covers_every_category() {
+        let cases = [
+            ("https://www.tiktok.com/@user/video/123", UrlKind::Video),
+            ("https://vm.tiktok.com/ZMabcdef", UrlKind::Video),
+            ("https://www.tiktok.com/@user", UrlKind::Profile),
+            ("https://www.tiktok.com/@user/playlist/faves-700", UrlKind::Playlist),
+            ("https://www.tiktok.com/@user/photo/7234567890", UrlKind::Photo),
+            ("https://www.tiktok.com/@user/live", UrlKind::Live),
+            ("https://example.com/@user/video/123", UrlKind::Invalid),
+            ("not a url", UrlKind::Invalid),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(classify_url(url), expected, "url: {url}");
+        }
+    }
+
+    #[test]
+    fn normalize_adds_scheme() {
+        assert_eq!(
+            normalize_tiktok_url("www.tiktok.com/@u/video/1"),
+            "https://www.tiktok.com/@u/video/1"
+        );
+        assert_eq!(
+            normalize_tiktok_url("  https://www.tiktok.com/@u/video/1  "),
+            "https://www.tiktok.com/@u/video/1"
+        );
+    }
+
+    #[test]
+    fn normalize_produces_one_canonical_form() {
+        let canonical = "https://www.tiktok.com/@u/video/123";
+        for variant in [
+            "https://www.tiktok.com/@u/video/123",
+            "https://www.tiktok.com/@u/video/123/",
+            "https://m.tiktok.com/@u/video/123",
+            "https://tiktok.com/@u/video/123?is_from_webapp=1&sender_device=pc",
+            "https://www.tiktok.com/@u/video/123?utm_source=share&_r=1",
+            "www.tiktok.com/@u/video/123/?web_id=987",
+        ] {
+            assert_eq!(normalize_tiktok_url(variant), canonical, "variant: {variant}");
+        }
+    }
+
+    #[test]
+    fn normalize_keeps_meaningful_query_and_short_links() {
+        assert_eq!(
+            normalize_tiktok_url("https://www.tiktok.com/@u/video/123?lang=en&_t=8x"),
+            "https://www.tiktok.com/@u/video/123?lang=en"
+        );
+        assert_eq!(
+            normalize_tiktok_url("https://vm.tiktok.com/ZMabcdef/"),
+            "https://vm.tiktok.com/ZMabcdef"
+        );
+    }
+
+    #[test]
+    fn sanitize_filename_policies_handle_non_latin_titles() {
+        let arabic = "\u{641}\u{64a}\u{62f}\u{64a}\u{648} \u{631}\u{627}\u{626}\u{639}!";
+        let cjk = "\u{3059}\u{3054}\u{3044}\u{52d5}\u{753b}: \u{6700}\u{9ad8}?";
+
+        // The ASCII policy reduces foreign scripts to nothing.
+        assert_eq!(sanitize_filename_with(arabic, FilenamePolicy::Ascii), "");
+        // The unicode policy keeps the letters and strips only unsafe chars.
+        let kept = sanitize_filename_with(arabic, FilenamePolicy::Unicode);
+        assert!(kept.contains('\u{641}'));
+
+        let kept = sanitize_filename_with(cjk, FilenamePolicy::Unicode);
+        assert!(kept.contains('\u{52d5}'));
+        assert!(!kept.contains(':'));
+        assert!(!kept.contains('?'));
+
+        // Path separators never survive either policy.
+        assert!(!sanitize_filename_with("a/b\\c", FilenamePolicy::Unicode).contains('/'));
+        assert!(!sanitize_filename_with("a/b\\c", FilenamePolicy::Unicode).contains('\\'));
+    }
+
+    #[test]
+    fn degenerate_titles_fall_back_to_the_video_id() {
+        // Pure emoji, whitespace and empty titles all sanitize to nothing.
+        for title in ["\u{1f525}\u{1f525}\u{1f525}", "   ", ""] {
+            let name = title_or_id(title, "7234567890123456789", FilenamePolicy::Ascii);
+            assert_eq!(name, "7234567890123456789", "title {title:?}");
+            let name = title_or_id(title, "7234567890123456789", FilenamePolicy::Unicode);
+            assert!(!name.is_empty());
+        }
+        // A usable title wins over the id.
+        assert_eq!(
+            title_or_id("my dance", "123", FilenamePolicy::Ascii),
+            "my_dance"
+        );
+        // Even a degenerate id leaves something to name the file with.
+        assert_eq!(title_or_id("...", "...", FilenamePolicy::Ascii), "video");
+    }
+
+    #[test]
+    fn sanitize_strips_unsafe_characters() {
+        assert_eq!(
+            sanitize_filename_with("hello world!", FilenamePolicy::Ascii),
+            "hello_world"
+        );
+        assert_eq!(
+            sanitize_filename_with("a/b\\c:d", FilenamePolicy::Ascii),
+            "a_b_c_d"
+        );
+    }
+}
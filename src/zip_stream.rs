@@ -0,0 +1,149 @@
+//! A minimal ZIP writer for archives that are built while they are being
+//! sent. The `zip` crate needs a seekable sink, which a streaming response
+//! body is not. We get away without seeking because every entry's bytes are
+//! fully on disk before it is appended — the local header can be written
+//! complete, and only the central directory waits until the end. Entries
+//! are stored uncompressed: they are media files that don't compress.
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Per-entry bookkeeping for the central directory written by `finish`.
+struct EntryRecord {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// ZIP general-purpose flag marking entry names as UTF-8.
+const UTF8_FLAG: u16 = 0x800;
+
+pub struct StreamingZipWriter<W> {
+    writer: W,
+    entries: Vec<EntryRecord>,
+    offset: u64,
+}
+
+impl<W: AsyncWrite + Unpin> StreamingZipWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            entries: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Append one complete entry. The archive so far is flushed to the
+    /// sink, so a client downloading the stream receives the entry as soon
+    /// as this returns.
+    pub async fn add_entry(&mut self, name: &str, data: &[u8]) -> std::io::Result<()> {
+        let size = u32::try_from(data.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "entry exceeds the 4 GiB ZIP entry limit",
+            )
+        })?;
+        let offset = u32::try_from(self.offset).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "archive exceeds the 4 GiB ZIP offset limit",
+            )
+        })?;
+        let crc = crc32fast::hash(data);
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes()); // local header
+        header.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        header.extend_from_slice(&UTF8_FLAG.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // stored
+        header.extend_from_slice(&0u32.to_le_bytes()); // mod time/date
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes()); // compressed
+        header.extend_from_slice(&size.to_le_bytes()); // uncompressed
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra length
+        header.extend_from_slice(name.as_bytes());
+
+        self.writer.write_all(&header).await?;
+        self.writer.write_all(data).await?;
+        self.writer.flush().await?;
+        self.offset += header.len() as u64 + data.len() as u64;
+        self.entries.push(EntryRecord {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Write the central directory and end-of-directory record, completing
+    /// the archive. Returns the sink for callers that want it back.
+    pub async fn finish(mut self) -> std::io::Result<W> {
+        let directory_offset = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&0x02014b50u32.to_le_bytes()); // central header
+            directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            directory.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            directory.extend_from_slice(&UTF8_FLAG.to_le_bytes());
+            directory.extend_from_slice(&0u16.to_le_bytes()); // stored
+            directory.extend_from_slice(&0u32.to_le_bytes()); // mod time/date
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes()); // compressed
+            directory.extend_from_slice(&entry.size.to_le_bytes()); // uncompressed
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&0u16.to_le_bytes()); // extra length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // comment length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // disk number
+            directory.extend_from_slice(&0u16.to_le_bytes()); // internal attrs
+            directory.extend_from_slice(&0u32.to_le_bytes()); // external attrs
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let count = self.entries.len() as u16;
+        let directory_size = directory.len() as u32;
+        directory.extend_from_slice(&0x06054b50u32.to_le_bytes()); // end of directory
+        directory.extend_from_slice(&0u16.to_le_bytes()); // this disk
+        directory.extend_from_slice(&0u16.to_le_bytes()); // directory disk
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&directory_size.to_le_bytes());
+        directory.extend_from_slice(&(directory_offset as u32).to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes()); // comment length
+
+        self.writer.write_all(&directory).await?;
+        self.writer.flush().await?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn streamed_zip_reads_back_with_all_entries() {
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let producer = tokio::spawn(async move {
+            let mut zip = StreamingZipWriter::new(writer);
+            zip.add_entry("001_first.mp4", b"first video bytes").await.unwrap();
+            zip.add_entry("002_second.mp4", b"second video bytes").await.unwrap();
+            zip.finish().await.unwrap();
+        });
+
+        let mut bytes = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut bytes)
+            .await
+            .unwrap();
+        producer.await.unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut archive.by_name("002_second.mp4").unwrap(), &mut contents)
+            .unwrap();
+        assert_eq!(contents, "second video bytes");
+    }
+}